@@ -1,3 +1,6 @@
+use crate::yml::{RULE_LEVELS, RULE_STATUSES};
+use chrono::{DateTime, Utc};
+use clap::builder::PossibleValuesParser;
 use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
 
@@ -13,27 +16,184 @@ pub struct AppArg {
 }
 
 #[derive(Args, Clone, Debug)]
-pub struct CsvTimelineOption {
+pub struct RuleOption {
+    /// Directory of additional rules, loaded over the default rules (./rules or the embedded rules). Can be specified multiple times
+    #[clap(short = 'r', long = "rules", value_name = "DIR")]
+    pub rules: Vec<PathBuf>,
+
+    /// Do not load the default rules, only the rules of the --rules directories
+    #[clap(long = "no-default-rules", requires = "rules")]
+    pub no_default_rules: bool,
+
+    /// Exit with an error if any rule fails to load
+    #[clap(long = "fail-on-rule-error")]
+    pub fail_on_rule_error: bool,
+
+    /// Tuning file with the rules to disable and the false positives to suppress (default: config/tuning.yml next to the executable)
+    #[clap(long = "tuning", value_name = "FILE")]
+    pub tuning: Option<PathBuf>,
+
+    /// Minimum level of the rules to load
+    #[clap(help_heading = Some("Rule Filtering"), long = "min-level", value_name = "LEVEL", value_parser = PossibleValuesParser::new(RULE_LEVELS))]
+    pub min_level: Option<String>,
+
+    /// Only load rules with the specified status (ex: stable,test)
+    #[clap(help_heading = Some("Rule Filtering"), long = "include-status", value_name = "STATUS", value_parser = PossibleValuesParser::new(RULE_STATUSES), value_delimiter = ',')]
+    pub include_status: Vec<String>,
+
+    /// Do not load rules with the specified status (ex: deprecated,experimental)
+    #[clap(help_heading = Some("Rule Filtering"), long = "exclude-status", value_name = "STATUS", value_parser = PossibleValuesParser::new(RULE_STATUSES), value_delimiter = ',')]
+    pub exclude_status: Vec<String>,
+
+    /// Only load rules with the specified tag. A tag also matches its sub-techniques (ex: attack.t1553)
+    #[clap(help_heading = Some("Rule Filtering"), long = "include-tag", value_name = "TAG", value_delimiter = ',')]
+    pub include_tag: Vec<String>,
+
+    /// Do not load rules with the specified tag. A tag also matches its sub-techniques (ex: attack.discovery)
+    #[clap(help_heading = Some("Rule Filtering"), long = "exclude-tag", value_name = "TAG", value_delimiter = ',')]
+    pub exclude_tag: Vec<String>,
+
+    /// Only load rules for the specified logsource category (ex: process_creation,file_event)
+    #[clap(help_heading = Some("Rule Filtering"), long = "logsource-category", value_name = "CATEGORY", value_delimiter = ',')]
+    pub logsource_category: Vec<String>,
+}
+
+// Log data to parse. Shared by the subcommands that parse Unified Logs
+#[derive(Args, Clone, Debug)]
+pub struct InputOption {
+    /// Number of threads used to parse tracev3 files (default: number of CPU cores)
+    #[arg(help_heading = Some("General"), short = 't', long = "threads", value_name = "NUMBER", value_parser = clap::value_parser!(u16).range(1..))]
+    pub threads: Option<u16>,
+
     /// Path to logarchive formatted directory
-    #[arg(help_heading = Some("Input"), short = 'a', long = "archive_dir", value_name = "ARCHIVE", conflicts_with_all = ["live_analysis"])]
+    #[arg(help_heading = Some("Input"), short = 'a', long = "archive_dir", value_name = "ARCHIVE", conflicts_with_all = ["live_analysis"], required_unless_present = "live_analysis")]
     pub archive_dir: Option<PathBuf>,
     /// Run on live system
     #[arg(help_heading = Some("Input"), short = 'l', long = "live_analysis", conflicts_with_all = ["archive_dir"])]
     pub live_analysis: bool,
 
+    /// Start time of the log entries to load (ex: "2023-01-01T00:00:00Z")
+    #[arg(help_heading = Some("Filtering"), long = "timeline-start", value_name = "DATE", value_parser = parse_rfc3339)]
+    pub timeline_start: Option<DateTime<Utc>>,
+    /// End time of the log entries to load (ex: "2023-01-31T23:59:59+09:00")
+    #[arg(help_heading = Some("Filtering"), long = "timeline-end", value_name = "DATE", value_parser = parse_rfc3339)]
+    pub timeline_end: Option<DateTime<Utc>>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct TimelineOption {
+    #[command(flatten)]
+    pub input: InputOption,
+
+    #[command(flatten)]
+    pub rule_option: RuleOption,
+
     #[arg(help_heading = Some("Output"), short = 'o', long = "output", value_name = "OUTPUT")]
     pub output: PathBuf,
+    /// Save detection results to a separate CSV file
+    #[arg(help_heading = Some("Output"), short = 'd', long = "detections", value_name = "DETECTIONS")]
+    pub detections: Option<PathBuf>,
+    /// Save the TCC (privacy permission) decisions to a separate CSV file
+    #[arg(help_heading = Some("Output"), long = "tcc", value_name = "TCC")]
+    pub tcc: Option<PathBuf>,
+    /// Overwrite the output files if they already exist
+    #[arg(help_heading = Some("Output"), long = "overwrite")]
+    pub overwrite: bool,
+}
+
+fn parse_rfc3339(s: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|date_time| date_time.with_timezone(&Utc))
+        .map_err(|err| {
+            format!("{err}. Specify the date in RFC3339 format (ex: 2023-01-01T00:00:00Z)")
+        })
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct LogonSummaryOption {
+    #[command(flatten)]
+    pub input: InputOption,
+
+    /// Save the summary to a CSV file instead of printing it
+    #[arg(help_heading = Some("Output"), short = 'o', long = "output", value_name = "OUTPUT")]
+    pub output: Option<PathBuf>,
+    /// Overwrite the output file if it already exists
+    #[arg(help_heading = Some("Output"), long = "overwrite")]
+    pub overwrite: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ValidateRulesOption {
+    /// Directory of the rules to check
+    #[arg(help_heading = Some("Input"), short = 'd', long = "directory", value_name = "DIR")]
+    pub directory: PathBuf,
+
+    /// Save the report to a file instead of printing it
+    #[arg(help_heading = Some("Output"), short = 'o', long = "output", value_name = "OUTPUT")]
+    pub output: Option<PathBuf>,
+    /// Overwrite the output file if it already exists
+    #[arg(help_heading = Some("Output"), long = "overwrite")]
+    pub overwrite: bool,
 }
 
 #[derive(Subcommand)]
 pub enum Action {
-    CsvTimeline(CsvTimelineOption),
+    /// Save the timeline in CSV format
+    CsvTimeline(TimelineOption),
+    /// Save the timeline in JSON Lines format
+    JsonTimeline(TimelineOption),
+    /// Check the rules in a directory and print a JSON report
+    ValidateRules(ValidateRulesOption),
+    /// List the successful and failed logons per user
+    LogonSummary(LogonSummaryOption),
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::args::{parse_rfc3339, Action, AppArg};
+    use clap::Parser;
+
+    #[test]
+    fn test_parse_rfc3339() {
+        let date_time = parse_rfc3339("2023-01-01T09:00:00+09:00").unwrap();
+        assert_eq!(date_time.to_rfc3339(), "2023-01-01T00:00:00+00:00");
+        assert!(parse_rfc3339("2023-01-01 00:00:00").is_err());
+    }
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn test_rule_option() {
+        let cli = AppArg::try_parse_from([
+            "macos-log-dfir",
+            "csv-timeline",
+            "-a",
+            "archive",
+            "-o",
+            "out.csv",
+            "--min-level",
+            "high",
+        ])
+        .unwrap();
+        assert!(
+            matches!(cli.action, Action::CsvTimeline(opt) if opt.rule_option.min_level.as_deref() == Some("high"))
+        );
+        // ルールを読み込まないサブコマンドでは、ルールのオプションを受け付けない
+        assert!(AppArg::try_parse_from([
+            "macos-log-dfir",
+            "validate-rules",
+            "-d",
+            "rules",
+            "--min-level",
+            "high",
+        ])
+        .is_err());
+        assert!(AppArg::try_parse_from([
+            "macos-log-dfir",
+            "logon-summary",
+            "-a",
+            "archive",
+            "--rules",
+            "rules",
+        ])
+        .is_err());
     }
 }
@@ -1,22 +1,636 @@
+use crate::events::EventRecord;
+use crate::rule::correlation::{CorrelationResult, CorrelationRule, CorrelationState};
+use crate::rule::count::{AggregationResult, AggregationState};
+use crate::tuning::{SuppressionAction, Tuning};
 use crate::RuleNode;
-use macos_unifiedlogs::unified_log::LogData;
+use hashbrown::HashMap;
+use yaml_rust::Yaml;
 
 #[derive(Debug)]
 pub struct DetectInfo {
     pub rulepath: String,
     pub ruletitle: String,
+    pub ruleid: String,
     pub level: String,
-    pub logdata: LogData,
+    pub tags: Vec<String>,
+    // Matched a suppression in the tuning file (only kept when the suppression action is tag)
+    pub suppressed: bool,
+    // Aggregation condition of a threshold or correlation rule and the counted value.
+    // The log entry is the one that reached the threshold
+    pub aggregation: Option<String>,
+    // Log entries that contributed to a correlation rule hit
+    pub correlated_logs: Vec<CorrelatedLog>,
+    pub logdata: EventRecord,
 }
 
-pub fn detect(results: &Vec<LogData>, rulenode: &Vec<RuleNode>) -> Vec<DetectInfo> {
-    vec![]
+// A log entry that contributed to a correlation rule hit, with the title of the rule it matched.
+// Only the fields written to the detections file are kept
+#[derive(Debug)]
+pub struct CorrelatedLog {
+    pub ruletitle: String,
+    pub time: f64,
+    pub process: String,
+    pub message: String,
+}
+
+// Check a parsed log entry against all initialized rules.
+// The indexes of the rules with an aggregation condition and of the rules referenced by correlation rules that the log entry
+// matched are added to aggregation_hits, to be counted by the Aggregator.
+// Hits of rules with an aggregation condition are only returned once counted, and hits of referenced rules only when they generate
+pub fn detect(
+    data: &EventRecord,
+    rulenode: &[RuleNode],
+    tuning: &Tuning,
+    aggregation_hits: &mut Vec<usize>,
+) -> Vec<DetectInfo> {
+    let mut detect_infos = vec![];
+    for (rule_index, rule) in rulenode.iter().enumerate() {
+        // Only the log entries of the rule's logsource are checked
+        if !rule.is_target_logsource(data) || !rule.select(data) {
+            continue;
+        }
+        let suppressed = tuning.is_suppressed(get_rule_id(&rule.yaml), data);
+        if suppressed && tuning.action == SuppressionAction::Drop {
+            continue;
+        }
+        if rule.has_aggregation() || rule.correlated {
+            aggregation_hits.push(rule_index);
+        }
+        if rule.has_aggregation() || !rule.generate {
+            continue;
+        }
+        detect_infos.push(create_detect_info(
+            &rule.rulepath,
+            &rule.yaml,
+            suppressed,
+            data.clone(),
+        ));
+    }
+    detect_infos
+}
+
+// Counts the hits of the rules with an aggregation condition and of the correlation rules, in the order the log entries
+// are written. Each group only keeps the time and the counted values of the hits inside its timeframe,
+// so the memory used does not grow with the number of log entries
+#[derive(Default)]
+pub struct Aggregator {
+    rules: HashMap<usize, AggregationState>,
+    correlations: HashMap<usize, CorrelationState>,
+}
+
+impl Aggregator {
+    // Count a log entry that matched the rules of aggregation_hits (see detect) and return a detection
+    // for each threshold or correlation reached
+    pub fn add(
+        &mut self,
+        data: &EventRecord,
+        aggregation_hits: &[usize],
+        rulenode: &[RuleNode],
+        correlations: &[CorrelationRule],
+        tuning: &Tuning,
+    ) -> Vec<DetectInfo> {
+        let mut detect_infos = vec![];
+        for &rule_index in aggregation_hits {
+            let rule = &rulenode[rule_index];
+            if rule.has_aggregation() {
+                let state = self.rules.entry(rule_index).or_default();
+                if let Some(result) = rule.aggregate(state, data) {
+                    detect_infos.push(aggregation_detect_info(rule, result, tuning));
+                }
+            }
+            for (correlation_index, correlation) in correlations.iter().enumerate() {
+                if !correlation.get_rule_indexes().contains(&rule_index) {
+                    continue;
+                }
+                let state = self.correlations.entry(correlation_index).or_default();
+                if let Some(result) = correlation.correlate(state, rule_index, data) {
+                    detect_infos.push(correlation_detect_info(
+                        rulenode,
+                        correlation,
+                        result,
+                        tuning,
+                    ));
+                }
+            }
+        }
+        detect_infos
+    }
+
+    // Count the windows still open once all log entries have been parsed
+    pub fn finish(
+        self,
+        rulenode: &[RuleNode],
+        correlations: &[CorrelationRule],
+        tuning: &Tuning,
+    ) -> Vec<DetectInfo> {
+        let mut rules: Vec<_> = self.rules.into_iter().collect();
+        rules.sort_by_key(|(rule_index, _)| *rule_index);
+        let mut correlation_states: Vec<_> = self.correlations.into_iter().collect();
+        correlation_states.sort_by_key(|(correlation_index, _)| *correlation_index);
+
+        let mut detect_infos = vec![];
+        for (rule_index, state) in rules {
+            let rule = &rulenode[rule_index];
+            for result in rule.finish_aggregation(state) {
+                detect_infos.push(aggregation_detect_info(rule, result, tuning));
+            }
+        }
+        for (correlation_index, state) in correlation_states {
+            let correlation = &correlations[correlation_index];
+            for result in correlation.finish(state) {
+                detect_infos.push(correlation_detect_info(
+                    rulenode,
+                    correlation,
+                    result,
+                    tuning,
+                ));
+            }
+        }
+        detect_infos
+    }
+}
+
+// Detection of a rule with an aggregation condition, with the condition and the counted value
+fn aggregation_detect_info(
+    rule: &RuleNode,
+    result: AggregationResult,
+    tuning: &Tuning,
+) -> DetectInfo {
+    let aggregation = rule.get_aggregation();
+    let condition = aggregation
+        .map(|aggregation| aggregation.to_string())
+        .unwrap_or_default();
+    let by_field_name = aggregation.and_then(|aggregation| aggregation.by_field_name.as_ref());
+    let summary = match (by_field_name, &result.by_value) {
+        (Some(field), Some(value)) => format!(
+            "{} (count: {}, {}: {})",
+            condition, result.count, field, value
+        ),
+        _ => format!("{} (count: {})", condition, result.count),
+    };
+    let suppressed = tuning.is_suppressed(get_rule_id(&rule.yaml), &result.logdata);
+    let mut detect_info =
+        create_detect_info(&rule.rulepath, &rule.yaml, suppressed, result.logdata);
+    detect_info.aggregation = Some(summary);
+    detect_info
+}
+
+// Detection of a correlation rule, with the log entries that contributed to it
+fn correlation_detect_info(
+    rulenode: &[RuleNode],
+    correlation: &CorrelationRule,
+    result: CorrelationResult,
+    tuning: &Tuning,
+) -> DetectInfo {
+    let summary = correlation.get_summary(&result);
+    let correlated_logs: Vec<CorrelatedLog> = result
+        .records
+        .into_iter()
+        .map(|hit| CorrelatedLog {
+            ruletitle: get_rule_title(&rulenode[hit.rule_index].yaml),
+            time: hit.time,
+            process: hit.process,
+            message: hit.message,
+        })
+        .collect();
+    let suppressed = tuning.is_suppressed(get_rule_id(&correlation.yaml), &result.logdata);
+    let mut detect_info = create_detect_info(
+        &correlation.rulepath,
+        &correlation.yaml,
+        suppressed,
+        result.logdata,
+    );
+    detect_info.aggregation = Some(summary);
+    detect_info.correlated_logs = correlated_logs;
+    detect_info
+}
+
+fn get_rule_id(yaml: &Yaml) -> &str {
+    yaml["id"].as_str().unwrap_or_default()
+}
+
+fn get_rule_title(yaml: &Yaml) -> String {
+    yaml["title"].as_str().unwrap_or_default().to_string()
+}
+
+fn create_detect_info(
+    rulepath: &str,
+    yaml: &Yaml,
+    suppressed: bool,
+    logdata: EventRecord,
+) -> DetectInfo {
+    DetectInfo {
+        rulepath: rulepath.to_string(),
+        ruletitle: get_rule_title(yaml),
+        ruleid: get_rule_id(yaml).to_string(),
+        level: yaml["level"].as_str().unwrap_or_default().to_string(),
+        tags: get_mitre_tags(yaml),
+        suppressed,
+        aggregation: None,
+        correlated_logs: vec![],
+        logdata,
+    }
+}
+
+// Only the MITRE ATT&CK tags (attack.xxx) are kept in the detection results
+fn get_mitre_tags(yaml: &Yaml) -> Vec<String> {
+    yaml["tags"]
+        .as_vec()
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|tag| tag.as_str())
+                .filter(|tag| tag.starts_with("attack."))
+                .map(|tag| tag.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Print the number of hits for each rule that matched at least one log entry
+pub fn print_detect_summary(detect_infos: &[DetectInfo]) {
+    let suppressed_count = detect_infos.iter().filter(|info| info.suppressed).count();
+    if suppressed_count == 0 {
+        println!("Detected {} events", detect_infos.len());
+    } else {
+        println!(
+            "Detected {} events ({} suppressed)",
+            detect_infos.len() - suppressed_count,
+            suppressed_count
+        );
+    }
+    let mut counts: HashMap<(&str, &str), usize> = HashMap::new();
+    for info in detect_infos.iter().filter(|info| !info.suppressed) {
+        *counts
+            .entry((info.ruletitle.as_str(), info.level.as_str()))
+            .or_insert(0) += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    for ((title, level), count) in counts {
+        println!("  [{}] {}: {}", level, title, count);
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::detection::{detect, Aggregator};
+    use crate::events::synthesize_events;
+    use crate::rule::correlation::{mark_correlated_rules, CorrelationRule};
+    use crate::test_helpers::{create_logdata, parse_rule_from_str};
+    use crate::tuning::Tuning;
+    use hashbrown::HashSet;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn test_detect() {
+        let rule = parse_rule_from_str(
+            r#"
+        title: xattr
+        id: f5141b6d-9f42-41c6-a7bf-2a780678b29b
+        level: low
+        tags:
+            - attack.defense_evasion
+            - attack.t1553.001
+            - car.2013-05-004
+        detection:
+            selection:
+                processImagePath|endswith: '/xattr'
+            condition: selection
+        "#,
+        );
+        let rules = [rule];
+        let tuning = Tuning::default();
+        assert!(detect(
+            &create_logdata("/bin/ls", "test"),
+            &rules,
+            &tuning,
+            &mut vec![]
+        )
+        .is_empty());
+        let detect_infos = detect(
+            &create_logdata("/usr/bin/xattr", "test"),
+            &rules,
+            &tuning,
+            &mut vec![],
+        );
+        assert_eq!(detect_infos.len(), 1);
+        assert_eq!(detect_infos[0].rulepath, "testpath");
+        assert_eq!(detect_infos[0].ruletitle, "xattr");
+        assert_eq!(
+            detect_infos[0].ruleid,
+            "f5141b6d-9f42-41c6-a7bf-2a780678b29b"
+        );
+        assert_eq!(detect_infos[0].level, "low");
+        assert_eq!(
+            detect_infos[0].tags,
+            vec!["attack.defense_evasion", "attack.t1553.001"]
+        );
+        assert_eq!(detect_infos[0].logdata.process, "/usr/bin/xattr");
+    }
+
+    #[test]
+    fn test_detect_logsource() {
+        let rules = [parse_rule_from_str(
+            r#"
+        title: xattr
+        logsource:
+            product: macos
+            category: process_creation
+        detection:
+            selection:
+                Image|endswith: '/xattr'
+            condition: selection
+        "#,
+        )];
+        let tuning = Tuning::default();
+        // process_creationのルールは、メッセージから作成したprocess_creationのイベントと、tracev3から読んだログとだけ比較する
+        let mut data = create_logdata("/usr/bin/xattr", "xattr -d com.apple.quarantine a.app");
+        assert_eq!(detect(&data, &rules, &tuning, &mut vec![]).len(), 1);
+        data.event_type = "process_creation".to_string();
+        assert_eq!(detect(&data, &rules, &tuning, &mut vec![]).len(), 1);
+        data.event_type = "tcc".to_string();
+        assert!(detect(&data, &rules, &tuning, &mut vec![]).is_empty());
+        data.event_type = "activityCreateEvent".to_string();
+        assert!(detect(&data, &rules, &tuning, &mut vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_detect_unmapped_logsource() {
+        let rules = [parse_rule_from_str(
+            r#"
+        title: sudo
+        detection:
+            selection:
+                processImagePath: /usr/bin/sudo
+            condition: selection
+        "#,
+        )];
+        let tuning = Tuning::default();
+        // logsourceの無いルールは、tracev3から読んだログとだけ比較する
+        let data = create_logdata(
+            "/usr/bin/sudo",
+            "admin : TTY=ttys000 ; PWD=/Users/admin ; USER=root ; COMMAND=/bin/ls",
+        );
+        let events = synthesize_events(&data);
+        assert!(!events.is_empty());
+        let detect_infos: Vec<_> = std::iter::once(&data)
+            .chain(events.iter())
+            .flat_map(|record| detect(record, &rules, &tuning, &mut vec![]))
+            .collect();
+        assert_eq!(detect_infos.len(), 1);
+    }
+
+    #[test]
+    fn test_detect_file_event_source_once() {
+        let rules = [
+            parse_rule_from_str(
+                r#"
+        title: backgroundtaskmanagementd
+        detection:
+            selection:
+                processImagePath|endswith: '/backgroundtaskmanagementd'
+            condition: selection
+        "#,
+            ),
+            parse_rule_from_str(
+                r#"
+        title: backgroundtaskmanagementd count
+        detection:
+            selection:
+                processImagePath|endswith: '/backgroundtaskmanagementd'
+            condition: selection | count() > 0
+        "#,
+            ),
+        ];
+        let tuning = Tuning::default();
+        let data = create_logdata(
+            "/System/Library/PrivateFrameworks/BackgroundTaskManagement.framework/Resources/backgroundtaskmanagementd",
+            "registerLaunchItem: result=no error, new item disposition=[enabled, allowed, visible, notified], identifier=com.example.agent, url=file:///Library/LaunchAgents/com.example.agent.plist",
+        );
+        let events = synthesize_events(&data);
+        assert_eq!(events.len(), 1);
+        // file_eventは元のログのプロセスを持つが、logsourceの無いルールは元のログでだけ検知する
+        let mut aggregation_hits = vec![];
+        let detect_infos: Vec<_> = std::iter::once(&data)
+            .chain(events.iter())
+            .flat_map(|record| detect(record, &rules, &tuning, &mut aggregation_hits))
+            .collect();
+        assert_eq!(detect_infos.len(), 1);
+        assert_eq!(aggregation_hits.len(), 1);
+    }
+
+    #[test]
+    fn test_detect_authentication_source_once() {
+        let rules = [
+            parse_rule_from_str(
+                r#"
+        title: sshd failed password
+        detection:
+            selection:
+                process: sshd
+                eventMessage|startswith: 'Failed password'
+            condition: selection
+        "#,
+            ),
+            parse_rule_from_str(
+                r#"
+        title: sshd failed password count
+        detection:
+            selection:
+                process: sshd
+                eventMessage|startswith: 'Failed password'
+            condition: selection | count() > 1
+        "#,
+            ),
+        ];
+        let tuning = Tuning::default();
+        let mut aggregator = Aggregator::default();
+        let mut aggregation_count = 0;
+        let mut detect_infos = vec![];
+        for port in [52144, 52145] {
+            let data = create_logdata(
+                "/usr/sbin/sshd",
+                &format!("Failed password for admin from 10.0.0.5 port {port} ssh2"),
+            );
+            let events = synthesize_events(&data);
+            assert_eq!(events.len(), 1);
+            // authenticationは元のログのプロセスとメッセージを持つが、logsourceの無いルールは元のログでだけ検知する
+            for record in std::iter::once(&data).chain(events.iter()) {
+                let mut aggregation_hits = vec![];
+                detect_infos.extend(detect(record, &rules, &tuning, &mut aggregation_hits));
+                aggregation_count += aggregation_hits.len();
+                // timeframeの無い集計条件は、全てのログを読み込んだ後に集計する
+                assert!(aggregator
+                    .add(record, &aggregation_hits, &rules, &[], &tuning)
+                    .is_empty());
+            }
+        }
+        assert_eq!(detect_infos.len(), 2);
+        assert_eq!(aggregation_count, 2);
+        let detect_infos = aggregator.finish(&rules, &[], &tuning);
+        assert_eq!(detect_infos.len(), 1);
+        assert_eq!(
+            detect_infos[0].aggregation,
+            Some("count() > 1 (count: 2)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_detect_suppressed() {
+        let rules = [parse_rule_from_str(
+            r#"
+        title: xattr
+        id: f5141b6d-9f42-41c6-a7bf-2a780678b29b
+        level: low
+        detection:
+            selection:
+                processImagePath|endswith: '/xattr'
+            condition: selection
+        "#,
+        )];
+        let data = create_logdata("/usr/bin/xattr", "xattr -l /Applications/EDR.app");
+        let tuning_yaml = |action: &str| {
+            YamlLoader::load_from_str(&format!(
+                r#"
+        action: {}
+        suppressions:
+            - rule: f5141b6d-9f42-41c6-a7bf-2a780678b29b
+              selection:
+                  eventMessage|contains: 'EDR.app'
+        "#,
+                action
+            ))
+            .unwrap()
+        };
+
+        let tuning = Tuning::from_yaml(&tuning_yaml("drop")[0]).unwrap();
+        assert!(detect(&data, &rules, &tuning, &mut vec![]).is_empty());
+        let tuning = Tuning::from_yaml(&tuning_yaml("tag")[0]).unwrap();
+        let detect_infos = detect(&data, &rules, &tuning, &mut vec![]);
+        assert_eq!(detect_infos.len(), 1);
+        assert!(detect_infos[0].suppressed);
+    }
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn test_detect_aggregations() {
+        let rules = [parse_rule_from_str(
+            r#"
+        title: sudo brute force
+        id: 5b5e9a8b-7f0e-4e0f-9c5a-2a8e1b0c6d3f
+        level: medium
+        logsource:
+            product: macos
+            category: authentication
+        detection:
+            selection:
+                AuthenticationMethod: sudo
+                Status: failure
+            timeframe: 5m
+            condition: selection | count() by TargetUserName > 2
+        "#,
+        )];
+        let tuning = Tuning::default();
+        let mut aggregator = Aggregator::default();
+        let mut detect_infos = vec![];
+        // sudoのeuidは常に0なので、認証したユーザーはTargetUserNameで区別する
+        for (minute, user) in ["admin", "alice", "admin", "admin"].iter().enumerate() {
+            let message = format!(
+                "{} : 3 incorrect password attempts ; TTY=ttys000 ; PWD=/Users/{} ; USER=root ; COMMAND=/bin/ls",
+                user, user
+            );
+            let mut data = create_logdata("/usr/bin/sudo", &message);
+            data.time += minute as f64 * 60_000_000_000.0;
+            for event in synthesize_events(&data) {
+                // 集計条件のあるルールは、ログ毎には検知しない
+                let mut aggregation_hits = vec![];
+                assert!(detect(&event, &rules, &tuning, &mut aggregation_hits).is_empty());
+                assert_eq!(aggregation_hits, vec![0]);
+                // timeframeの間に閾値を超えた時点で検知する
+                detect_infos.extend(aggregator.add(
+                    &event,
+                    &aggregation_hits,
+                    &rules,
+                    &[],
+                    &tuning,
+                ));
+            }
+        }
+        assert_eq!(detect_infos.len(), 1);
+        assert!(aggregator.finish(&rules, &[], &tuning).is_empty());
+        assert_eq!(detect_infos[0].ruletitle, "sudo brute force");
+        assert_eq!(
+            detect_infos[0].aggregation,
+            Some("count() by TargetUserName > 2 (count: 3, TargetUserName: admin)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_detect_correlations() {
+        let mut rules: Vec<_> = ["curl", "xattr"]
+            .iter()
+            .map(|process| {
+                parse_rule_from_str(&format!(
+                    r#"
+        title: {} execution
+        name: {}_execution
+        detection:
+            selection:
+                process: {}
+            condition: selection
+        "#,
+                    process, process, process
+                ))
+            })
+            .collect();
+        let yaml = YamlLoader::load_from_str(
+            r#"
+        title: Download and quarantine removal
+        id: 6a7d3b1e-2c4f-4e8a-9b0d-5f1c2e3a4b5c
+        level: high
+        correlation:
+            type: temporal_ordered
+            rules:
+                - curl_execution
+                - xattr_execution
+            timespan: 5m
+        "#,
+        )
+        .unwrap()
+        .remove(0);
+        let mut correlation = CorrelationRule::new("correlation.yml".to_string(), yaml);
+        correlation.init(&rules, &HashSet::new()).unwrap();
+        let correlations = [correlation];
+        mark_correlated_rules(&mut rules, &correlations);
+
+        // 相関ルールから参照されているルールは、単独では検知しない
+        let tuning = Tuning::default();
+        let mut aggregator = Aggregator::default();
+        let curl = create_logdata("/usr/bin/curl", "curl -o a.zip");
+        let mut xattr = create_logdata("/usr/bin/xattr", "xattr -c a.app");
+        xattr.time += 60_000_000_000.0;
+        let mut detect_infos = vec![];
+        for record in [&curl, &xattr] {
+            let mut aggregation_hits = vec![];
+            assert!(detect(record, &rules, &tuning, &mut aggregation_hits).is_empty());
+            detect_infos.extend(aggregator.add(
+                record,
+                &aggregation_hits,
+                &rules,
+                &correlations,
+                &tuning,
+            ));
+        }
+        assert!(aggregator.finish(&rules, &correlations, &tuning).is_empty());
+
+        assert_eq!(detect_infos.len(), 1);
+        assert_eq!(detect_infos[0].ruletitle, "Download and quarantine removal");
+        assert_eq!(detect_infos[0].level, "high");
+        assert_eq!(detect_infos[0].logdata.process, "/usr/bin/xattr");
+        let titles: Vec<&str> = detect_infos[0]
+            .correlated_logs
+            .iter()
+            .map(|log| log.ruletitle.as_str())
+            .collect();
+        assert_eq!(titles, vec!["curl execution", "xattr execution"]);
     }
 }
@@ -0,0 +1,69 @@
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+// Errors raised while parsing the Unified Log data
+#[derive(Debug)]
+pub enum ParseError {
+    // Failed to collect the UUID text, dsc or timesync files used to build the log entries
+    Metadata {
+        kind: &'static str,
+        path: String,
+        reason: String,
+    },
+    // Failed to list the tracev3 files in a directory
+    ReadDir {
+        path: String,
+        reason: String,
+    },
+    // Failed to parse a tracev3 file
+    Tracev3 {
+        path: String,
+        reason: String,
+    },
+    // Failed to write the timeline
+    Output(String),
+}
+
+impl ParseError {
+    pub fn metadata(kind: &'static str, path: &Path, reason: impl fmt::Display) -> ParseError {
+        ParseError::Metadata {
+            kind,
+            path: path.display().to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn read_dir(path: &Path, reason: impl fmt::Display) -> ParseError {
+        ParseError::ReadDir {
+            path: path.display().to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn tracev3(path: &str, reason: impl fmt::Display) -> ParseError {
+        ParseError::Tracev3 {
+            path: path.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Metadata { kind, path, reason } => {
+                write!(f, "Failed to read {} files in {}: {}", kind, path, reason)
+            }
+            ParseError::ReadDir { path, reason } => {
+                write!(f, "Failed to read directory {}: {}", path, reason)
+            }
+            ParseError::Tracev3 { path, reason } => {
+                write!(f, "Failed to parse {}: {}", path, reason)
+            }
+            ParseError::Output(reason) => write!(f, "Failed to write the timeline: {}", reason),
+        }
+    }
+}
+
+impl Error for ParseError {}
@@ -0,0 +1,719 @@
+use lazy_static::lazy_static;
+use macos_unifiedlogs::unified_log::LogData;
+use regex::{Captures, Regex};
+use std::ops::{Deref, DerefMut};
+
+// Sigma logsource categories of the events synthesized from Unified Log messages.
+// A synthesized event is an EventRecord whose eventType is the category, so it is written to the timeline
+// and checked against the rules in the same way as the log entry it was extracted from
+pub const PROCESS_CREATION: &str = "process_creation";
+pub const FILE_EVENT: &str = "file_event";
+pub const TCC: &str = "tcc";
+pub const AUTHENTICATION: &str = "authentication";
+
+// A log entry or a synthesized event, as written to the timeline and checked against the rules.
+// The fields of a synthesized event that have no Unified Log counterpart (ex: the TCC service) are kept in
+// event_data, so the LogData fields keep the values of the log entry the event came from
+#[derive(Clone, Debug)]
+pub struct EventRecord {
+    pub data: LogData,
+    // Sigma field names and values (ex: ("Service", "kTCCServiceCamera"))
+    pub event_data: Vec<(&'static str, String)>,
+}
+
+impl EventRecord {
+    // Get the value of a field kept in event_data
+    pub fn get_event_data(&self, sigma_field: &str) -> Option<&str> {
+        self.event_data
+            .iter()
+            .find(|(name, _)| *name == sigma_field)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl From<LogData> for EventRecord {
+    fn from(data: LogData) -> Self {
+        EventRecord {
+            data,
+            event_data: vec![],
+        }
+    }
+}
+
+impl Deref for EventRecord {
+    type Target = LogData;
+
+    fn deref(&self) -> &LogData {
+        &self.data
+    }
+}
+
+impl DerefMut for EventRecord {
+    fn deref_mut(&mut self) -> &mut LogData {
+        &mut self.data
+    }
+}
+
+// Sigma fields of each synthesized event that are kept in EventRecord::event_data
+const EVENT_DATA_FIELDS: [(&str, &[&str]); 4] = [
+    // Image, User and ProcessId are those of the launched process.
+    // The Unified Log fields are those of the message, so the process is the one that logged the launch
+    (
+        PROCESS_CREATION,
+        &["Image", "CommandLine", "ParentImage", "User", "ProcessId"],
+    ),
+    // Operation is what was done to the file (ex: register). Image, User and ProcessId are those of the logging process
+    (
+        FILE_EVENT,
+        &["TargetFilename", "Operation", "Image", "User", "ProcessId"],
+    ),
+    // The client is the bundle id (or path) of the app the decision applies to,
+    // and Image, User and ProcessId are those of the client process, not of tccd
+    (
+        TCC,
+        &[
+            "Service",
+            "Client",
+            "AuthValue",
+            "AuthReason",
+            "AttributionChain",
+            "Image",
+            "User",
+            "ProcessId",
+        ],
+    ),
+    // The user is the account whose credentials were checked (ex: the user running sudo), and Status is success or failure.
+    // The Unified Log fields are those of the message, so Image and ProcessId are those of the logging process
+    (
+        AUTHENTICATION,
+        &[
+            "TargetUserName",
+            "AuthenticationMethod",
+            "Status",
+            "IpAddress",
+            "Tty",
+        ],
+    ),
+];
+
+// A known message format that a synthesized event is extracted from.
+// The named groups of the regex give the fields of the event.
+// process_creation:
+//   image: path of the launched executable (the first word of cmdline if missing)
+//   cmdline: command line (image if missing)
+//   parent: path of the launching process (the process that logged the message if missing)
+//   pid: process id of the launched process (empty if missing)
+//   user: user name or uid the process runs as (the uid of the logging process if missing)
+// file_event:
+//   path: path of the file. A file:// URL is converted to a path.
+//   The process that logged the message is used as the process that accessed the file, and the operation
+//   of the extractor as the Operation
+// authentication:
+//   user: account whose credentials were checked. If missing, the uid of the logging process unless it is root
+//         (ex: loginwindow runs as the logged in user, while authd runs as root and the user is left empty)
+//   failure: matched only when the authentication failed
+//   method: detail of the method, appended to the operation (ex: publickey for ssh)
+//   right: authorization right, converted to the method (ex: system.login.screensaver is a screen unlock)
+//   ip: address of the remote client
+//   tty: terminal the user authenticated on
+struct Extractor {
+    event_type: &'static str,
+    // Names of the processes that log the message
+    processes: &'static [&'static str],
+    regex: Regex,
+    // Operation on the file for file_event (ex: register), or the authentication method
+    operation: &'static str,
+}
+
+lazy_static! {
+    static ref EXTRACTORS: Vec<Extractor> = vec![
+        // sudo: "user : TTY=ttys000 ; PWD=/Users/user ; USER=root ; COMMAND=/usr/bin/id -u"
+        // A failed attempt ("user : 3 incorrect password attempts ; TTY=...") does not run the command
+        Extractor {
+            event_type: PROCESS_CREATION,
+            processes: &["sudo"],
+            regex: Regex::new(r"^\s*\S+ : TTY=.*?\bUSER=(?P<user>[^\s;]+)\s*;\s*COMMAND=(?P<cmdline>.+?)\s*$").unwrap(),
+            operation: "",
+        },
+        // launchd (subsystem com.apple.xpc.launchd): "[gui/501/com.example.agent [1234]:] Successfully spawned agent[1234] because ipc message"
+        // Only the executable name is logged, not its path
+        Extractor {
+            event_type: PROCESS_CREATION,
+            processes: &["launchd"],
+            regex: Regex::new(r"\bSuccessfully spawned (?P<image>\S+?)\[(?P<pid>\d+)\]").unwrap(),
+            operation: "",
+        },
+        // runningboardd: "[app<application.com.example.App.1234.5678(501)>:9012] Now tracking process"
+        // Only the RunningBoard identity (the bundle id for apps) is logged, not the path
+        Extractor {
+            event_type: PROCESS_CREATION,
+            processes: &["runningboardd"],
+            regex: Regex::new(r"^\s*\[(?:app|xpcservice|daemon|osservice)<(?P<image>[^<>()\[\]]+?)(?:\((?P<user>\d+)\))?>(?:\{[^}]*\})?:(?P<pid>\d+)\] Now tracking process").unwrap(),
+            operation: "",
+        },
+        // Gatekeeper checking the code signature of a launched executable:
+        // "GK process assessment: /Applications/Example.app/Contents/MacOS/Example <-- (/sbin/launchd, /sbin/launchd)"
+        // The paths in parentheses are the parent and the responsible process
+        Extractor {
+            event_type: PROCESS_CREATION,
+            processes: &["syspolicyd"],
+            regex: Regex::new(r"\bGK process assessment: (?P<image>/.+?) <-- \((?P<parent>/[^,)]+)").unwrap(),
+            operation: "",
+        },
+        // Background Task Management registering a login item, launch agent or launch daemon:
+        // "registerLaunchItem: result=no error, new item disposition=[enabled, allowed, visible, notified],
+        //  identifier=com.example.agent, url=file:///Library/LaunchAgents/com.example.agent.plist"
+        Extractor {
+            event_type: FILE_EVENT,
+            processes: &["backgroundtaskmanagementd"],
+            regex: Regex::new(r"\bregisterLaunchItem:.*?\burl=(?P<path>file://[^,\s]+)").unwrap(),
+            operation: "register",
+        },
+        // installd installing a package: "PackageKit: Touched bundle /Applications/Example.app"
+        Extractor {
+            event_type: FILE_EVENT,
+            processes: &["installd"],
+            regex: Regex::new(r"\bPackageKit: Touched bundle (?P<path>/.+?)\s*$").unwrap(),
+            operation: "install",
+        },
+        // launchd loading a launch agent or daemon (ex: launchctl load, launchctl bootstrap):
+        // "Bootstrapping service from /Library/LaunchDaemons/com.example.daemon.plist"
+        Extractor {
+            event_type: FILE_EVENT,
+            processes: &["launchd"],
+            regex: Regex::new(r"(?i)\b(?:load|bootstrap)\w*\b[^/]*?(?P<path>/\S+?\.plist)\b").unwrap(),
+            operation: "load",
+        },
+        // Launch Services or the quarantine of a downloaded file:
+        // "Setting quarantine attribute on /Users/admin/Downloads/Example.dmg"
+        Extractor {
+            event_type: FILE_EVENT,
+            processes: &["lsd", "CoreServicesUIAgent"],
+            regex: Regex::new(r#"(?i)\bquarantine\b.*?\b(?:on|for|of)\s+(?P<path>/[^\s"',]+)"#).unwrap(),
+            operation: "quarantine",
+        },
+        // XProtect scanning a file: "XProtect scan of /Users/admin/Downloads/Example.dmg"
+        Extractor {
+            event_type: FILE_EVENT,
+            processes: &["XprotectService", "XProtect", "XProtectRemediator", "syspolicyd"],
+            regex: Regex::new(r#"\bXProtect\b.*?\bscan\w*\s+(?:of\s+)?(?P<path>/[^\s"',]+)"#).unwrap(),
+            operation: "scan",
+        },
+        // fseventsd discarding the file system event history of a volume:
+        // "event logs in /Volumes/Data/.fseventsd out of sync with volume.  destroying old logs. (3 0 2)"
+        Extractor {
+            event_type: FILE_EVENT,
+            processes: &["fseventsd"],
+            regex: Regex::new(r"\bevent logs in (?P<path>/\S+) out of sync with volume\.\s+destroying old logs").unwrap(),
+            operation: "delete",
+        },
+        // sudo: "user : TTY=ttys000 ; ..." or "user : 3 incorrect password attempts ; TTY=ttys000 ; ..."
+        Extractor {
+            event_type: AUTHENTICATION,
+            processes: &["sudo"],
+            regex: Regex::new(r"^\s*(?P<user>\S+) : (?:(?P<failure>\d+ incorrect password attempts?|user NOT in sudoers|a password is required) ; )?TTY=(?P<tty>[^\s;]+)").unwrap(),
+            operation: "sudo",
+        },
+        // su: "admin to root on /dev/ttys001" or "BAD SU admin to root on /dev/ttys001"
+        Extractor {
+            event_type: AUTHENTICATION,
+            processes: &["su"],
+            regex: Regex::new(r"^\s*(?P<failure>BAD SU )?\S+ to (?P<user>\S+) on (?P<tty>\S+)").unwrap(),
+            operation: "su",
+        },
+        // sshd: "Accepted publickey for admin from 10.0.0.5 port 52144 ssh2: ED25519 SHA256:..."
+        // or "Failed password for invalid user test from 10.0.0.5 port 52144 ssh2"
+        Extractor {
+            event_type: AUTHENTICATION,
+            processes: &["sshd", "sshd-session", "sshd-auth"],
+            regex: Regex::new(r"^\s*(?:Accepted|(?P<failure>Failed)) (?P<method>\S+) for (?:invalid user )?(?P<user>\S+) from (?P<ip>\S+) port \d+").unwrap(),
+            operation: "ssh",
+        },
+        // Screen Sharing: "Authentication: SUCCEEDED :: User Name: admin :: Viewer Address: 10.0.0.5 :: Type: DH"
+        Extractor {
+            event_type: AUTHENTICATION,
+            processes: &["screensharingd"],
+            regex: Regex::new(r"\bAuthentication: (?:SUCCEEDED|(?P<failure>FAILED)) :: User Name: (?P<user>.*?) :: Viewer Address: (?P<ip>\S+)").unwrap(),
+            operation: "screen sharing",
+        },
+        // Wrong password of a local account (ex: at the login window): "Failed to authenticate user <admin> (error: 9)."
+        Extractor {
+            event_type: AUTHENTICATION,
+            processes: &["opendirectoryd"],
+            regex: Regex::new(r"\b(?P<failure>Failed) to authenticate user <(?P<user>[^>]*)>").unwrap(),
+            operation: "password",
+        },
+        // loginwindow of the logged in user notifying the session of a screen unlock:
+        // "-[SessionAgentNotificationCenter sendBSDNotification:withOptions:] | Sending BSD notification: com.apple.sessionagent.screenIsUnlocked"
+        Extractor {
+            event_type: AUTHENTICATION,
+            processes: &["loginwindow"],
+            regex: Regex::new(r"\bSending BSD notification: com\.apple\.sessionagent\.screenIsUnlocked\b").unwrap(),
+            operation: "screen unlock",
+        },
+        // Authorization of the rights that need the user's credentials (login, screen unlock, administrator):
+        // "Succeeded authorizing right 'system.login.screensaver' by client '/System/Library/...' [123] for authorization created by ..."
+        // The user is not logged
+        Extractor {
+            event_type: AUTHENTICATION,
+            processes: &["authd"],
+            regex: Regex::new(r"\b(?:Succeeded authorizing|(?P<failure>Failed to authorize)) right '(?P<right>system\.login\.console|system\.login\.screensaver|system\.privilege\.admin)'").unwrap(),
+            operation: "",
+        },
+    ];
+}
+
+// Whether a Sigma field is stored in a different place in the synthesized events
+pub fn is_event_field(sigma_field: &str) -> bool {
+    EVENT_DATA_FIELDS
+        .iter()
+        .any(|(_, fields)| fields.contains(&sigma_field))
+}
+
+// Whether a log entry is a synthesized event rather than a log entry read from the tracev3 files
+pub fn is_synthesized_event(event_type: &str) -> bool {
+    [PROCESS_CREATION, FILE_EVENT, TCC, AUTHENTICATION].contains(&event_type)
+}
+
+// Extract the synthesized events from a log entry. Most log entries have none
+pub fn synthesize_events(data: &LogData) -> Vec<EventRecord> {
+    let process = data.process.rsplit('/').next().unwrap_or_default();
+    EXTRACTORS
+        .iter()
+        .filter(|extractor| extractor.processes.contains(&process))
+        .filter_map(|extractor| {
+            let captures = extractor.regex.captures(&data.message)?;
+            match extractor.event_type {
+                FILE_EVENT => create_file_event(data, extractor, &captures),
+                AUTHENTICATION => Some(create_authentication(data, extractor, &captures)),
+                _ => Some(create_process_creation(data, extractor, &captures)),
+            }
+        })
+        .collect()
+}
+
+fn create_process_creation(
+    data: &LogData,
+    extractor: &Extractor,
+    captures: &Captures,
+) -> EventRecord {
+    let group = |name| captures.name(name).map(|m| m.as_str().trim());
+    let cmdline = group("cmdline");
+    let image = group("image")
+        .or_else(|| cmdline.and_then(|cmdline| cmdline.split_whitespace().next()))
+        .unwrap_or_default();
+    let user = match group("user") {
+        Some(user) => user.to_string(),
+        None => data.euid.to_string(),
+    };
+    EventRecord {
+        data: LogData {
+            event_type: extractor.event_type.to_string(),
+            ..data.clone()
+        },
+        event_data: vec![
+            ("Image", image.to_string()),
+            ("CommandLine", cmdline.unwrap_or(image).to_string()),
+            (
+                "ParentImage",
+                group("parent").unwrap_or(&data.process).to_string(),
+            ),
+            ("User", user),
+            ("ProcessId", group("pid").unwrap_or_default().to_string()),
+        ],
+    }
+}
+
+fn create_file_event(
+    data: &LogData,
+    extractor: &Extractor,
+    captures: &Captures,
+) -> Option<EventRecord> {
+    let path = captures.name("path")?.as_str().trim();
+    let path = match path.strip_prefix("file://") {
+        Some(url_path) => decode_url_path(url_path),
+        None => path.to_string(),
+    };
+    Some(EventRecord {
+        data: LogData {
+            event_type: extractor.event_type.to_string(),
+            ..data.clone()
+        },
+        event_data: vec![
+            ("TargetFilename", path),
+            ("Operation", extractor.operation.to_string()),
+            ("Image", data.process.to_owned()),
+            ("User", data.euid.to_string()),
+            ("ProcessId", data.pid.to_string()),
+        ],
+    })
+}
+
+fn create_authentication(
+    data: &LogData,
+    extractor: &Extractor,
+    captures: &Captures,
+) -> EventRecord {
+    let group = |name| captures.name(name).map(|m| m.as_str().trim());
+    let method = match (group("method"), group("right")) {
+        (Some(method), _) => format!("{} {}", extractor.operation, method),
+        (None, Some(right)) => authorization_method(right).to_string(),
+        (None, None) => extractor.operation.to_string(),
+    };
+    let status = if group("failure").is_some() {
+        "failure"
+    } else {
+        "success"
+    };
+    let user = match group("user") {
+        Some(user) => user.to_string(),
+        None if data.euid != 0 => data.euid.to_string(),
+        None => String::default(),
+    };
+    EventRecord {
+        data: LogData {
+            event_type: extractor.event_type.to_string(),
+            ..data.clone()
+        },
+        event_data: vec![
+            ("TargetUserName", user),
+            ("AuthenticationMethod", method),
+            ("Status", status.to_string()),
+            ("IpAddress", group("ip").unwrap_or_default().to_string()),
+            ("Tty", group("tty").unwrap_or_default().to_string()),
+        ],
+    }
+}
+
+// Authentication method of an authorization right that needs the user's credentials
+fn authorization_method(right: &str) -> &'static str {
+    match right {
+        "system.login.console" => "login",
+        "system.login.screensaver" => "screen unlock",
+        _ => "admin authorization",
+    }
+}
+
+// Decode the percent-encoded characters (ex: %20) of the path of a file:// URL.
+// Invalid escapes are left as they are
+fn decode_url_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) if bytes[i] == b'%' => {
+                decoded.push(byte);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::{
+        decode_url_path, is_event_field, synthesize_events, AUTHENTICATION, FILE_EVENT,
+        PROCESS_CREATION,
+    };
+    use crate::rule::fieldmapping::get_event_value;
+    use crate::test_helpers::{create_logdata, parse_rule_from_str};
+
+    #[test]
+    fn test_synthesize_sudo() {
+        let mut data = create_logdata(
+            "/usr/bin/sudo",
+            "    admin : TTY=ttys000 ; PWD=/Users/admin ; USER=root ; COMMAND=/usr/bin/dscl . -create /Users/hidden IsHidden 1",
+        );
+        data.euid = 501;
+        // sudoの認証のauthenticationも作成する
+        let events = synthesize_events(&data);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].event_type, AUTHENTICATION);
+        let event = &events[0];
+        assert_eq!(event.event_type, PROCESS_CREATION);
+        let value = |key| get_event_value(event, key).unwrap();
+        assert_eq!(value("Image"), "/usr/bin/dscl");
+        assert_eq!(
+            value("CommandLine"),
+            "/usr/bin/dscl . -create /Users/hidden IsHidden 1"
+        );
+        assert_eq!(value("ParentImage"), "/usr/bin/sudo");
+        assert_eq!(value("User"), "root");
+        assert_eq!(value("ProcessId"), "");
+        // Unified Logのフィールドは元のログのまま
+        assert_eq!(event.process, data.process);
+        assert_eq!(event.euid, 501);
+        assert_eq!(event.message, data.message);
+        assert_eq!(event.time, data.time);
+
+        // 既存のprocess_creationのルールで検知できる
+        let rule_node = parse_rule_from_str(
+            r#"
+        detection:
+            selection:
+                Image|endswith: '/dscl'
+                CommandLine|contains: 'IsHidden'
+            condition: selection
+        "#,
+        );
+        assert!(rule_node.select(event));
+        assert!(!rule_node.select(&data));
+
+        // 実行ユーザーはUSER=のユーザー名のまま
+        let data = create_logdata(
+            "/usr/bin/sudo",
+            "admin : TTY=ttys000 ; PWD=/Users/admin ; USER=alice ; COMMAND=/bin/ls",
+        );
+        let events = synthesize_events(&data);
+        assert_eq!(get_event_value(&events[0], "User").unwrap(), "alice");
+    }
+
+    #[test]
+    fn test_synthesize_launchd() {
+        let data = create_logdata(
+            "/sbin/launchd",
+            "[gui/501/com.example.agent [1234]:] Successfully spawned agent[1234] because ipc message from: Finder[500]",
+        );
+        let events = synthesize_events(&data);
+        assert_eq!(events.len(), 1);
+        let value = |key| get_event_value(&events[0], key).unwrap();
+        assert_eq!(value("Image"), "agent");
+        assert_eq!(value("ProcessId"), "1234");
+        assert_eq!(value("ParentImage"), "/sbin/launchd");
+        assert_eq!(value("User"), "0");
+        assert_eq!(events[0].process, "/sbin/launchd");
+
+        // 他のプロセスのログや、形式の違うメッセージからは作成しない
+        let data = create_logdata("/bin/zsh", "Successfully spawned agent[1234]");
+        assert!(synthesize_events(&data).is_empty());
+        let data = create_logdata("/usr/bin/sudo", "admin : a password is required");
+        assert!(synthesize_events(&data).is_empty());
+    }
+
+    #[test]
+    fn test_synthesize_runningboardd() {
+        let data = create_logdata(
+            "/usr/libexec/runningboardd",
+            "[app<application.com.example.App.1234.5678(501)>:9012] Now tracking process",
+        );
+        let events = synthesize_events(&data);
+        assert_eq!(events.len(), 1);
+        let value = |key| get_event_value(&events[0], key).unwrap();
+        assert_eq!(value("Image"), "application.com.example.App.1234.5678");
+        assert_eq!(value("ProcessId"), "9012");
+        assert_eq!(value("User"), "501");
+    }
+
+    #[test]
+    fn test_synthesize_gatekeeper() {
+        let data = create_logdata(
+            "/usr/libexec/syspolicyd",
+            "GK process assessment: /Users/admin/Downloads/Example.app/Contents/MacOS/Example <-- (/sbin/launchd, /sbin/launchd)",
+        );
+        let events = synthesize_events(&data);
+        assert_eq!(events.len(), 1);
+        let value = |key| get_event_value(&events[0], key).unwrap();
+        assert_eq!(
+            value("Image"),
+            "/Users/admin/Downloads/Example.app/Contents/MacOS/Example"
+        );
+        assert_eq!(value("CommandLine"), value("Image"));
+        assert_eq!(value("ParentImage"), "/sbin/launchd");
+    }
+
+    #[test]
+    fn test_synthesize_file_event() {
+        let data = create_logdata(
+            "/System/Library/PrivateFrameworks/BackgroundTaskManagement.framework/Resources/backgroundtaskmanagementd",
+            "registerLaunchItem: result=no error, new item disposition=[enabled, allowed, visible, notified], identifier=com.example.agent, url=file:///Library/StartupItems/Example%20Agent/StartupParameters.plist",
+        );
+        let events = synthesize_events(&data);
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.event_type, FILE_EVENT);
+        assert_eq!(
+            get_event_value(event, "TargetFilename").unwrap(),
+            "/Library/StartupItems/Example Agent/StartupParameters.plist"
+        );
+        assert_eq!(get_event_value(event, "Operation").unwrap(), "register");
+        assert_eq!(get_event_value(event, "Image").unwrap(), data.process);
+        // Unified Logのフィールドは元のログのまま
+        assert_eq!(event.category, data.category);
+        assert_eq!(event.message, data.message);
+
+        // file_eventのルールで検知できる
+        let rule_node = parse_rule_from_str(
+            r#"
+        detection:
+            selection:
+                TargetFilename|contains: '/Library/StartupItems/'
+                TargetFilename|endswith: '.plist'
+            condition: selection
+        "#,
+        );
+        assert!(rule_node.select(event));
+
+        let file_event = |process, message| {
+            let events = synthesize_events(&create_logdata(process, message));
+            assert_eq!(events.len(), 1, "{message}");
+            let value = |key| get_event_value(&events[0], key).unwrap();
+            ["TargetFilename", "Operation"].map(value)
+        };
+        assert_eq!(
+            file_event(
+                "/System/Library/PrivateFrameworks/PackageKit.framework/Resources/installd",
+                "PackageKit: Touched bundle /Applications/Example.app"
+            ),
+            ["/Applications/Example.app", "install"]
+        );
+        assert_eq!(
+            file_event(
+                "/sbin/launchd",
+                "Bootstrapping service from /Library/LaunchDaemons/com.example.daemon.plist"
+            ),
+            ["/Library/LaunchDaemons/com.example.daemon.plist", "load"]
+        );
+        assert_eq!(
+            file_event(
+                "/System/Library/CoreServices/CoreServicesUIAgent.app/Contents/MacOS/CoreServicesUIAgent",
+                "Setting quarantine attribute on /Users/admin/Downloads/Example.dmg"
+            ),
+            ["/Users/admin/Downloads/Example.dmg", "quarantine"]
+        );
+        assert_eq!(
+            file_event(
+                "/Library/Apple/System/Library/CoreServices/XProtect.app/Contents/XPCServices/XprotectService.xpc/Contents/MacOS/XprotectService",
+                "XProtect scan of /Users/admin/Downloads/Example.dmg"
+            ),
+            ["/Users/admin/Downloads/Example.dmg", "scan"]
+        );
+        assert_eq!(
+            file_event(
+                "/System/Library/Frameworks/CoreServices.framework/Versions/A/Frameworks/FSEvents.framework/Versions/A/Support/fseventsd",
+                "event logs in /Volumes/Data/.fseventsd out of sync with volume.  destroying old logs. (3 0 2)"
+            ),
+            ["/Volumes/Data/.fseventsd", "delete"]
+        );
+    }
+
+    #[test]
+    fn test_synthesize_authentication() {
+        let authentication = |process, message| {
+            let events: Vec<_> = synthesize_events(&create_logdata(process, message))
+                .into_iter()
+                .filter(|event| event.event_type == AUTHENTICATION)
+                .collect();
+            assert_eq!(events.len(), 1, "{message}");
+            let value = |key| get_event_value(&events[0], key).unwrap();
+            [
+                "TargetUserName",
+                "AuthenticationMethod",
+                "Status",
+                "IpAddress",
+                "Tty",
+            ]
+            .map(value)
+        };
+
+        // 失敗したsudoではコマンドが実行されないため、process_creationは作成しない
+        let message = "admin : 3 incorrect password attempts ; TTY=ttys000 ; PWD=/Users/admin ; USER=root ; COMMAND=/bin/ls";
+        assert_eq!(
+            authentication("/usr/bin/sudo", message),
+            ["admin", "sudo", "failure", "", "ttys000"]
+        );
+        let data = create_logdata("/usr/bin/sudo", message);
+        let events = synthesize_events(&data);
+        assert_eq!(events.len(), 1);
+        // Unified Logのフィールドは元のログのまま
+        assert_eq!(events[0].subsystem, data.subsystem);
+        assert_eq!(events[0].category, data.category);
+        assert_eq!(events[0].message, data.message);
+        assert_eq!(
+            authentication("/usr/bin/su", "BAD SU admin to root on /dev/ttys001"),
+            ["root", "su", "failure", "", "/dev/ttys001"]
+        );
+        assert_eq!(
+            authentication(
+                "/usr/sbin/sshd",
+                "Accepted publickey for admin from 10.0.0.5 port 52144 ssh2: ED25519 SHA256:abc"
+            ),
+            ["admin", "ssh publickey", "success", "10.0.0.5", ""]
+        );
+        assert_eq!(
+            authentication(
+                "/usr/libexec/sshd-session",
+                "Failed password for invalid user test from 2001:db8::1 port 52144 ssh2"
+            ),
+            ["test", "ssh password", "failure", "2001:db8::1", ""]
+        );
+        assert_eq!(
+            authentication(
+                "/System/Library/CoreServices/RemoteManagement/screensharingd.bundle/Contents/MacOS/screensharingd",
+                "Authentication: SUCCEEDED :: User Name: admin :: Viewer Address: 10.0.0.7 :: Type: DH"
+            ),
+            ["admin", "screen sharing", "success", "10.0.0.7", ""]
+        );
+        assert_eq!(
+            authentication(
+                "/usr/libexec/opendirectoryd",
+                "Failed to authenticate user <admin> (error: 9)."
+            ),
+            ["admin", "password", "failure", "", ""]
+        );
+        assert_eq!(
+            authentication(
+                "/System/Library/Frameworks/Security.framework/Versions/A/XPCServices/authd.xpc/Contents/MacOS/authd",
+                "Succeeded authorizing right 'system.login.screensaver' by client '/System/Library/Frameworks/Security.framework/Versions/A/MachServices/SecurityAgent.bundle' [512] for authorization created by '/System/Library/CoreServices/loginwindow.app' [402] (3,0)"
+            ),
+            ["", "screen unlock", "success", "", ""]
+        );
+        // loginwindowはログインしたユーザーで動くので、そのuidをユーザーとする
+        let mut data = create_logdata(
+            "/System/Library/CoreServices/loginwindow.app/Contents/MacOS/loginwindow",
+            "-[SessionAgentNotificationCenter sendBSDNotification:withOptions:] | Sending BSD notification: com.apple.sessionagent.screenIsUnlocked",
+        );
+        data.euid = 501;
+        let events = synthesize_events(&data);
+        assert_eq!(events.len(), 1);
+        let value = |key| get_event_value(&events[0], key).unwrap();
+        assert_eq!(value("TargetUserName"), "501");
+        assert_eq!(value("AuthenticationMethod"), "screen unlock");
+        assert_eq!(value("Status"), "success");
+
+        // 認証と関係の無い権限からは作成しない
+        let data = create_logdata(
+            "/System/Library/Frameworks/Security.framework/Versions/A/XPCServices/authd.xpc/Contents/MacOS/authd",
+            "Succeeded authorizing right 'com.apple.ServiceManagement.daemons.modify' by client '/usr/libexec/smd' [120] for authorization created by '/usr/libexec/smd' [120] (2,0)",
+        );
+        assert!(synthesize_events(&data).is_empty());
+    }
+
+    #[test]
+    fn test_decode_url_path() {
+        assert_eq!(
+            decode_url_path("/Users/a%20b/c.plist"),
+            "/Users/a b/c.plist"
+        );
+        assert_eq!(decode_url_path("/%E3%81%82"), "/\u{3042}");
+        assert_eq!(decode_url_path("/100%/%zz%2"), "/100%/%zz%2");
+    }
+
+    #[test]
+    fn test_is_event_field() {
+        assert!(is_event_field("ParentImage"));
+        assert!(is_event_field("TargetFilename"));
+        assert!(is_event_field("TargetUserName"));
+        assert!(!is_event_field("eventMessage"));
+        assert!(!is_event_field("ThreadId"));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::args::RuleOption;
+use crate::yml::RULE_LEVELS;
+use yaml_rust::Yaml;
+
+// Check whether a rule is selected by the --min-level, --include/exclude-status, --include/exclude-tag
+// and --logsource-category options. The metadata of the rule is read from its YAML
+pub fn is_target_rule(yaml: &Yaml, opt: &RuleOption) -> bool {
+    if let Some(min_level) = &opt.min_level {
+        // A rule without a known level is treated as informational
+        let level = yaml["level"].as_str().unwrap_or_default().to_lowercase();
+        if level_index(&level).unwrap_or(0) < level_index(min_level).unwrap_or(0) {
+            return false;
+        }
+    }
+
+    let status = yaml["status"].as_str().unwrap_or_default().to_lowercase();
+    if !opt.include_status.is_empty() && !opt.include_status.contains(&status) {
+        return false;
+    }
+    if opt.exclude_status.contains(&status) {
+        return false;
+    }
+
+    let tags: Vec<String> = yaml["tags"]
+        .as_vec()
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|tag| tag.as_str())
+                .map(|tag| tag.to_lowercase())
+                .collect()
+        })
+        .unwrap_or_default();
+    if !opt.include_tag.is_empty() && !contains_tag(&tags, &opt.include_tag) {
+        return false;
+    }
+    if contains_tag(&tags, &opt.exclude_tag) {
+        return false;
+    }
+
+    if !opt.logsource_category.is_empty() {
+        let category = yaml["logsource"]["category"].as_str().unwrap_or_default();
+        if !opt
+            .logsource_category
+            .iter()
+            .any(|target| target.eq_ignore_ascii_case(category))
+        {
+            return false;
+        }
+    }
+    true
+}
+
+fn level_index(level: &str) -> Option<usize> {
+    RULE_LEVELS.iter().position(|l| *l == level)
+}
+
+// A filter tag matches the same tag and its sub-techniques (attack.t1553 matches attack.t1553.001)
+fn contains_tag(tags: &[String], filter_tags: &[String]) -> bool {
+    filter_tags.iter().any(|filter_tag| {
+        let filter_tag = filter_tag.to_lowercase();
+        tags.iter().any(|tag| {
+            tag == &filter_tag
+                || matches!(tag.strip_prefix(&filter_tag), Some(rest) if rest.starts_with('.'))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::args::RuleOption;
+    use crate::filter::is_target_rule;
+    use yaml_rust::YamlLoader;
+
+    fn rule_option() -> RuleOption {
+        RuleOption {
+            rules: vec![],
+            no_default_rules: false,
+            fail_on_rule_error: false,
+            tuning: None,
+            min_level: None,
+            include_status: vec![],
+            exclude_status: vec![],
+            include_tag: vec![],
+            exclude_tag: vec![],
+            logsource_category: vec![],
+        }
+    }
+
+    #[test]
+    fn test_is_target_rule() {
+        let yaml = YamlLoader::load_from_str(
+            r#"
+        title: Gatekeeper Bypass via Xattr
+        status: test
+        level: low
+        tags:
+            - attack.defense_evasion
+            - attack.t1553.001
+        logsource:
+            category: process_creation
+            product: macos
+        "#,
+        )
+        .unwrap()
+        .remove(0);
+        assert!(is_target_rule(&yaml, &rule_option()));
+
+        let is_target = |update: fn(&mut RuleOption)| {
+            let mut opt = rule_option();
+            update(&mut opt);
+            is_target_rule(&yaml, &opt)
+        };
+        assert!(is_target(|opt| opt.min_level = Some("low".to_string())));
+        assert!(!is_target(|opt| opt.min_level = Some("medium".to_string())));
+        assert!(is_target(
+            |opt| opt.include_status = vec!["stable".to_string(), "test".to_string()]
+        ));
+        assert!(!is_target(
+            |opt| opt.include_status = vec!["stable".to_string()]
+        ));
+        assert!(!is_target(
+            |opt| opt.exclude_status = vec!["test".to_string()]
+        ));
+        // サブテクニックも親のテクニックのタグで選択できる
+        assert!(is_target(
+            |opt| opt.include_tag = vec!["attack.t1553".to_string()]
+        ));
+        assert!(!is_target(
+            |opt| opt.include_tag = vec!["attack.t155".to_string()]
+        ));
+        assert!(!is_target(
+            |opt| opt.exclude_tag = vec!["attack.defense_evasion".to_string()]
+        ));
+        assert!(is_target(|opt| opt.logsource_category =
+            vec!["file_event".to_string(), "process_creation".to_string()]));
+        assert!(!is_target(
+            |opt| opt.logsource_category = vec!["file_event".to_string()]
+        ));
+    }
+}
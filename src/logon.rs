@@ -0,0 +1,164 @@
+use crate::events::EventRecord;
+use crate::rule::fieldmapping::get_event_value;
+use chrono::{SecondsFormat, TimeZone, Utc};
+use hashbrown::HashMap;
+
+// Logons with the same result, user, method and source address, counted by logon-summary
+#[derive(Debug, PartialEq)]
+pub struct LogonCount {
+    pub successful: bool,
+    pub user: String,
+    pub method: String,
+    pub source_ip: String,
+    pub count: usize,
+    pub first_time: f64,
+    pub last_time: f64,
+}
+
+// Count the authentication events per result, user, method and source address.
+// The events without a user (ex: the rights authorized by authd) are not counted.
+// The successful logons come first, each sorted with the most frequent logons first
+pub fn summarize_logons(events: &[EventRecord]) -> Vec<LogonCount> {
+    let mut counts: HashMap<(bool, String, String, String), LogonCount> = HashMap::new();
+    for event in events.iter().filter(|event| has_user(event)) {
+        let value = |key| get_event_value(event, key).unwrap_or_default();
+        let successful = value("Status") == "success";
+        let user = value("TargetUserName");
+        let method = value("AuthenticationMethod");
+        let source_ip = value("IpAddress");
+        let count = counts
+            .entry((successful, user.clone(), method.clone(), source_ip.clone()))
+            .or_insert_with(|| LogonCount {
+                successful,
+                user,
+                method,
+                source_ip,
+                count: 0,
+                first_time: event.time,
+                last_time: event.time,
+            });
+        count.count += 1;
+        count.first_time = count.first_time.min(event.time);
+        count.last_time = count.last_time.max(event.time);
+    }
+    let mut counts: Vec<LogonCount> = counts.into_values().collect();
+    counts.sort_by(|a, b| {
+        b.successful
+            .cmp(&a.successful)
+            .then(b.count.cmp(&a.count))
+            .then_with(|| {
+                (&a.user, &a.method, &a.source_ip).cmp(&(&b.user, &b.method, &b.source_ip))
+            })
+    });
+    counts
+}
+
+// Whether the user of an authentication event is known, so that it can be counted per user
+pub fn has_user(event: &EventRecord) -> bool {
+    !get_event_value(event, "TargetUserName")
+        .unwrap_or_default()
+        .is_empty()
+}
+
+pub fn print_logon_summary(counts: &[LogonCount]) {
+    for (successful, heading) in [(true, "Successful logons"), (false, "Failed logons")] {
+        let counts: Vec<&LogonCount> = counts
+            .iter()
+            .filter(|count| count.successful == successful)
+            .collect();
+        println!();
+        println!(
+            "{}: {}",
+            heading,
+            counts.iter().map(|count| count.count).sum::<usize>()
+        );
+        if counts.is_empty() {
+            continue;
+        }
+        println!(
+            "  {:>6}  {:<16}  {:<20}  {:<16}  {:<20}  Last",
+            "Count", "User", "Method", "Source IP", "First"
+        );
+        // The source address is only logged for remote logons
+        let or_dash = |value: &str| {
+            if value.is_empty() {
+                "-".to_string()
+            } else {
+                value.to_string()
+            }
+        };
+        for count in counts {
+            println!(
+                "  {:>6}  {:<16}  {:<20}  {:<16}  {:<20}  {}",
+                count.count,
+                or_dash(&count.user),
+                or_dash(&count.method),
+                or_dash(&count.source_ip),
+                format_time(count.first_time),
+                format_time(count.last_time)
+            );
+        }
+    }
+}
+
+pub fn format_time(time: f64) -> String {
+    Utc.timestamp_nanos(time as i64)
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::synthesize_events;
+    use crate::logon::{has_user, summarize_logons, LogonCount};
+    use crate::test_helpers::create_logdata;
+
+    #[test]
+    fn test_summarize_logons() {
+        let messages = [
+            "Failed password for admin from 10.0.0.5 port 52144 ssh2",
+            "Failed password for admin from 10.0.0.5 port 52145 ssh2",
+            "Accepted password for admin from 10.0.0.5 port 52146 ssh2",
+            "Failed password for invalid user test from 10.0.0.5 port 52147 ssh2",
+            "Failed password for admin from 10.0.0.9 port 40000 ssh2",
+            "Failed password for admin from 10.0.0.5 port 52148 ssh2",
+        ];
+        let mut events: Vec<_> = messages
+            .iter()
+            .enumerate()
+            .flat_map(|(i, message)| {
+                let mut data = create_logdata("/usr/sbin/sshd", message);
+                data.time += i as f64 * 1_000_000_000.0;
+                synthesize_events(&data)
+            })
+            .collect();
+        // ユーザーの分からないauthdの認証は数えない
+        let authd = synthesize_events(&create_logdata(
+            "/System/Library/Frameworks/Security.framework/Versions/A/XPCServices/authd.xpc/Contents/MacOS/authd",
+            "Succeeded authorizing right 'system.login.screensaver' by client '/System/Library/Frameworks/Security.framework/Versions/A/MachServices/SecurityAgent.bundle' [512] for authorization created by '/System/Library/CoreServices/loginwindow.app' [402] (3,0)",
+        ));
+        assert!(!has_user(&authd[0]));
+        events.extend(authd);
+        let counts = summarize_logons(&events);
+        let start = events[0].time;
+
+        // 成功したログオンが先で、それぞれ回数の多い順
+        let logon = |successful, user: &str, source_ip: &str, count, first, last| LogonCount {
+            successful,
+            user: user.to_string(),
+            method: "ssh password".to_string(),
+            source_ip: source_ip.to_string(),
+            count,
+            first_time: start + first as f64 * 1_000_000_000.0,
+            last_time: start + last as f64 * 1_000_000_000.0,
+        };
+        assert_eq!(
+            counts,
+            vec![
+                logon(true, "admin", "10.0.0.5", 1, 2, 2),
+                logon(false, "admin", "10.0.0.5", 3, 0, 5),
+                logon(false, "admin", "10.0.0.9", 1, 4, 4),
+                logon(false, "test", "10.0.0.5", 1, 3, 3),
+            ]
+        );
+    }
+}
@@ -1,20 +1,59 @@
+use crate::detection::print_detect_summary;
+use crate::error::ParseError;
+use crate::filter::is_target_rule;
+use crate::logon::{has_user, print_logon_summary, summarize_logons};
+use crate::output::{
+    create_file, output_detections, output_logon_summary, output_tcc_events, TimelineWriter,
+};
+use crate::rule::correlation::{
+    get_rule_names, get_rule_references, is_correlation_rule, mark_correlated_rules,
+    CorrelationRule,
+};
 use crate::rule::rulenode::RuleNode;
-use crate::yml::read_yaml_files;
-use args::{Action, AppArg};
+use crate::tuning::{load_tuning, Tuning};
+use crate::validate::validate_rules;
+use crate::yml::{load_rules, RuleError};
+use args::{
+    Action, AppArg, InputOption, LogonSummaryOption, RuleOption, TimelineOption,
+    ValidateRulesOption,
+};
 use clap::Parser;
+use hashbrown::HashSet;
 use libmimalloc_sys::mi_stats_print_out;
 use mimalloc::MiMalloc;
-use parser::{parse_live_system, parse_log_archive};
-use std::path::Path;
+use parser::{
+    parse_live_system, parse_log_archive, print_parse_failures, ParseResult, TimeFilter,
+    TimelineConfig,
+};
+use std::error::Error;
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+use std::process::ExitCode;
 use std::ptr::null_mut;
+use std::thread;
+use yaml_rust::Yaml;
 
 mod args;
 mod detection;
+mod error;
+mod events;
+mod filter;
+mod logon;
 mod output;
 mod parser;
+mod tcc;
+#[cfg(test)]
+mod test_helpers;
+mod tuning;
+mod validate;
 mod yml;
 mod rule {
+    pub mod aggregation_parser;
     pub mod condition_parser;
+    pub mod correlation;
+    pub mod count;
+    pub mod fieldmapping;
+    pub mod logsource;
     pub mod matchers;
     pub mod rulenode;
     pub mod selectionnodes;
@@ -23,39 +62,329 @@ mod rule {
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-fn main() {
+fn main() -> ExitCode {
     let cli = AppArg::parse();
-    let rule_folder = if Path::new("./rules").exists() {
-        Path::new("./rules")
-    } else {
-        Path::new("../../rules")
+    let exit_code = match cli.action {
+        Action::CsvTimeline(opt) => match load_rule_nodes(&opt.rule_option) {
+            Some(rules) => timeline(opt, &rules, TimelineWriter::csv),
+            None => ExitCode::from(EXIT_FAILURE),
+        },
+        Action::JsonTimeline(opt) => match load_rule_nodes(&opt.rule_option) {
+            Some(rules) => timeline(opt, &rules, TimelineWriter::jsonl),
+            None => ExitCode::from(EXIT_FAILURE),
+        },
+        Action::ValidateRules(opt) => validate(opt),
+        Action::LogonSummary(opt) => logon_summary(opt),
+    };
+    if cli.debug {
+        println!();
+        println!("Memory usage stats:");
+        unsafe {
+            mi_stats_print_out(None, null_mut());
+        }
+    }
+    exit_code
+}
+
+// Exit codes: 1 if nothing could be output (or a rule is invalid in validate-rules),
+// 2 if some of the log data could not be parsed
+const EXIT_FAILURE: u8 = 1;
+const EXIT_PARTIAL_FAILURE: u8 = 2;
+
+// Rules and settings applied to the log entries
+struct LoadedRules {
+    rule_nodes: Vec<RuleNode>,
+    correlations: Vec<CorrelationRule>,
+    tuning: Tuning,
+}
+
+// Load and initialize the rules selected by the rule filtering options and the tuning file.
+// A rule filtered out by the options is still loaded when a selected correlation rule references it,
+// but its own hits are not reported. Rules that cannot be loaded are reported and skipped.
+// Returns None if the rules cannot be loaded at all, or if any rule fails in strict mode
+fn load_rule_nodes(opt: &RuleOption) -> Option<LoadedRules> {
+    let tuning = match load_tuning(opt.tuning.as_ref()) {
+        Ok(tuning) => tuning,
+        Err(err) => {
+            eprintln!("Failed to load the tuning file. {}", err);
+            return None;
+        }
+    };
+    let mut rule_errors = vec![];
+    let yaml = match load_rules(&opt.rules, opt.no_default_rules, &mut rule_errors) {
+        Ok(yaml) => yaml,
+        Err(err) => {
+            eprintln!("Failed to load rules. {}", err);
+            return None;
+        }
     };
-    let yaml = read_yaml_files(rule_folder).unwrap();
-    let rule_nodes: Vec<RuleNode> = yaml
+    let (correlation_yaml, rule_yaml): (Vec<_>, Vec<_>) = yaml
+        .into_iter()
+        .partition(|(_, yaml_data)| is_correlation_rule(yaml_data));
+    // Correlation rules cannot reference other correlation rules, even the ones filtered out
+    let correlation_names: HashSet<String> = correlation_yaml
+        .iter()
+        .flat_map(|(_, yaml_data)| get_rule_names(yaml_data))
+        .collect();
+    let mut filtered_count = 0;
+    let correlation_yaml: Vec<_> = correlation_yaml
         .into_iter()
-        .map(|(path, yaml_data)| RuleNode::new(path, yaml_data))
-        .map(|mut rule: RuleNode| {
-            // TODO　エラーハンドリング
-            let _ = rule.init();
-            rule
+        .filter(|(_, yaml_data)| {
+            let is_target = is_target_rule(yaml_data, opt) && !tuning.is_disabled(yaml_data);
+            if !is_target {
+                filtered_count += 1;
+            }
+            is_target
         })
         .collect();
+    let references: HashSet<String> = correlation_yaml
+        .iter()
+        .flat_map(|(_, yaml_data)| get_rule_references(yaml_data))
+        .collect();
+    let is_referenced = |yaml_data: &Yaml| {
+        get_rule_names(yaml_data)
+            .iter()
+            .any(|name| references.contains(name))
+    };
 
-    match cli.action {
-        Action::CsvTimeline(opt) => {
-            if opt.live_analysis {
-                parse_live_system(opt.output)
-            } else {
-                parse_log_archive(opt.archive_dir.unwrap(), opt.output)
+    let mut rule_nodes = vec![];
+    let mut hidden_rules = vec![];
+    for (path, yaml_data) in rule_yaml {
+        let is_target = is_target_rule(&yaml_data, opt);
+        if tuning.is_disabled(&yaml_data) || (!is_target && !is_referenced(&yaml_data)) {
+            filtered_count += 1;
+            continue;
+        }
+        let mut rule = RuleNode::new(path, yaml_data);
+        match rule.init() {
+            Ok(_) => {
+                if !is_target {
+                    hidden_rules.push(rule_nodes.len());
+                }
+                rule_nodes.push(rule);
             }
+            Err(messages) => rule_errors.push(RuleError {
+                path: rule.rulepath,
+                messages,
+            }),
         }
     }
-    if cli.debug {
-        println!();
-        println!("Memory usage stats:");
-        unsafe {
-            mi_stats_print_out(None, null_mut());
+
+    let mut correlations = vec![];
+    for (path, yaml_data) in correlation_yaml {
+        let mut correlation = CorrelationRule::new(path, yaml_data);
+        match correlation.init(&rule_nodes, &correlation_names) {
+            Ok(_) => correlations.push(correlation),
+            Err(messages) => rule_errors.push(RuleError {
+                path: correlation.rulepath,
+                messages,
+            }),
+        }
+    }
+    mark_correlated_rules(&mut rule_nodes, &correlations);
+    for index in hidden_rules {
+        rule_nodes[index].generate = false;
+    }
+
+    for rule_error in &rule_errors {
+        eprintln!("Failed to load rule. {}", rule_error);
+    }
+    println!(
+        "Loaded {} rules ({} skipped, {} filtered out)",
+        rule_nodes.len() + correlations.len(),
+        rule_errors.len(),
+        filtered_count
+    );
+    if opt.fail_on_rule_error && !rule_errors.is_empty() {
+        eprintln!("Stopped because some rules could not be loaded. (--fail-on-rule-error)");
+        return None;
+    }
+    Some(LoadedRules {
+        rule_nodes,
+        correlations,
+        tuning,
+    })
+}
+
+// Print or save the rule validation report. Fails if any rule has an error
+fn validate(opt: ValidateRulesOption) -> ExitCode {
+    let report = match validate_rules(&opt.directory) {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    };
+    let written = match &opt.output {
+        Some(out) => create_file(out, opt.overwrite).and_then(|file| {
+            serde_json::to_writer_pretty(BufWriter::new(file), &report)?;
+            Ok(())
+        }),
+        None => serde_json::to_writer_pretty(io::stdout().lock(), &report)
+            .map(|_| println!())
+            .map_err(|err| err.into()),
+    };
+    if let Err(err) = written {
+        eprintln!("Failed to write the report: {}", err);
+        return ExitCode::from(EXIT_FAILURE);
+    }
+    if report["summary"]["invalid"] == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_FAILURE)
+    }
+}
+
+type CreateWriter = fn(&PathBuf, bool) -> Result<TimelineWriter, Box<dyn Error>>;
+
+fn timeline(opt: TimelineOption, rules: &LoadedRules, create_writer: CreateWriter) -> ExitCode {
+    for out in [&opt.detections, &opt.tcc].into_iter().flatten() {
+        if out.exists() && !opt.overwrite {
+            eprintln!(
+                "{} already exists. Specify --overwrite to replace it.",
+                out.display()
+            );
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    }
+    let mut writer = match create_writer(&opt.output, opt.overwrite) {
+        Ok(writer) => writer,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(EXIT_FAILURE);
         }
+    };
+    let result = match parse_logs(&opt.input, rules, &mut writer, opt.tcc.is_some(), false) {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    };
+    if let Err(err) = writer.flush() {
+        eprintln!("Failed to write the timeline: {}", err);
+        return ExitCode::from(EXIT_FAILURE);
+    }
+    println!("Saved results to: {}", opt.output.display());
+    print_detect_summary(&result.detect_infos);
+    if let Some(detections) = opt.detections {
+        match output_detections(&result.detect_infos, &detections, opt.overwrite) {
+            Ok(_) => println!("Saved detection results to: {}", detections.display()),
+            Err(err) => {
+                eprintln!("Failed to write detection results: {}", err);
+                return ExitCode::from(EXIT_FAILURE);
+            }
+        }
+    }
+    if let Some(tcc) = opt.tcc {
+        match output_tcc_events(&result.tcc_events, &tcc, opt.overwrite) {
+            Ok(_) => println!(
+                "Saved {} TCC decision(s) to: {}",
+                result.tcc_events.len(),
+                tcc.display()
+            ),
+            Err(err) => {
+                eprintln!("Failed to write TCC decisions: {}", err);
+                return ExitCode::from(EXIT_FAILURE);
+            }
+        }
+    }
+
+    print_parse_failures(&result.failures);
+    if result.failures.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_PARTIAL_FAILURE)
+    }
+}
+
+// Print or save the successful and failed logons per user. No rules are needed, only the authentication events
+fn logon_summary(opt: LogonSummaryOption) -> ExitCode {
+    if let Some(out) = &opt.output {
+        if out.exists() && !opt.overwrite {
+            eprintln!(
+                "{} already exists. Specify --overwrite to replace it.",
+                out.display()
+            );
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    }
+    let rules = LoadedRules {
+        rule_nodes: vec![],
+        correlations: vec![],
+        tuning: Tuning::default(),
+    };
+    let result = match parse_logs(
+        &opt.input,
+        &rules,
+        &mut TimelineWriter::Discard,
+        false,
+        true,
+    ) {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(EXIT_FAILURE);
+        }
+    };
+    let counts = summarize_logons(&result.authentication_events);
+    match &opt.output {
+        Some(out) => match output_logon_summary(&counts, out, opt.overwrite) {
+            Ok(_) => println!("Saved logon summary to: {}", out.display()),
+            Err(err) => {
+                eprintln!("Failed to write the logon summary: {}", err);
+                return ExitCode::from(EXIT_FAILURE);
+            }
+        },
+        None => print_logon_summary(&counts),
+    }
+    let unknown_users = result
+        .authentication_events
+        .iter()
+        .filter(|event| !has_user(event))
+        .count();
+    if unknown_users > 0 {
+        println!(
+            "{} authentication events without a user (ex: rights authorized by authd) are not included.",
+            unknown_users
+        );
+    }
+
+    print_parse_failures(&result.failures);
+    if result.failures.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_PARTIAL_FAILURE)
+    }
+}
+
+// Parse the log data selected by the input options and check the log entries against the rules.
+// The tcc and authentication events are only kept in memory with collect_tcc and collect_authentication
+fn parse_logs(
+    input: &InputOption,
+    rules: &LoadedRules,
+    writer: &mut TimelineWriter,
+    collect_tcc: bool,
+    collect_authentication: bool,
+) -> Result<ParseResult, ParseError> {
+    let config = TimelineConfig {
+        rules: &rules.rule_nodes,
+        correlations: &rules.correlations,
+        tuning: &rules.tuning,
+        time_filter: TimeFilter {
+            start: input.timeline_start.map(|t| t.timestamp_nanos()),
+            end: input.timeline_end.map(|t| t.timestamp_nanos()),
+        },
+        threads: input.threads.map_or_else(
+            || thread::available_parallelism().map_or(1, |n| n.get()),
+            usize::from,
+        ),
+        collect_tcc,
+        collect_authentication,
+    };
+    match &input.archive_dir {
+        Some(archive_dir) => parse_log_archive(archive_dir.to_owned(), writer, &config),
+        None => parse_live_system(writer, &config),
     }
 }
 
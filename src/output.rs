@@ -1,41 +1,474 @@
+use crate::detection::DetectInfo;
+use crate::events::EventRecord;
+use crate::logon::{format_time, LogonCount};
+use crate::rule::fieldmapping::get_event_value;
 use chrono::{SecondsFormat, TimeZone, Utc};
-use macos_unifiedlogs::unified_log::LogData;
+use csv::Writer;
+use serde_json::{json, Map, Value};
 use std::error::Error;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 
-pub fn output(results: &Vec<LogData>, out: &PathBuf) -> Result<(), Box<dyn Error>> {
-    let csv_file = OpenOptions::new().append(true).create(true).open(out)?;
-    let mut writer = csv::Writer::from_writer(csv_file);
-    for data in results {
+// Create the output file. An existing file is only replaced when overwrite is specified
+pub fn create_file(out: &PathBuf, overwrite: bool) -> Result<File, Box<dyn Error>> {
+    if out.exists() && !overwrite {
+        return Err(format!(
+            "{} already exists. Specify --overwrite to replace it.",
+            out.display()
+        )
+        .into());
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out)?;
+    Ok(file)
+}
+
+// Writer for the timeline output. Each log entry is written as a CSV row or a JSON Lines object.
+// Discard writes nothing, for the subcommands that only use the events collected during the run (ex: logon-summary)
+pub enum TimelineWriter {
+    Csv(Box<Writer<File>>),
+    Jsonl(BufWriter<File>),
+    Discard,
+}
+
+impl TimelineWriter {
+    // Create the timeline CSV writer and write the header row
+    pub fn csv(out: &PathBuf, overwrite: bool) -> Result<TimelineWriter, Box<dyn Error>> {
+        let mut writer = csv::Writer::from_writer(create_file(out, overwrite)?);
+        writer.write_record([
+            "Timestamp",
+            "EventType",
+            "LogType",
+            "Subsystem",
+            "ThreadID",
+            "PID",
+            "EUID",
+            "Library",
+            "LibraryUUID",
+            "ActivityID",
+            "Category",
+            "Process",
+            "ProcessUUID",
+            "Message",
+            "RawMessage",
+            "BootUUID",
+            "TimezoneName",
+            "EventData",
+        ])?;
+        Ok(TimelineWriter::Csv(Box::new(writer)))
+    }
+
+    // Create the timeline JSON Lines writer
+    pub fn jsonl(out: &PathBuf, overwrite: bool) -> Result<TimelineWriter, Box<dyn Error>> {
+        Ok(TimelineWriter::Jsonl(BufWriter::new(create_file(
+            out, overwrite,
+        )?)))
+    }
+
+    pub fn write(
+        &mut self,
+        data: &EventRecord,
+        detect_infos: &[DetectInfo],
+    ) -> Result<(), Box<dyn Error>> {
+        let date_time = Utc
+            .timestamp_nanos(data.time as i64)
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+        match self {
+            TimelineWriter::Csv(writer) => {
+                writer.write_record(&[
+                    date_time,
+                    data.event_type.to_owned(),
+                    data.log_type.to_owned(),
+                    data.subsystem.to_owned(),
+                    data.thread_id.to_string(),
+                    data.pid.to_string(),
+                    data.euid.to_string(),
+                    data.library.to_owned(),
+                    data.library_uuid.to_owned(),
+                    data.activity_id.to_string(),
+                    data.category.to_owned(),
+                    data.process.to_owned(),
+                    data.process_uuid.to_owned(),
+                    data.message.to_owned(),
+                    data.raw_message.to_owned(),
+                    data.boot_uuid.to_owned(),
+                    data.timezone_name.to_owned(),
+                    format_event_data(data),
+                ])?;
+            }
+            TimelineWriter::Jsonl(writer) => {
+                // The keys are the LogData field names, as in the JSON output of macos-unifiedlogs
+                let mut record = Map::new();
+                record.insert("timestamp".to_string(), Value::String(date_time));
+                for (key, value) in [
+                    ("subsystem", json!(data.subsystem)),
+                    ("thread_id", json!(data.thread_id)),
+                    ("pid", json!(data.pid)),
+                    ("euid", json!(data.euid)),
+                    ("library", json!(data.library)),
+                    ("library_uuid", json!(data.library_uuid)),
+                    ("activity_id", json!(data.activity_id)),
+                    ("time", json!(data.time)),
+                    ("category", json!(data.category)),
+                    ("event_type", json!(data.event_type)),
+                    ("log_type", json!(data.log_type)),
+                    ("process", json!(data.process)),
+                    ("process_uuid", json!(data.process_uuid)),
+                    ("message", json!(data.message)),
+                    ("raw_message", json!(data.raw_message)),
+                    ("boot_uuid", json!(data.boot_uuid)),
+                    ("timezone_name", json!(data.timezone_name)),
+                ] {
+                    record.insert(key.to_string(), value);
+                }
+                if !data.event_data.is_empty() {
+                    let event_data = data
+                        .event_data
+                        .iter()
+                        .map(|(name, value)| (name.to_string(), Value::String(value.to_owned())))
+                        .collect();
+                    record.insert("eventData".to_string(), Value::Object(event_data));
+                }
+                if !detect_infos.is_empty() {
+                    let detections = detect_infos
+                        .iter()
+                        .map(|info| {
+                            json!({
+                                "title": info.ruletitle,
+                                "id": info.ruleid,
+                                "level": info.level,
+                                "path": info.rulepath,
+                                "tags": info.tags,
+                                "suppressed": info.suppressed,
+                            })
+                        })
+                        .collect();
+                    record.insert("detections".to_string(), Value::Array(detections));
+                }
+                serde_json::to_writer(&mut *writer, &record)?;
+                writer.write_all(b"\n")?;
+            }
+            TimelineWriter::Discard => {}
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        match self {
+            TimelineWriter::Csv(writer) => writer.flush()?,
+            TimelineWriter::Jsonl(writer) => writer.flush()?,
+            TimelineWriter::Discard => {}
+        }
+        Ok(())
+    }
+}
+
+pub fn output_detections(
+    detect_infos: &[DetectInfo],
+    out: &PathBuf,
+    overwrite: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(create_file(out, overwrite)?);
+    writer.write_record([
+        "Timestamp",
+        "RuleTitle",
+        "Level",
+        "RulePath",
+        "RuleID",
+        "MitreTags",
+        "Process",
+        "PID",
+        "Subsystem",
+        "Category",
+        "Message",
+        "Suppressed",
+        "Aggregation",
+        "CorrelatedEvents",
+        "EventData",
+    ])?;
+
+    // Sort by timestamp so that the hits can be triaged in time order
+    let mut detect_infos: Vec<&DetectInfo> = detect_infos.iter().collect();
+    detect_infos.sort_by(|a, b| a.logdata.time.total_cmp(&b.logdata.time));
+    for info in detect_infos {
+        let data = &info.logdata;
         let date_time = Utc.timestamp_nanos(data.time as i64);
         writer.write_record(&[
             date_time.to_rfc3339_opts(SecondsFormat::Millis, true),
-            data.event_type.to_owned(),
-            data.log_type.to_owned(),
-            data.subsystem.to_owned(),
-            data.thread_id.to_string(),
+            info.ruletitle.to_owned(),
+            info.level.to_owned(),
+            info.rulepath.to_owned(),
+            info.ruleid.to_owned(),
+            info.tags.join(" ¦ "),
+            data.process.to_owned(),
             data.pid.to_string(),
-            data.euid.to_string(),
-            data.library.to_owned(),
-            data.library_uuid.to_owned(),
-            data.activity_id.to_string(),
+            data.subsystem.to_owned(),
             data.category.to_owned(),
-            data.process.to_owned(),
-            data.process_uuid.to_owned(),
             data.message.to_owned(),
-            data.raw_message.to_owned(),
-            data.boot_uuid.to_owned(),
-            data.timezone_name.to_owned(),
+            info.suppressed.to_string(),
+            info.aggregation.to_owned().unwrap_or_default(),
+            info.correlated_logs
+                .iter()
+                .map(|log| {
+                    format!(
+                        "{} [{}] {}: {}",
+                        Utc.timestamp_nanos(log.time as i64)
+                            .to_rfc3339_opts(SecondsFormat::Millis, true),
+                        log.ruletitle,
+                        log.process,
+                        log.message
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(" ¦ "),
+            format_event_data(data),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// The fields of a synthesized event that have no Unified Log column (ex: "Service: kTCCServiceCamera ¦ Client: ...")
+fn format_event_data(data: &EventRecord) -> String {
+    data.event_data
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect::<Vec<String>>()
+        .join(" ¦ ")
+}
+
+// Columns of the TCC decisions file. The fields of the tcc events, in the order they are triaged
+const TCC_COLUMNS: [&str; 8] = [
+    "Service",
+    "Client",
+    "AuthValue",
+    "AuthReason",
+    "Image",
+    "ProcessId",
+    "User",
+    "AttributionChain",
+];
+
+pub fn output_tcc_events(
+    tcc_events: &[EventRecord],
+    out: &PathBuf,
+    overwrite: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(create_file(out, overwrite)?);
+    let mut header = vec!["Timestamp"];
+    header.extend(TCC_COLUMNS);
+    writer.write_record(header)?;
+    for data in tcc_events {
+        let mut record = vec![Utc
+            .timestamp_nanos(data.time as i64)
+            .to_rfc3339_opts(SecondsFormat::Millis, true)];
+        record.extend(
+            TCC_COLUMNS
+                .iter()
+                .map(|column| get_event_value(data, column).unwrap_or_default()),
+        );
+        writer.write_record(record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn output_logon_summary(
+    counts: &[LogonCount],
+    out: &PathBuf,
+    overwrite: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = csv::Writer::from_writer(create_file(out, overwrite)?);
+    writer.write_record([
+        "Result",
+        "Count",
+        "User",
+        "Method",
+        "SourceIP",
+        "FirstTimestamp",
+        "LastTimestamp",
+    ])?;
+    for count in counts {
+        let result = if count.successful {
+            "Successful"
+        } else {
+            "Failed"
+        };
+        writer.write_record(&[
+            result.to_string(),
+            count.count.to_string(),
+            count.user.to_owned(),
+            count.method.to_owned(),
+            count.source_ip.to_owned(),
+            format_time(count.first_time),
+            format_time(count.last_time),
         ])?;
     }
+    writer.flush()?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::detection::DetectInfo;
+    use crate::events::TCC;
+    use crate::output::{output_detections, output_tcc_events, TimelineWriter};
+    use crate::test_helpers::{create_logdata, create_tccd_logdata};
+    use serde_json::Value;
+    use std::fs;
+
+    #[test]
+    fn test_output_detections() {
+        let out = std::env::temp_dir().join("macos-log-dfir-test-detections.csv");
+        let detect_infos = vec![DetectInfo {
+            rulepath: "rules/test.yml".to_string(),
+            ruletitle: "Gatekeeper Bypass via Xattr".to_string(),
+            ruleid: "f5141b6d-9f42-41c6-a7bf-2a780678b29b".to_string(),
+            level: "low".to_string(),
+            tags: vec![
+                "attack.defense_evasion".to_string(),
+                "attack.t1553.001".to_string(),
+            ],
+            suppressed: false,
+            aggregation: None,
+            correlated_logs: vec![],
+            logdata: create_logdata("/usr/bin/xattr", "xattr -d com.apple.quarantine"),
+        }];
+        output_detections(&detect_infos, &out, true).unwrap();
+        let csv = fs::read_to_string(&out).unwrap();
+        fs::remove_file(&out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "Timestamp,RuleTitle,Level,RulePath,RuleID,MitreTags,Process,PID,Subsystem,Category,Message,Suppressed,Aggregation,CorrelatedEvents,EventData"
+        );
+        assert_eq!(
+            lines[1],
+            "2023-03-28T10:40:00.000Z,Gatekeeper Bypass via Xattr,low,rules/test.yml,f5141b6d-9f42-41c6-a7bf-2a780678b29b,attack.defense_evasion ¦ attack.t1553.001,/usr/bin/xattr,100,com.apple.xpc,default,xattr -d com.apple.quarantine,false,,,"
+        );
+    }
+
+    #[test]
+    fn test_output_tcc_events() {
+        let out = std::env::temp_dir().join("macos-log-dfir-test-tcc.csv");
+        let mut data = create_tccd_logdata(
+            "AUTHREQ_RESULT: msgID=401.12, authValue=2, authReason=3, authVersion=1, desired_auth=0, error=(null),",
+        );
+        data.event_type = TCC.to_string();
+        data.event_data = vec![
+            ("Service", "kTCCServiceAccessibility".to_string()),
+            ("Client", "com.googlecode.iterm2".to_string()),
+            ("AuthValue", "Allowed".to_string()),
+            ("AuthReason", "User Set".to_string()),
+            (
+                "AttributionChain",
+                "responsible: com.googlecode.iterm2 > accessing: /tmp/agent".to_string(),
+            ),
+            ("Image", "/tmp/agent".to_string()),
+            ("User", "501".to_string()),
+            ("ProcessId", "5612".to_string()),
+        ];
+        output_tcc_events(&[data], &out, true).unwrap();
+        let csv = fs::read_to_string(&out).unwrap();
+        fs::remove_file(&out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "Timestamp,Service,Client,AuthValue,AuthReason,Image,ProcessId,User,AttributionChain"
+        );
+        assert_eq!(
+            lines[1],
+            "2023-03-28T10:40:00.000Z,kTCCServiceAccessibility,com.googlecode.iterm2,Allowed,User Set,/tmp/agent,5612,501,responsible: com.googlecode.iterm2 > accessing: /tmp/agent"
+        );
+    }
+
+    #[test]
+    fn test_output_timeline() {
+        let out = std::env::temp_dir().join("macos-log-dfir-test-timeline.csv");
+        let _ = fs::remove_file(&out);
+        let data = create_logdata("/usr/bin/xattr", "xattr -l a.app");
+
+        // ヘッダーは最初に1回だけ書き込まれる
+        let mut writer = TimelineWriter::csv(&out, false).unwrap();
+        writer.write(&data, &[]).unwrap();
+        writer.write(&data, &[]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        let csv = fs::read_to_string(&out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("Timestamp,EventType,LogType,Subsystem,"));
+        assert!(lines[1].starts_with("2023-03-28T10:40:00.000Z,logEvent,Default,com.apple.xpc,"));
+
+        // 既存のファイルは--overwriteを指定しない限り上書きしない
+        assert!(TimelineWriter::csv(&out, false).is_err());
+        let writer = TimelineWriter::csv(&out, true).unwrap();
+        drop(writer);
+        let csv = fs::read_to_string(&out).unwrap();
+        fs::remove_file(&out).unwrap();
+        assert_eq!(csv.lines().count(), 1);
+    }
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn test_output_jsonl_timeline() {
+        let out = std::env::temp_dir().join("macos-log-dfir-test-timeline.jsonl");
+        let data = create_logdata("/usr/bin/xattr", "line1\nline2");
+        let detect_infos = vec![DetectInfo {
+            rulepath: "rules/test.yml".to_string(),
+            ruletitle: "Gatekeeper Bypass via Xattr".to_string(),
+            ruleid: "f5141b6d-9f42-41c6-a7bf-2a780678b29b".to_string(),
+            level: "low".to_string(),
+            tags: vec!["attack.defense_evasion".to_string()],
+            suppressed: false,
+            aggregation: None,
+            correlated_logs: vec![],
+            logdata: create_logdata("/usr/bin/xattr", "line1\nline2"),
+        }];
+
+        let mut writer = TimelineWriter::jsonl(&out, true).unwrap();
+        writer.write(&data, &[]).unwrap();
+        writer.write(&data, &detect_infos).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        let jsonl = fs::read_to_string(&out).unwrap();
+        fs::remove_file(&out).unwrap();
+
+        // 改行を含むメッセージでも1レコード1行になる
+        let lines: Vec<&str> = jsonl.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let first: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(first["timestamp"], "2023-03-28T10:40:00.000Z");
+        assert_eq!(first["message"], "line1\nline2");
+        assert_eq!(first["process"], "/usr/bin/xattr");
+        assert_eq!(first["pid"], 100);
+        assert_eq!(first["event_type"], "logEvent");
+        assert!(first.get("detections").is_none());
+        let second: Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(
+            second["detections"][0]["title"],
+            "Gatekeeper Bypass via Xattr"
+        );
+        assert_eq!(second["detections"][0]["level"], "low");
+        assert_eq!(second["detections"][0]["tags"][0], "attack.defense_evasion");
+        assert!(second.get("eventData").is_none());
+
+        // メッセージから作成したイベントのフィールドはeventDataに出力する
+        let mut writer = TimelineWriter::jsonl(&out, true).unwrap();
+        let mut event = create_logdata("/usr/libexec/tccd", "AUTHREQ_RESULT: msgID=401.12");
+        event.event_data = vec![("Service", "kTCCServiceCamera".to_string())];
+        writer.write(&event, &[]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        let jsonl = fs::read_to_string(&out).unwrap();
+        fs::remove_file(&out).unwrap();
+        let record: Value = serde_json::from_str(jsonl.trim_end()).unwrap();
+        assert_eq!(record["eventData"]["Service"], "kTCCServiceCamera");
+        assert_eq!(record["message"], "AUTHREQ_RESULT: msgID=401.12");
     }
 }
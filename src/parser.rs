@@ -1,58 +1,199 @@
-use crate::output::output;
+use crate::detection::{detect, Aggregator, DetectInfo};
+use crate::error::ParseError;
+use crate::events::{synthesize_events, EventRecord, AUTHENTICATION, TCC};
+use crate::output::TimelineWriter;
+use crate::rule::correlation::CorrelationRule;
+use crate::rule::rulenode::RuleNode;
+use crate::tcc::{is_tcc_message, TccParser};
+use crate::tuning::Tuning;
 use macos_unifiedlogs::dsc::SharedCacheStrings;
 use macos_unifiedlogs::parser::{
     build_log, collect_shared_strings, collect_shared_strings_system, collect_strings,
     collect_strings_system, collect_timesync, collect_timesync_system, parse_log,
 };
 use macos_unifiedlogs::timesync::TimesyncBoot;
-use macos_unifiedlogs::unified_log::UnifiedLogData;
+use macos_unifiedlogs::unified_log::{LogData, UnifiedLogData};
 use macos_unifiedlogs::uuidtext::UUIDText;
-use std::fs;
-use std::path::PathBuf;
+use std::any::Any;
+use std::fs::{self, DirEntry};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+// Settings applied to every log entry during a run
+pub struct TimelineConfig<'a> {
+    pub rules: &'a [RuleNode],
+    pub correlations: &'a [CorrelationRule],
+    pub tuning: &'a Tuning,
+    pub time_filter: TimeFilter,
+    pub threads: usize,
+    // Keep the tcc events in memory for the TCC decisions file (--tcc)
+    pub collect_tcc: bool,
+    // Keep the authentication events in memory for logon-summary
+    pub collect_authentication: bool,
+}
+
+// Time range of the log entries to output (nanoseconds since the Unix epoch)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeFilter {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+}
+
+impl TimeFilter {
+    pub fn contains(&self, time: f64) -> bool {
+        let time = time as i64;
+        self.start.is_none_or(|start| start <= time) && self.end.is_none_or(|end| time <= end)
+    }
+
+    fn is_before_start(&self, time: Option<i64>) -> bool {
+        matches!((self.start, time), (Some(start), Some(time)) if time < start)
+    }
+
+    fn is_after_end(&self, time: Option<i64>) -> bool {
+        matches!((self.end, time), (Some(end), Some(time)) if end < time)
+    }
+}
+
+// Outcome of a run. Files that could not be parsed are skipped and listed in failures
+pub struct ParseResult {
+    pub detect_infos: Vec<DetectInfo>,
+    // TCC decisions, saved to a separate file with their own columns. Only collected with collect_tcc
+    pub tcc_events: Vec<EventRecord>,
+    // Authentication events, summarized per user by logon-summary. Only collected with collect_authentication
+    pub authentication_events: Vec<EventRecord>,
+    pub failures: Vec<ParseError>,
+}
+
+// State carried over between the tracev3 files of a run
+struct ParseState {
+    // We need to persist the Oversize log entries (they contain large strings that don't fit in normal log entries)
+    // Some log entries have Oversize strings located in different tracev3 files.
+    // This is very rare. Seen in ~20 log entries out of ~700,000. Seen in ~700 out of ~18 million
+    oversize_strings: UnifiedLogData,
+    // Exclude missing data from returned output. Keep separate until we parse all oversize entries.
+    // Then at end, go through all missing data and check all parsed oversize entries again
+    missing_data: Vec<UnifiedLogData>,
+    log_count: usize,
+    detect_infos: Vec<DetectInfo>,
+    // Counts the hits of the rules with an aggregation condition and of the rules referenced by correlation rules
+    aggregator: Aggregator,
+    // Joins the TCC messages of the whole run, in the order of the tracev3 files
+    tcc_parser: TccParser,
+    tcc_events: Vec<EventRecord>,
+    authentication_events: Vec<EventRecord>,
+    failures: Vec<ParseError>,
+}
+
+impl ParseState {
+    fn new(failures: Vec<ParseError>) -> ParseState {
+        ParseState {
+            oversize_strings: UnifiedLogData {
+                header: Vec::new(),
+                catalog_data: Vec::new(),
+                oversize: Vec::new(),
+            },
+            missing_data: Vec::new(),
+            log_count: 0,
+            detect_infos: Vec::new(),
+            aggregator: Aggregator::default(),
+            tcc_parser: TccParser::default(),
+            tcc_events: Vec::new(),
+            authentication_events: Vec::new(),
+            failures,
+        }
+    }
+
+    // Record a file that could not be parsed and move on to the next one
+    fn fail(&mut self, err: ParseError) {
+        eprintln!("{}", err);
+        self.failures.push(err);
+    }
+}
 
 // Parse a provided directory path. Currently expect the path to follow macOS log collect structure
-pub fn parse_log_archive(path: PathBuf, out: PathBuf) {
-    let mut archive_path = path.clone();
+pub fn parse_log_archive(
+    path: PathBuf,
+    writer: &mut TimelineWriter,
+    config: &TimelineConfig,
+) -> Result<ParseResult, ParseError> {
+    let archive_path = path;
+    if !archive_path.is_dir() {
+        return Err(ParseError::read_dir(&archive_path, "No such directory"));
+    }
+    let mut failures = vec![];
 
     // Parse all UUID files which contain strings and other metadata
-    let string_results = collect_strings(&archive_path.display().to_string()).unwrap();
+    let string_results = collect_strings(&archive_path.display().to_string())
+        .map_err(|err| ParseError::metadata("UUID text", &archive_path, err));
 
     // Parse UUID cache files which also contain strings and other metadata
-    let shared_strings_results =
-        collect_shared_strings(&archive_path.join("dsc").display().to_string()).unwrap();
+    let dsc_path = archive_path.join("dsc");
+    let shared_strings_results = collect_shared_strings(&dsc_path.display().to_string())
+        .map_err(|err| ParseError::metadata("dsc", &dsc_path, err));
 
     // Parse all timesync files
-    let timesync_data =
-        collect_timesync(&archive_path.join("timesync").display().to_string()).unwrap();
+    // Timestamps cannot be calculated without them, so there is nothing useful to output
+    let timesync_path = archive_path.join("timesync");
+    let timesync_data = collect_timesync(&timesync_path.display().to_string())
+        .map_err(|err| ParseError::metadata("timesync", &timesync_path, err))?;
 
     // Keep UUID, UUID cache, timesync files in memory while we parse all tracev3 files
     // Allows for faster lookups
-    parse_trace_file(
-        &string_results,
-        &shared_strings_results,
+    let result = parse_trace_file(
+        &unwrap_or_fail(string_results, &mut failures),
+        &unwrap_or_fail(shared_strings_results, &mut failures),
         &timesync_data,
-        path,
-        out,
-    );
+        archive_path,
+        writer,
+        config,
+        failures,
+    )?;
 
-    println!("\nFinished parsing Unified Log data. Saved results to: output.csv");
+    println!("\nFinished parsing Unified Log data.");
+    Ok(result)
 }
 
 // Parse a live macOS system
-pub fn parse_live_system(out: PathBuf) {
-    let strings = collect_strings_system().unwrap();
-    let shared_strings = collect_shared_strings_system().unwrap();
-    let timesync_data = collect_timesync_system().unwrap();
-
-    parse_trace_file(
-        &strings,
-        &shared_strings,
+pub fn parse_live_system(
+    writer: &mut TimelineWriter,
+    config: &TimelineConfig,
+) -> Result<ParseResult, ParseError> {
+    let mut failures = vec![];
+    let strings = collect_strings_system().map_err(|err| {
+        ParseError::metadata("UUID text", Path::new("/private/var/db/uuidtext"), err)
+    });
+    let shared_strings = collect_shared_strings_system()
+        .map_err(|err| ParseError::metadata("dsc", Path::new("/private/var/db/uuidtext/dsc"), err));
+    let timesync_data = collect_timesync_system().map_err(|err| {
+        ParseError::metadata(
+            "timesync",
+            Path::new("/private/var/db/diagnostics/timesync"),
+            err,
+        )
+    })?;
+
+    let result = parse_trace_file(
+        &unwrap_or_fail(strings, &mut failures),
+        &unwrap_or_fail(shared_strings, &mut failures),
         &timesync_data,
         PathBuf::from("/private/var/db/diagnostics"),
-        out,
-    );
+        writer,
+        config,
+        failures,
+    )?;
 
-    println!("\nFinished parsing Unified Log data. Saved results to: output.csv");
+    println!("\nFinished parsing Unified Log data.");
+    Ok(result)
+}
+
+// Log entries can still be built without the UUID text or dsc files, only their messages will be incomplete.
+// Record the failure and continue with no strings
+fn unwrap_or_fail<T>(result: Result<Vec<T>, ParseError>, failures: &mut Vec<ParseError>) -> Vec<T> {
+    result.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        failures.push(err);
+        Vec::new()
+    })
 }
 
 // Use the provided strings, shared strings, timesync data to parse the Unified Log data at provided path.
@@ -62,19 +203,12 @@ fn parse_trace_file(
     shared_strings_results: &[SharedCacheStrings],
     timesync_data: &[TimesyncBoot],
     path: PathBuf,
-    out: PathBuf,
-) {
-    // We need to persist the Oversize log entries (they contain large strings that don't fit in normal log entries)
-    // Some log entries have Oversize strings located in different tracev3 files.
-    // This is very rare. Seen in ~20 log entries out of ~700,000. Seen in ~700 out of ~18 million
-    let mut oversize_strings = UnifiedLogData {
-        header: Vec::new(),
-        catalog_data: Vec::new(),
-        oversize: Vec::new(),
-    };
+    writer: &mut TimelineWriter,
+    config: &TimelineConfig,
+    failures: Vec<ParseError>,
+) -> Result<ParseResult, ParseError> {
+    let mut state = ParseState::new(failures);
 
-    // Exclude missing data from returned output. Keep separate until we parse all oversize entries.
-    // Then at end, go through all missing data and check all parsed oversize entries again
     let archive_path = path;
     let archive_paths = vec![
         archive_path.join("Persist"),
@@ -83,8 +217,6 @@ fn parse_trace_file(
         archive_path.join("HighVolume"),
     ];
 
-    let mut missing_data: Vec<UnifiedLogData> = Vec::new();
-    let mut log_count = 0;
     for path in archive_paths {
         if !path.exists() {
             continue;
@@ -94,41 +226,80 @@ fn parse_trace_file(
             shared_strings_results,
             timesync_data,
             &path,
-            &out,
-            &mut oversize_strings,
+            writer,
             true,
-            &mut missing_data,
-            &mut log_count,
-        )
+            config,
+            &mut state,
+        )?;
     }
 
     // Check if livedata exists. We only have it if 'log collect' was used
-    if archive_path.join("logdata.LiveData.tracev3").exists() {
+    let live_data_path = archive_path.join("logdata.LiveData.tracev3");
+    if live_data_path.exists() {
         println!("Parsing: logdata.LiveData.tracev3");
-        let mut log_data = parse_log(&archive_path.display().to_string()).unwrap();
-        log_data.oversize.append(&mut oversize_strings.oversize);
-        let (results, missing_logs) = build_log(
-            &log_data,
-            string_results,
-            shared_strings_results,
-            timesync_data,
-            true,
-        );
-        // Track missing data
-        missing_data.push(missing_logs);
-        log_count += results.len();
-
-        output(&results, &out).unwrap();
-        // Track oversize entries
-        oversize_strings.oversize = log_data.oversize;
+        let live_data_path = live_data_path.display().to_string();
+        match parse_log(&live_data_path) {
+            Ok(mut log_data) => {
+                log_data
+                    .oversize
+                    .append(&mut state.oversize_strings.oversize);
+                let (results, missing_logs) = build_log(
+                    &log_data,
+                    string_results,
+                    shared_strings_results,
+                    timesync_data,
+                    true,
+                );
+                // Track missing data
+                state.missing_data.push(missing_logs);
+                state.log_count += results.len();
+                output_results(results, config, writer, &mut state)?;
+                // Track oversize entries
+                state.oversize_strings.oversize = log_data.oversize;
+            }
+            Err(err) => state.fail(ParseError::tracev3(&live_data_path, err)),
+        }
     }
 
     // Since we have all Oversize entries now. Go through any log entries that we were not able to build before
-    for mut leftover_data in missing_data {
-        // Add all of our previous oversize data to logs for lookups
+    build_missing_logs(
+        string_results,
+        shared_strings_results,
+        timesync_data,
+        writer,
+        config,
+        &mut state,
+    )?;
+    println!("Parsed {} log entries", state.log_count);
+
+    // All log entries have been checked, so the windows still open can be counted
+    let aggregator = std::mem::take(&mut state.aggregator);
+    state
+        .detect_infos
+        .extend(aggregator.finish(config.rules, config.correlations, config.tuning));
+    Ok(ParseResult {
+        detect_infos: state.detect_infos,
+        tcc_events: state.tcc_events,
+        authentication_events: state.authentication_events,
+        failures: state.failures,
+    })
+}
+
+// Build the log entries that referenced Oversize entries of other tracev3 files, using the Oversize entries of all files.
+// The Oversize entries are moved into each missing log data for the lookups and taken back afterwards, so they are never copied
+fn build_missing_logs(
+    string_results: &[UUIDText],
+    shared_strings_results: &[SharedCacheStrings],
+    timesync_data: &[TimesyncBoot],
+    writer: &mut TimelineWriter,
+    config: &TimelineConfig,
+    state: &mut ParseState,
+) -> Result<(), ParseError> {
+    for mut leftover_data in std::mem::take(&mut state.missing_data) {
+        let own_oversize_count = leftover_data.oversize.len();
         leftover_data
             .oversize
-            .append(&mut oversize_strings.oversize.to_owned());
+            .append(&mut state.oversize_strings.oversize);
 
         // Exclude_missing = false
         // If we fail to find any missing data its probably due to the logs rolling
@@ -140,64 +311,655 @@ fn parse_trace_file(
             timesync_data,
             false,
         );
-        log_count += results.len();
-
-        output(&results, &out).unwrap();
+        state.oversize_strings.oversize = leftover_data.oversize.split_off(own_oversize_count);
+        state.log_count += results.len();
+        output_results(results, config, writer, state)?;
     }
-    println!("Parsed {} log entries", log_count);
+    Ok(())
+}
+
+// Log entries built from one tracev3 file, together with the rules they matched
+struct ParsedLog {
+    log_data: UnifiedLogData,
+    missing_logs: UnifiedLogData,
+    log_count: usize,
+    results: Vec<DetectedRecord>,
 }
 
+// A log entry or synthesized event with the rules it matched.
+// The hits are None for the TCC messages outside of the time range, which are only kept for the TccParser
+type DetectedRecord = (EventRecord, Option<RecordHits>);
+
+// The rules a log entry matched. aggregation_hits are the indexes of the rules with an aggregation condition or referenced
+// by correlation rules, counted by the Aggregator when the log entry is written so that the hits are counted in file order
+struct RecordHits {
+    detect_infos: Vec<DetectInfo>,
+    aggregation_hits: Vec<usize>,
+}
+
+// Parse all tracev3 files in a directory. Files that cannot be read or parsed are recorded in the state and skipped.
+// Only a failure to write the timeline stops the run
 fn dump_logs(
     string_results: &[UUIDText],
     shared_strings_results: &[SharedCacheStrings],
     timesync_data: &[TimesyncBoot],
-    archive_path: &PathBuf,
-    out: &PathBuf,
-    oversize_strings: &mut UnifiedLogData,
+    archive_path: &Path,
+    writer: &mut TimelineWriter,
+    exclude_missing: bool,
+    config: &TimelineConfig,
+    state: &mut ParseState,
+) -> Result<(), ParseError> {
+    let mut paths: Vec<DirEntry> = match fs::read_dir(archive_path) {
+        Ok(entries) => entries
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry),
+                Err(err) => {
+                    state.fail(ParseError::read_dir(archive_path, err));
+                    None
+                }
+            })
+            .collect(),
+        Err(err) => {
+            state.fail(ParseError::read_dir(archive_path, err));
+            return Ok(());
+        }
+    };
+    // Sort the tracev3 files so that the output does not depend on the directory order
+    paths.sort_by_key(|data| data.path());
+
+    let full_paths: Vec<String> = paths
+        .iter()
+        .map(|data| data.path().display().to_string())
+        .collect();
+
+    // Parse up to `threads` tracev3 files at the same time.
+    // Each file is built only with its own Oversize entries, so the log entries that reference Oversize entries
+    // of other files are always tracked as missing data and built once all Oversize entries have been parsed,
+    // whatever the number of threads. The results are written in file order, so the output stays deterministic.
+    for chunk in full_paths.chunks(config.threads.max(1)) {
+        let parsed_logs: Vec<Result<Option<ParsedLog>, ParseError>> = thread::scope(|scope| {
+            let handles: Vec<_> = chunk
+                .iter()
+                .map(|full_path| {
+                    println!("Parsing: {}", full_path);
+                    scope.spawn(move || {
+                        parse_tracev3(
+                            full_path,
+                            string_results,
+                            shared_strings_results,
+                            timesync_data,
+                            exclude_missing,
+                            config,
+                        )
+                    })
+                })
+                .collect();
+            // The parser may panic on a corrupted tracev3 file. Treat it the same as a parse error
+            handles
+                .into_iter()
+                .zip(chunk)
+                .map(|(handle, full_path)| {
+                    handle.join().unwrap_or_else(|panic| {
+                        Err(ParseError::tracev3(
+                            full_path,
+                            panic_message(panic.as_ref()),
+                        ))
+                    })
+                })
+                .collect()
+        });
+
+        for parsed_log in parsed_logs {
+            match parsed_log {
+                Ok(Some(parsed_log)) => track_parsed_log(parsed_log, config, writer, state)?,
+                Ok(None) => continue,
+                Err(err) => state.fail(err),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Add the Oversize entries and missing log data of a parsed tracev3 file to the state and write its results
+fn track_parsed_log(
+    mut parsed_log: ParsedLog,
+    config: &TimelineConfig,
+    writer: &mut TimelineWriter,
+    state: &mut ParseState,
+) -> Result<(), ParseError> {
+    // Track Oversize entries
+    state
+        .oversize_strings
+        .oversize
+        .append(&mut parsed_log.log_data.oversize);
+
+    // Track missing logs
+    state.missing_data.push(parsed_log.missing_logs);
+    state.log_count += parsed_log.log_count;
+    write_results(parsed_log.results, config, writer, state)
+}
+
+// Parse a tracev3 file and build its log entries. Called from the worker threads of dump_logs
+fn parse_tracev3(
+    full_path: &str,
+    string_results: &[UUIDText],
+    shared_strings_results: &[SharedCacheStrings],
+    timesync_data: &[TimesyncBoot],
+    exclude_missing: bool,
+    config: &TimelineConfig,
+) -> Result<Option<ParsedLog>, ParseError> {
+    if !Path::new(full_path).exists() {
+        println!("File {} no longer on disk", full_path);
+        return Ok(None);
+    }
+    let log_data = parse_log(full_path).map_err(|err| ParseError::tracev3(full_path, err))?;
+
+    // A tracev3 file whose entries are all outside of the time range has no entries to output.
+    // Oversize entries are still tracked since they may be referenced from other tracev3 files
+    if is_outside_time_range(&log_data, timesync_data, &config.time_filter) {
+        println!("Skipping: {} (outside of the time range)", full_path);
+        return Ok(Some(skipped_log(log_data)));
+    }
+    Ok(Some(build_tracev3(
+        log_data,
+        string_results,
+        shared_strings_results,
+        timesync_data,
+        exclude_missing,
+        config,
+    )))
+}
+
+// Build the log entries of a parsed tracev3 file and check them against the rules
+fn build_tracev3(
+    log_data: UnifiedLogData,
+    string_results: &[UUIDText],
+    shared_strings_results: &[SharedCacheStrings],
+    timesync_data: &[TimesyncBoot],
     exclude_missing: bool,
-    missing_data: &mut Vec<UnifiedLogData>,
-    log_count: &mut usize,
-) {
-    let paths = fs::read_dir(archive_path).unwrap();
-
-    // Loop through all tracev3 files in Persist directory
-    for log_path in paths {
-        let data = log_path.unwrap();
-        let full_path = data.path().display().to_string();
-        println!("Parsing: {}", full_path);
-
-        let mut log_data = if data.path().exists() {
-            parse_log(&full_path).unwrap()
+    config: &TimelineConfig,
+) -> ParsedLog {
+    // Get all constructed logs and any log data that failed to get constrcuted (exclude_missing = true)
+    let (results, missing_logs) = build_log(
+        &log_data,
+        string_results,
+        shared_strings_results,
+        timesync_data,
+        exclude_missing,
+    );
+
+    let log_count = results.len();
+    let results = detect_results(results, config);
+    ParsedLog {
+        log_data,
+        missing_logs,
+        log_count,
+        results,
+    }
+}
+
+// Message of a panic caught from a worker thread
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        format!("parser panicked: {}", message)
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        format!("parser panicked: {}", message)
+    } else {
+        "parser panicked".to_string()
+    }
+}
+
+// A tracev3 file that is skipped, keeping only its Oversize entries
+fn skipped_log(log_data: UnifiedLogData) -> ParsedLog {
+    ParsedLog {
+        log_data,
+        missing_logs: UnifiedLogData {
+            header: Vec::new(),
+            catalog_data: Vec::new(),
+            oversize: Vec::new(),
+        },
+        log_count: 0,
+        results: vec![],
+    }
+}
+
+// Whether all entries of a tracev3 file are outside of the time range
+fn is_outside_time_range(
+    log_data: &UnifiedLogData,
+    timesync_data: &[TimesyncBoot],
+    time_filter: &TimeFilter,
+) -> bool {
+    let (start, end) = time_range(log_data, timesync_data);
+    time_filter.is_after_end(start) || time_filter.is_before_start(end)
+}
+
+// Wall clock time range of the entries in a tracev3 file. The start is calculated from its header chunk
+// and the end from the time of the last entry in its catalog chunks
+fn time_range(
+    log_data: &UnifiedLogData,
+    timesync_data: &[TimesyncBoot],
+) -> (Option<i64>, Option<i64>) {
+    let header = match log_data.header.first() {
+        Some(header) => header,
+        None => return (None, None),
+    };
+    let wall_time = |continuous_time| {
+        let time =
+            TimesyncBoot::get_timestamp(timesync_data, &header.boot_uuid, continuous_time, 0);
+        if time > 0.0 {
+            Some(time as i64)
         } else {
-            println!("File {} no longer on disk", full_path);
+            None
+        }
+    };
+    let last_entry_time = log_data
+        .catalog_data
+        .iter()
+        .flat_map(|catalog_data| &catalog_data.catalog.catalog_subchunks)
+        .map(|subchunk| subchunk.end)
+        .max();
+    (
+        wall_time(header.continous_time),
+        last_entry_time.and_then(wall_time),
+    )
+}
+
+// Check the built log entries against the rules and write them to the timeline along with any hits
+fn output_results(
+    results: Vec<LogData>,
+    config: &TimelineConfig,
+    writer: &mut TimelineWriter,
+    state: &mut ParseState,
+) -> Result<(), ParseError> {
+    let results = detect_results(results, config);
+    write_results(results, config, writer, state)
+}
+
+// Check the log entries in the time range against the rules.
+// The events synthesized from a log entry (ex: process_creation) follow it in the timeline
+fn detect_results(results: Vec<LogData>, config: &TimelineConfig) -> Vec<DetectedRecord> {
+    let mut detected_records = vec![];
+    for data in results {
+        if !config.time_filter.contains(data.time) {
+            if is_tcc_message(&data) {
+                detected_records.push((EventRecord::from(data), None));
+            }
             continue;
-        };
+        }
+        let events = synthesize_events(&data);
+        for record in std::iter::once(EventRecord::from(data)).chain(events) {
+            let record_hits = detect_record(&record, config);
+            detected_records.push((record, Some(record_hits)));
+        }
+    }
+    detected_records
+}
 
-        // Get all constructed logs and any log data that failed to get constrcuted (exclude_missing = true)
-        log_data.oversize.append(&mut oversize_strings.oversize);
-        let (results, missing_logs) = build_log(
-            &log_data,
-            string_results,
-            shared_strings_results,
-            timesync_data,
-            exclude_missing,
-        );
-        // Track Oversize entries
-        oversize_strings
-            .oversize
-            .append(&mut log_data.oversize.to_owned());
+// Write the log entries to the timeline along with any hits.
+// The TCC messages are joined here, in file order. A tcc event in the time range follows the message with the result
+fn write_results(
+    results: Vec<DetectedRecord>,
+    config: &TimelineConfig,
+    writer: &mut TimelineWriter,
+    state: &mut ParseState,
+) -> Result<(), ParseError> {
+    for (data, record_hits) in results {
+        let tcc_event = state
+            .tcc_parser
+            .parse(&data)
+            .filter(|event| config.time_filter.contains(event.time));
+        if let Some(record_hits) = record_hits {
+            write_record(data, record_hits, config, writer, state)?;
+        }
+        if let Some(event) = tcc_event {
+            let event_hits = detect_record(&event, config);
+            write_record(event, event_hits, config, writer, state)?;
+        }
+    }
+    Ok(())
+}
+
+// Check a log entry against the rules
+fn detect_record(record: &EventRecord, config: &TimelineConfig) -> RecordHits {
+    let mut aggregation_hits = vec![];
+    let detect_infos = detect(record, config.rules, config.tuning, &mut aggregation_hits);
+    RecordHits {
+        detect_infos,
+        aggregation_hits,
+    }
+}
 
-        // Track missing logs
-        missing_data.push(missing_logs);
-        *log_count += results.len();
-        output(&results, out).unwrap();
+fn write_record(
+    data: EventRecord,
+    record_hits: RecordHits,
+    config: &TimelineConfig,
+    writer: &mut TimelineWriter,
+    state: &mut ParseState,
+) -> Result<(), ParseError> {
+    writer
+        .write(&data, &record_hits.detect_infos)
+        .map_err(|err| ParseError::Output(err.to_string()))?;
+    state.detect_infos.extend(record_hits.detect_infos);
+    // The thresholds and correlations reached by this log entry
+    state.detect_infos.extend(state.aggregator.add(
+        &data,
+        &record_hits.aggregation_hits,
+        config.rules,
+        config.correlations,
+        config.tuning,
+    ));
+    match data.event_type.as_str() {
+        TCC if config.collect_tcc => state.tcc_events.push(data),
+        AUTHENTICATION if config.collect_authentication => state.authentication_events.push(data),
+        _ => {}
+    }
+    Ok(())
+}
+
+// List the files that could not be parsed at the end of the run
+pub fn print_parse_failures(failures: &[ParseError]) {
+    if failures.is_empty() {
+        return;
+    }
+    eprintln!();
+    eprintln!("Failed to parse {} item(s):", failures.len());
+    for failure in failures {
+        eprintln!("  {}", failure);
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::error::ParseError;
+    use crate::events::TCC;
+    use crate::output::TimelineWriter;
+    use crate::parser::{
+        build_missing_logs, build_tracev3, is_outside_time_range, output_results, panic_message,
+        parse_trace_file, skipped_log, track_parsed_log, ParseState, TimeFilter, TimelineConfig,
+    };
+    use crate::rule::fieldmapping::get_event_value;
+    use crate::test_helpers::{create_logdata, create_tccd_logdata, parse_rule_from_str};
+    use crate::tuning::Tuning;
+    use macos_unifiedlogs::timesync::TimesyncBoot;
+    use macos_unifiedlogs::unified_log::{LogData, UnifiedLogData};
+    use serde_json::Value;
+    use std::fs;
+
+    #[test]
+    fn test_time_filter() {
+        let filter = TimeFilter {
+            start: Some(100),
+            end: Some(200),
+        };
+        assert!(!filter.contains(99.0));
+        assert!(filter.contains(100.0));
+        assert!(filter.contains(200.0));
+        assert!(!filter.contains(201.0));
+        assert!(filter.is_before_start(Some(99)));
+        assert!(!filter.is_before_start(Some(100)));
+        assert!(filter.is_after_end(Some(201)));
+        assert!(!filter.is_after_end(Some(200)));
+        assert!(!filter.is_before_start(None));
+        assert!(!filter.is_after_end(None));
+
+        let filter = TimeFilter::default();
+        assert!(filter.contains(0.0));
+        assert!(!filter.is_before_start(Some(0)));
+        assert!(!filter.is_after_end(Some(i64::MAX)));
+    }
+
+    #[test]
+    fn test_build_tracev3_oversize_of_skipped_file() {
+        // ヘッダーの開始時間と最後のログの時間、Oversizeのデータを持つtracev3ファイル
+        let tracev3 = |start: u64, end: u64, data_ref_index: u32| {
+            let mut log_data = UnifiedLogData {
+                header: vec![Default::default()],
+                catalog_data: Vec::new(),
+                oversize: Vec::new(),
+            };
+            log_data.header[0].continous_time = start;
+            log_data.catalog_data.push(Default::default());
+            log_data.catalog_data[0]
+                .catalog
+                .catalog_subchunks
+                .extend([Default::default(), Default::default()]);
+            log_data.catalog_data[0].catalog.catalog_subchunks[0].end = (start + end) / 2;
+            log_data.catalog_data[0].catalog.catalog_subchunks[1].end = end;
+            log_data.oversize.push(Default::default());
+            log_data.oversize[0].data_ref_index = data_ref_index;
+            log_data
+        };
+        let wall_time = |time| TimesyncBoot::get_timestamp(&[], "", time, 0) as i64;
+        let tuning = Tuning::default();
+        let config = TimelineConfig {
+            rules: &[],
+            correlations: &[],
+            tuning: &tuning,
+            time_filter: TimeFilter {
+                start: Some(wall_time(5_000)),
+                end: None,
+            },
+            threads: 1,
+            collect_tcc: false,
+            collect_authentication: false,
+        };
+        let skipped = tracev3(1_000, 2_000, 1);
+        let in_range = tracev3(4_000, 6_000, 2);
+        // 最後のログが時間範囲より前のファイルはスキップし、開始時間が時間範囲より前でも最後のログが時間範囲内のファイルはスキップしない
+        assert!(is_outside_time_range(&skipped, &[], &config.time_filter));
+        assert!(!is_outside_time_range(&in_range, &[], &config.time_filter));
+
+        let out = std::env::temp_dir().join("macos-log-dfir-test-skipped-oversize.csv");
+        let mut writer = TimelineWriter::csv(&out, true).unwrap();
+        let mut state = ParseState::new(vec![]);
+        // スキップしたファイルのOversizeのデータも、後のファイルのログのために保持する
+        track_parsed_log(skipped_log(skipped), &config, &mut writer, &mut state).unwrap();
+        assert_eq!(state.oversize_strings.oversize.len(), 1);
+        let parsed_log = build_tracev3(in_range, &[], &[], &[], true, &config);
+        // ファイルのログは、そのファイル自身のOversizeのデータだけで構築する
+        assert_eq!(parsed_log.log_data.oversize.len(), 1);
+        track_parsed_log(parsed_log, &config, &mut writer, &mut state).unwrap();
+
+        // 他のファイルのOversizeのデータを参照するログは、最後に全てのOversizeのデータを使って構築する
+        state.missing_data.push(tracev3(7_000, 8_000, 3));
+        build_missing_logs(&[], &[], &[], &mut writer, &config, &mut state).unwrap();
+        drop(writer);
+        fs::remove_file(&out).unwrap();
+
+        // 構築に使ったOversizeのデータは、コピーせずに戻している
+        assert!(state.missing_data.is_empty());
+        let data_ref_indexes: Vec<u32> = state
+            .oversize_strings
+            .oversize
+            .iter()
+            .map(|oversize| oversize.data_ref_index)
+            .collect();
+        assert_eq!(data_ref_indexes, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_parse_trace_file_failures() {
+        let out = std::env::temp_dir().join("macos-log-dfir-test-failures.csv");
+        let archive = std::env::temp_dir().join("macos-log-dfir-test-failures");
+        let _ = fs::remove_dir_all(&archive);
+        fs::create_dir_all(&archive).unwrap();
+        // tracev3ファイルのディレクトリがファイルになっていて読めない
+        fs::write(archive.join("Persist"), "").unwrap();
+
+        let mut writer = TimelineWriter::csv(&out, true).unwrap();
+        let tuning = Tuning::default();
+        let config = TimelineConfig {
+            rules: &[],
+            correlations: &[],
+            tuning: &tuning,
+            time_filter: TimeFilter::default(),
+            threads: 1,
+            collect_tcc: false,
+            collect_authentication: false,
+        };
+        let result =
+            parse_trace_file(&[], &[], &[], archive.clone(), &mut writer, &config, vec![]).unwrap();
+        drop(writer);
+        fs::remove_file(&out).unwrap();
+        fs::remove_dir_all(&archive).unwrap();
+
+        // 読めなかったディレクトリは記録して処理を続ける
+        assert_eq!(result.failures.len(), 1);
+        assert!(matches!(result.failures[0], ParseError::ReadDir { .. }));
+        assert!(result.detect_infos.is_empty());
+    }
+
+    #[test]
+    fn test_output_results_detect() {
+        let rules = [parse_rule_from_str(
+            r#"
+        title: xattr
+        detection:
+            selection:
+                processImagePath|endswith: '/xattr'
+            condition: selection
+        "#,
+        )];
+        let tuning = Tuning::default();
+        let config = TimelineConfig {
+            rules: &rules,
+            correlations: &[],
+            tuning: &tuning,
+            time_filter: TimeFilter::default(),
+            threads: 1,
+            collect_tcc: false,
+            collect_authentication: false,
+        };
+        let out = std::env::temp_dir().join("macos-log-dfir-test-detect.jsonl");
+        let mut writer = TimelineWriter::jsonl(&out, true).unwrap();
+        let mut state = ParseState::new(vec![]);
+        let results = vec![
+            create_logdata("/bin/ls", "ls -la").data,
+            create_logdata("/usr/bin/xattr", "xattr -c a.app").data,
+        ];
+        output_results(results, &config, &mut writer, &mut state).unwrap();
+        drop(writer);
+        let jsonl = fs::read_to_string(&out).unwrap();
+        fs::remove_file(&out).unwrap();
+
+        // ルールに一致したログだけ検知結果を記録し、タイムラインにも検知したルールを出力する
+        assert_eq!(state.detect_infos.len(), 1);
+        assert_eq!(state.detect_infos[0].ruletitle, "xattr");
+        assert_eq!(state.detect_infos[0].logdata.process, "/usr/bin/xattr");
+        let records: Vec<Value> = jsonl
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].get("detections").is_none());
+        assert_eq!(records[1]["detections"][0]["title"], "xattr");
+    }
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn test_output_results_tcc() {
+        let tccd_logdata = |minute: f64, message: &str| -> LogData {
+            let mut data = create_tccd_logdata(message);
+            data.time += minute * 60_000_000_000.0;
+            data.data
+        };
+        let start = create_logdata("", "").time as i64 + 60_000_000_000;
+        let tuning = Tuning::default();
+        let config = TimelineConfig {
+            rules: &[],
+            correlations: &[],
+            tuning: &tuning,
+            time_filter: TimeFilter {
+                start: Some(start),
+                end: None,
+            },
+            threads: 1,
+            collect_tcc: true,
+            collect_authentication: false,
+        };
+        let run = |config: &TimelineConfig| {
+            let mut state = ParseState::new(vec![]);
+            // 時間範囲より前のメッセージと、別のtracev3ファイルの結果のメッセージからtccのイベントを作成する
+            let files = [
+                vec![
+                    tccd_logdata(0.0, "AUTHREQ_CTX: msgID=401.12, function=TCCAccessRequest, service=kTCCServiceCamera, preflight=no, query=1, client_dict=(null), daemon_dict=0x0"),
+                    tccd_logdata(0.0, "AUTHREQ_SUBJECT: msgID=401.12, subject=com.example.app,"),
+                    tccd_logdata(0.0, "AUTHREQ_CTX: msgID=401.13, function=TCCAccessRequest, service=kTCCServiceMicrophone, preflight=no, query=1, client_dict=(null), daemon_dict=0x0"),
+                    tccd_logdata(0.5, "AUTHREQ_RESULT: msgID=401.13, authValue=0, authReason=2, authVersion=1, desired_auth=0, error=(null),"),
+                ],
+                vec![tccd_logdata(
+                    2.0,
+                    "AUTHREQ_RESULT: msgID=401.12, authValue=2, authReason=2, authVersion=1, desired_auth=0, error=(null),",
+                )],
+            ];
+            for file in files {
+                output_results(file, config, &mut TimelineWriter::Discard, &mut state).unwrap();
+            }
+            state.tcc_events
+        };
+
+        // 時間範囲外の結果のtccのイベントは出力しない
+        let tcc_events = run(&config);
+        assert_eq!(tcc_events.len(), 1);
+        let event = &tcc_events[0];
+        assert_eq!(event.event_type, TCC);
+        assert_eq!(
+            get_event_value(event, "Service").unwrap(),
+            "kTCCServiceCamera"
+        );
+        assert_eq!(get_event_value(event, "Client").unwrap(), "com.example.app");
+
+        // --tccを指定しない場合はtccのイベントを保持しない
+        let config = TimelineConfig {
+            collect_tcc: false,
+            ..config
+        };
+        assert!(run(&config).is_empty());
+    }
+
+    #[test]
+    fn test_output_results_authentication() {
+        let tuning = Tuning::default();
+        let run = |collect_authentication| {
+            let config = TimelineConfig {
+                rules: &[],
+                correlations: &[],
+                tuning: &tuning,
+                time_filter: TimeFilter::default(),
+                threads: 1,
+                collect_tcc: false,
+                collect_authentication,
+            };
+            let data = create_logdata(
+                "/usr/sbin/sshd",
+                "Accepted publickey for admin from 10.0.0.5 port 52144 ssh2",
+            );
+            let mut state = ParseState::new(vec![]);
+            output_results(
+                vec![data.data],
+                &config,
+                &mut TimelineWriter::Discard,
+                &mut state,
+            )
+            .unwrap();
+            state.authentication_events
+        };
+        // logon-summary以外ではauthenticationのイベントを保持しない
+        assert_eq!(run(true).len(), 1);
+        assert!(run(false).is_empty());
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let panic = std::thread::spawn(|| panic!("corrupted chunk"))
+            .join()
+            .unwrap_err();
+        assert_eq!(
+            panic_message(panic.as_ref()),
+            "parser panicked: corrupted chunk"
+        );
+        let panic = std::thread::spawn(|| panic!("{} bytes", 10))
+            .join()
+            .unwrap_err();
+        assert_eq!(panic_message(panic.as_ref()), "parser panicked: 10 bytes");
     }
 }
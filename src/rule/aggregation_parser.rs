@@ -0,0 +1,184 @@
+use super::condition_parser::RE_PIPE;
+use super::fieldmapping::FIELD_MAPPING;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::fmt;
+
+lazy_static! {
+    // | count() > 5, | count(field) by field >= 10 等の集計条件にマッチする正規表現
+    static ref AGGREGATION_REGEX: Regex = Regex::new(
+        r"^\s*\|\s*(\w+)\(\s*([\w.\-]*)\s*\)\s*(?:by\s+([\w.\-]+)\s*)?(==|!=|<=|>=|=|<|>)\s*(\d+)\s*$"
+    )
+    .unwrap();
+    // 5m, 1h 等のtimeframeにマッチする正規表現
+    static ref TIMEFRAME_REGEX: Regex = Regex::new(r"^\s*(\d+)\s*([smhd])\s*$").unwrap();
+}
+
+/// 集計条件の比較演算子
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggregationOperator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl AggregationOperator {
+    fn from_str(op: &str) -> Option<AggregationOperator> {
+        match op {
+            "=" | "==" => Some(AggregationOperator::Eq),
+            "!=" => Some(AggregationOperator::Ne),
+            "<" => Some(AggregationOperator::Lt),
+            "<=" => Some(AggregationOperator::Le),
+            ">" => Some(AggregationOperator::Gt),
+            ">=" => Some(AggregationOperator::Ge),
+            _ => None,
+        }
+    }
+
+    /// 集計した値と、条件に指定された値を比較します。
+    pub fn compare(&self, count: i64, cmp_num: i64) -> bool {
+        match self {
+            AggregationOperator::Eq => count == cmp_num,
+            AggregationOperator::Ne => count != cmp_num,
+            AggregationOperator::Lt => count < cmp_num,
+            AggregationOperator::Le => count <= cmp_num,
+            AggregationOperator::Gt => count > cmp_num,
+            AggregationOperator::Ge => count >= cmp_num,
+        }
+    }
+}
+
+/// conditionのパイプ以降に書かれた集計条件をパースした結果
+#[derive(Debug)]
+pub struct AggregationParseInfo {
+    /// count(field)のfield。count()の場合はNone
+    pub field_name: Option<String>,
+    /// byで指定されたグループ化するフィールド
+    pub by_field_name: Option<String>,
+    pub cmp_op: AggregationOperator,
+    pub cmp_num: i64,
+    // ルールに書かれた集計条件の文字列
+    condition: String,
+}
+
+impl fmt::Display for AggregationParseInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.condition)
+    }
+}
+
+/// conditionから集計条件を取り出してパースします。集計条件が無い場合はNoneを返す。
+pub fn parse_aggregation(condition_str: &str) -> Result<Option<AggregationParseInfo>, String> {
+    let aggregation_str = match RE_PIPE.find(condition_str) {
+        Some(matched) => matched.as_str(),
+        None => return Ok(None),
+    };
+    let captures = AGGREGATION_REGEX.captures(aggregation_str).ok_or_else(|| {
+        format!(
+            "An aggregation condition parse error has occurred. {}",
+            aggregation_str.trim()
+        )
+    })?;
+
+    let function = captures.get(1).unwrap().as_str();
+    if function != "count" {
+        return Err(format!(
+            "Unsupported aggregation function. function:{}",
+            function
+        ));
+    }
+    let field_name = captures
+        .get(2)
+        .map(|m| m.as_str())
+        .filter(|field| !field.is_empty());
+    let by_field_name = captures.get(3).map(|m| m.as_str());
+    for field in field_name.iter().chain(by_field_name.iter()) {
+        if FIELD_MAPPING.resolve(field).is_none() {
+            return Err(format!("Found unknown field. key:{}", field));
+        }
+    }
+    let cmp_num = captures
+        .get(5)
+        .unwrap()
+        .as_str()
+        .parse::<i64>()
+        .map_err(|err| format!("Invalid aggregation value. {}", err))?;
+
+    Ok(Some(AggregationParseInfo {
+        field_name: field_name.map(|field| field.to_string()),
+        by_field_name: by_field_name.map(|field| field.to_string()),
+        cmp_op: AggregationOperator::from_str(captures.get(4).unwrap().as_str()).unwrap(),
+        cmp_num,
+        condition: aggregation_str
+            .trim()
+            .trim_start_matches('|')
+            .trim()
+            .to_string(),
+    }))
+}
+
+/// timeframeをパースして、ナノ秒に変換します。
+pub fn parse_timeframe(timeframe: &str) -> Result<i64, String> {
+    let captures = TIMEFRAME_REGEX
+        .captures(timeframe)
+        .ok_or_else(|| format!("Invalid timeframe. timeframe:{}", timeframe))?;
+    let num = captures
+        .get(1)
+        .unwrap()
+        .as_str()
+        .parse::<i64>()
+        .map_err(|_| format!("Invalid timeframe. timeframe:{}", timeframe))?;
+    let seconds = match captures.get(2).unwrap().as_str() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => 24 * 60 * 60,
+    };
+    num.checked_mul(seconds * 1_000_000_000)
+        .ok_or_else(|| format!("Invalid timeframe. timeframe:{}", timeframe))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rule::aggregation_parser::{
+        parse_aggregation, parse_timeframe, AggregationOperator,
+    };
+
+    #[test]
+    fn test_parse_aggregation() {
+        assert!(parse_aggregation("selection").unwrap().is_none());
+
+        let info = parse_aggregation("selection | count() > 20")
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.field_name, None);
+        assert_eq!(info.by_field_name, None);
+        assert_eq!(info.cmp_op, AggregationOperator::Gt);
+        assert_eq!(info.cmp_num, 20);
+        assert_eq!(info.to_string(), "count() > 20");
+
+        let info = parse_aggregation("selection and not filter | count(process) by userID >= 3")
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.field_name, Some("process".to_string()));
+        assert_eq!(info.by_field_name, Some("userID".to_string()));
+        assert_eq!(info.cmp_op, AggregationOperator::Ge);
+        assert_eq!(info.cmp_num, 3);
+
+        assert!(parse_aggregation("selection | count() by NoSuchField > 1").is_err());
+        assert!(parse_aggregation("selection | sum(pid) > 1").is_err());
+        assert!(parse_aggregation("selection | count() 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_timeframe() {
+        assert_eq!(parse_timeframe("30s").unwrap(), 30_000_000_000);
+        assert_eq!(parse_timeframe("5m").unwrap(), 300_000_000_000);
+        assert_eq!(parse_timeframe("1h").unwrap(), 3_600_000_000_000);
+        assert_eq!(parse_timeframe("2d").unwrap(), 172_800_000_000_000);
+        assert!(parse_timeframe("5 minutes").is_err());
+    }
+}
@@ -2,11 +2,11 @@ use lazy_static::lazy_static;
 use regex::Regex;
 
 use self::selectionnodes::{
-    AndSelectionNode, NotSelectionNode, OrSelectionNode, RefSelectionNode, SelectionNode,
+    AndSelectionNode, CountSelectionNode, NotSelectionNode, OrSelectionNode, RefSelectionNode,
+    SelectionNode,
 };
 use super::selectionnodes;
 use hashbrown::HashMap;
-use itertools::Itertools;
 use std::{sync::Arc, vec::IntoIter};
 
 lazy_static! {
@@ -14,11 +14,14 @@ lazy_static! {
         Regex::new(r"^\(").unwrap(),
         Regex::new(r"^\)").unwrap(),
         Regex::new(r"^ ").unwrap(),
-        Regex::new(r"^\w+").unwrap(),
+        // selection名には、ルールによっては|や-が含まれる
+        Regex::new(r"^[\w|\-]+").unwrap(),
     ];
-    pub static ref RE_PIPE: Regex = Regex::new(r"\|.*").unwrap();
-    // all of selection* と 1 of selection* にマッチする正規表現
-    pub static ref OF_SELECTION: Regex = Regex::new(r"(all|1) of ([^*]+)\*").unwrap();
+    // 集計条件のパイプ。selection名に含まれる|と区別するため、前に空白があるものだけ対象にする
+    pub static ref RE_PIPE: Regex = Regex::new(r"\s+\|.*").unwrap();
+    // all of selection*, 1 of them, 2 of selection_cli_* 等にマッチする正規表現
+    pub static ref OF_SELECTION: Regex =
+        Regex::new(r"\b(all|\d+) of ([\w*|\-]+)").unwrap();
 }
 
 #[derive(Debug, Clone)]
@@ -121,10 +124,8 @@ impl ConditionCompiler {
         condition_str: &str,
         name_2_node: &HashMap<String, Arc<Box<dyn SelectionNode>>>,
     ) -> Result<Box<dyn SelectionNode>, String> {
-        let node_keys: Vec<String> = name_2_node.keys().cloned().collect();
-        let condition_str = Self::convert_condition(condition_str, &node_keys);
         // パイプはここでは処理しない
-        let captured = self::RE_PIPE.captures(condition_str.as_str());
+        let captured = self::RE_PIPE.captures(condition_str);
         let replaced_condition = if let Some(cap) = captured {
             let captured = cap.get(0).unwrap().as_str();
             condition_str.replacen(captured, "", 1)
@@ -132,7 +133,10 @@ impl ConditionCompiler {
             condition_str.to_string()
         };
 
-        let result = self.compile_condition_body(&replaced_condition, name_2_node);
+        let mut name_2_node = name_2_node.clone();
+        let result = Self::convert_condition(&replaced_condition, &mut name_2_node).and_then(
+            |converted_condition| self.compile_condition_body(&converted_condition, &name_2_node),
+        );
         if let Err(msg) = result {
             Err(format!("A condition parse error has occurred. {msg}"))
         } else {
@@ -140,28 +144,84 @@ impl ConditionCompiler {
         }
     }
 
-    // all of selection* と 1 of selection* を通常のand/orに変換する
-    pub fn convert_condition(condition_str: &str, node_keys: &[String]) -> String {
-        let mut converted_str = condition_str.to_string();
-        for matched in OF_SELECTION.find_iter(condition_str) {
-            let match_str: &str = matched.as_str();
-            let sep = if match_str.starts_with("all") {
-                " and "
+    // all of selection*, 1 of them, N of selection* を通常のand/orに変換する
+    // 2以上N未満の個数を指定された場合は、CountSelectionNodeをname_2_nodeに追加して、そのノードの名前に置き換える
+    pub fn convert_condition(
+        condition_str: &str,
+        name_2_node: &mut HashMap<String, Arc<Box<dyn SelectionNode>>>,
+    ) -> Result<String, String> {
+        // パターンの展開対象は元のselectionだけで、変換中に追加したCountSelectionNodeは含まない
+        let mut selection_keys: Vec<String> = name_2_node.keys().cloned().collect();
+        selection_keys.sort();
+        // themには、_で始まるselectionは含まない
+        let them_keys: Vec<String> = selection_keys
+            .iter()
+            .filter(|key| !key.starts_with('_'))
+            .cloned()
+            .collect();
+
+        let mut converted_str = String::new();
+        let mut last_end = 0;
+        for (count_index, captures) in OF_SELECTION.captures_iter(condition_str).enumerate() {
+            let matched = captures.get(0).unwrap();
+            let quantifier = captures.get(1).unwrap().as_str();
+            let pattern = captures.get(2).unwrap().as_str();
+
+            let target_keys: Vec<String> = if pattern == "them" {
+                them_keys.clone()
+            } else {
+                let pattern_regex = Regex::new(&format!(
+                    "^{}$",
+                    regex::escape(pattern).replace("\\*", ".*")
+                ))
+                .unwrap();
+                selection_keys
+                    .iter()
+                    .filter(|key| pattern_regex.is_match(key))
+                    .cloned()
+                    .collect()
+            };
+            if target_keys.is_empty() {
+                return Err(format!(
+                    "{} does not match any selection.",
+                    matched.as_str()
+                ));
+            }
+
+            let count = if quantifier == "all" {
+                target_keys.len()
+            } else {
+                quantifier.parse::<usize>().unwrap_or_default()
+            };
+            if count == 0 || count > target_keys.len() {
+                return Err(format!(
+                    "{} cannot be satisfied. {} selection(s) matched.",
+                    matched.as_str(),
+                    target_keys.len()
+                ));
+            }
+
+            let replaced_condition = if count == 1 {
+                format!("({})", target_keys.join(" or "))
+            } else if count == target_keys.len() {
+                format!("({})", target_keys.join(" and "))
             } else {
-                " or "
+                let count_node_name = format!("_count_of_{}", count_index);
+                let selection_nodes = target_keys
+                    .iter()
+                    .map(|key| Arc::clone(&name_2_node[key]))
+                    .collect();
+                let count_node: Box<dyn SelectionNode> =
+                    Box::new(CountSelectionNode::new(count, selection_nodes));
+                name_2_node.insert(count_node_name.to_owned(), Arc::new(count_node));
+                count_node_name
             };
-            let target_node_key_prefix = match_str
-                .replace('*', "")
-                .replace("all of ", "")
-                .replace("1 of ", "");
-            let replaced_condition = node_keys
-                .iter()
-                .filter(|x| x.starts_with(target_node_key_prefix.as_str()))
-                .join(sep);
-            converted_str =
-                converted_str.replace(match_str, format!("({})", replaced_condition).as_str())
+            converted_str.push_str(&condition_str[last_end..matched.start()]);
+            converted_str.push_str(&replaced_condition);
+            last_end = matched.end();
         }
-        converted_str
+        converted_str.push_str(&condition_str[last_end..]);
+        Ok(converted_str)
     }
 
     /// 与えたConditionからSelectionNodeを作る
@@ -0,0 +1,673 @@
+use super::aggregation_parser::{parse_timeframe, AggregationOperator};
+use super::count::{count_values, AggregationWindow, WindowMatch};
+use super::fieldmapping::{get_event_value, FIELD_MAPPING};
+use super::rulenode::RuleNode;
+use crate::events::EventRecord;
+use crate::yml::get_string_list;
+use hashbrown::{HashMap, HashSet};
+use std::collections::VecDeque;
+use std::fmt;
+use yaml_rust::Yaml;
+
+/// Sigmaの相関ルール(correlation)の種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CorrelationType {
+    /// 参照するルールに一致したログの数
+    EventCount,
+    /// 参照するルールに一致したログの、フィールドの値の種類の数
+    ValueCount,
+    /// 参照する全てのルールに、timespanの間に一致したログがあること
+    Temporal,
+    /// 参照する全てのルールに、timespanの間にrulesに書かれた順番で一致したログがあること
+    TemporalOrdered,
+}
+
+impl CorrelationType {
+    fn from_str(correlation_type: &str) -> Option<CorrelationType> {
+        match correlation_type {
+            "event_count" => Some(CorrelationType::EventCount),
+            "value_count" => Some(CorrelationType::ValueCount),
+            "temporal" => Some(CorrelationType::Temporal),
+            "temporal_ordered" => Some(CorrelationType::TemporalOrdered),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CorrelationType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let correlation_type = match self {
+            CorrelationType::EventCount => "event_count",
+            CorrelationType::ValueCount => "value_count",
+            CorrelationType::Temporal => "temporal",
+            CorrelationType::TemporalOrdered => "temporal_ordered",
+        };
+        write!(f, "{}", correlation_type)
+    }
+}
+
+/// 相関ルールのウィンドウに保持する、参照するルールに一致したログ。
+/// ログ全体ではなく、検知結果に出力する項目と集計に使う値だけを保持する
+#[derive(Debug)]
+pub struct CorrelationHit {
+    /// ログが一致したルールの番号
+    pub rule_index: usize,
+    pub time: f64,
+    pub process: String,
+    pub message: String,
+    /// value_countで値の種類を数えるフィールドの値
+    value: Option<String>,
+}
+
+/// 相関ルールに一致した結果
+#[derive(Debug)]
+pub struct CorrelationResult {
+    /// 相関ルールに一致したログ。時系列順に並んでいる
+    pub records: Vec<CorrelationHit>,
+    /// 集計した値。temporalとtemporal_orderedの場合は一致したルールの数
+    pub count: i64,
+    /// group-byで指定されたフィールドと値
+    pub group: Vec<(String, String)>,
+    /// 検知結果として出力するログ。最後に一致したログ
+    pub logdata: EventRecord,
+}
+
+/// 相関ルールの、group-byの値毎のウィンドウ。ログを読み込んだ順に集計する
+#[derive(Default)]
+pub struct CorrelationState {
+    windows: HashMap<Vec<String>, AggregationWindow<CorrelationHit>>,
+}
+
+/// Sigmaの相関ルール。他のルールに一致したログをtimespanの間で集計する
+pub struct CorrelationRule {
+    pub rulepath: String,
+    pub yaml: Yaml,
+    correlation_type: CorrelationType,
+    /// 参照するルールの、RuleNodeの配列での番号。rulesに書かれた順番に並んでいる
+    rule_indexes: Vec<usize>,
+    /// 参照するルール毎の、group-byで使うフィールド名。aliasesで読み替えた後のもの
+    group_fields: HashMap<usize, Vec<String>>,
+    group_by: Vec<String>,
+    timespan: i64,
+    conditions: Vec<(AggregationOperator, i64)>,
+    /// value_countで値の種類を数えるフィールド
+    field: Option<String>,
+    /// 参照するルールの検知結果も出力するかどうか
+    pub generate: bool,
+}
+
+/// correlationを持つルールは相関ルールとして扱う
+pub fn is_correlation_rule(yaml: &Yaml) -> bool {
+    !yaml["correlation"].is_badvalue()
+}
+
+/// 相関ルールが参照しているルールの名前またはid
+pub fn get_rule_references(yaml: &Yaml) -> Vec<String> {
+    get_string_list(&yaml["correlation"]["rules"])
+}
+
+/// ルールを参照する時に使える、ルールのnameとid
+pub fn get_rule_names(yaml: &Yaml) -> Vec<String> {
+    ["name", "id"]
+        .iter()
+        .filter_map(|key| yaml[*key].as_str())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+impl CorrelationRule {
+    pub fn new(rule_path: String, yaml_data: Yaml) -> CorrelationRule {
+        CorrelationRule {
+            rulepath: rule_path,
+            yaml: yaml_data,
+            correlation_type: CorrelationType::EventCount,
+            rule_indexes: vec![],
+            group_fields: HashMap::new(),
+            group_by: vec![],
+            timespan: 0,
+            conditions: vec![],
+            field: None,
+            generate: false,
+        }
+    }
+
+    /// 相関ルールをパースして、参照するルールをrulesの中から探します。
+    /// ルールはnameまたはidで参照できる。correlation_namesは全ての相関ルールのnameとidで、
+    /// 相関ルールの結果を別の相関ルールで集計することには対応していないため、相関ルールを参照している場合はエラーにする
+    pub fn init(
+        &mut self,
+        rules: &[RuleNode],
+        correlation_names: &HashSet<String>,
+    ) -> Result<(), Vec<String>> {
+        let correlation = self.yaml["correlation"].clone();
+        let mut err_msgs = vec![];
+
+        // 種類によって他の項目の書き方が変わるので、種類が分からない場合はここで終わる
+        self.correlation_type = match correlation["type"].as_str() {
+            Some(correlation_type) => match CorrelationType::from_str(correlation_type) {
+                Some(correlation_type) => correlation_type,
+                None => {
+                    return Err(vec![format!(
+                        "Unknown correlation type. type:{}",
+                        correlation_type
+                    )])
+                }
+            },
+            None => return Err(vec!["There is no type in correlation.".to_string()]),
+        };
+
+        let references = get_rule_references(&self.yaml);
+        if references.is_empty() {
+            err_msgs.push("There is no rules in correlation.".to_string());
+        }
+        let mut rule_names = vec![];
+        for reference in &references {
+            let found = rules.iter().position(|rule| {
+                rule.yaml["name"].as_str() == Some(reference)
+                    || rule.yaml["id"].as_str() == Some(reference)
+            });
+            match found {
+                Some(index) => {
+                    self.rule_indexes.push(index);
+                    rule_names.push(reference.as_str());
+                }
+                None if correlation_names.contains(reference) => err_msgs.push(format!(
+                    "Correlation rules cannot reference other correlation rules. rule:{}",
+                    reference
+                )),
+                None => err_msgs.push(format!("Referenced rule was not found. rule:{}", reference)),
+            }
+        }
+
+        self.group_by = get_string_list(&correlation["group-by"]);
+        for (&index, reference) in self.rule_indexes.iter().zip(rule_names) {
+            // aliasesでは、ルール毎に別のフィールドをgroup-byの1つのフィールドとして扱える
+            let fields = self
+                .group_by
+                .iter()
+                .map(|field| {
+                    let alias = &correlation["aliases"][field.as_str()];
+                    let rule = &rules[index].yaml;
+                    alias[reference]
+                        .as_str()
+                        .or_else(|| rule["name"].as_str().and_then(|name| alias[name].as_str()))
+                        .or_else(|| rule["id"].as_str().and_then(|id| alias[id].as_str()))
+                        .unwrap_or(field)
+                        .to_string()
+                })
+                .collect::<Vec<String>>();
+            for field in &fields {
+                if FIELD_MAPPING.resolve(field).is_none() {
+                    err_msgs.push(format!("Found unknown field. key:{}", field));
+                }
+            }
+            self.group_fields.insert(index, fields);
+        }
+
+        match correlation["timespan"].as_str() {
+            Some(timespan) => match parse_timeframe(timespan) {
+                Ok(timespan) => self.timespan = timespan,
+                Err(err_msg) => err_msgs.push(err_msg),
+            },
+            None => err_msgs.push("There is no timespan in correlation.".to_string()),
+        }
+
+        if let Err(err) = self.parse_condition(&correlation["condition"]) {
+            err_msgs.extend(err);
+        }
+        self.generate = correlation["generate"].as_bool().unwrap_or(false);
+
+        if err_msgs.is_empty() {
+            Ok(())
+        } else {
+            Err(err_msgs)
+        }
+    }
+
+    // event_countとvalue_countのconditionをパースする。gte: 10のように演算子と値を書き、fieldはvalue_countで使う
+    fn parse_condition(&mut self, condition: &Yaml) -> Result<(), Vec<String>> {
+        let is_count = matches!(
+            self.correlation_type,
+            CorrelationType::EventCount | CorrelationType::ValueCount
+        );
+        let condition_hash = match condition.as_hash() {
+            Some(condition_hash) => condition_hash,
+            None if is_count => {
+                return Err(vec!["There is no condition in correlation.".to_string()])
+            }
+            None => return Ok(()),
+        };
+
+        let mut err_msgs = vec![];
+        for (key, value) in condition_hash {
+            let key = key.as_str().unwrap_or_default();
+            let op = match key {
+                "field" => {
+                    match value.as_str() {
+                        Some(field) if FIELD_MAPPING.resolve(field).is_some() => {
+                            self.field = Some(field.to_string())
+                        }
+                        _ => err_msgs.push(format!(
+                            "Found unknown field. key:{}",
+                            value.as_str().unwrap_or_default()
+                        )),
+                    }
+                    continue;
+                }
+                "gt" => AggregationOperator::Gt,
+                "gte" => AggregationOperator::Ge,
+                "lt" => AggregationOperator::Lt,
+                "lte" => AggregationOperator::Le,
+                "eq" => AggregationOperator::Eq,
+                "neq" => AggregationOperator::Ne,
+                _ => {
+                    err_msgs.push(format!("Unknown correlation condition. key:{}", key));
+                    continue;
+                }
+            };
+            match value.as_i64() {
+                Some(num) => self.conditions.push((op, num)),
+                None => err_msgs.push(format!(
+                    "Correlation condition must be a number. key:{}",
+                    key
+                )),
+            }
+        }
+        if is_count && self.conditions.is_empty() {
+            err_msgs.push("There is no condition in correlation.".to_string());
+        }
+        if self.correlation_type == CorrelationType::ValueCount && self.field.is_none() {
+            err_msgs.push("value_count needs a field in the condition.".to_string());
+        }
+
+        if err_msgs.is_empty() {
+            Ok(())
+        } else {
+            Err(err_msgs)
+        }
+    }
+
+    /// 参照するルールの番号の一覧
+    pub fn get_rule_indexes(&self) -> &[usize] {
+        &self.rule_indexes
+    }
+
+    /// 参照するルールに一致したログをstateのウィンドウで集計し、相関ルールに一致した場合はその結果を返します。
+    pub fn correlate(
+        &self,
+        state: &mut CorrelationState,
+        rule_index: usize,
+        record: &EventRecord,
+    ) -> Option<CorrelationResult> {
+        // group-byで指定されたフィールドの値でグループ化する
+        let fields = self.group_fields.get(&rule_index)?;
+        let group: Vec<String> = fields
+            .iter()
+            .map(|field| get_event_value(record, field).unwrap_or_default())
+            .collect();
+        let hit = CorrelationHit {
+            rule_index,
+            time: record.time,
+            process: record.process.to_owned(),
+            message: record.message.to_owned(),
+            value: self
+                .field
+                .as_ref()
+                .and_then(|field| get_event_value(record, field)),
+        };
+        let matched = state
+            .windows
+            .entry(group.clone())
+            .or_insert_with(|| AggregationWindow::new(Some(self.timespan), self.is_sliding()))
+            .push(
+                record,
+                hit,
+                |window| self.count(window),
+                |count| self.is_match(count),
+            )?;
+        Some(self.create_result(group, matched))
+    }
+
+    /// 全てのログを集計した後に、stateに残っているウィンドウのうち相関ルールに一致したものを時系列順に返します。
+    pub fn finish(&self, state: CorrelationState) -> Vec<CorrelationResult> {
+        let mut results: Vec<CorrelationResult> = state
+            .windows
+            .into_iter()
+            .filter_map(|(group, window)| {
+                let matched =
+                    window.close(|window| self.count(window), |count| self.is_match(count))?;
+                Some(self.create_result(group, matched))
+            })
+            .collect();
+        results.sort_by(|a, b| a.logdata.time.total_cmp(&b.logdata.time));
+        results
+    }
+
+    // temporalとtemporal_orderedは、一致したルールの数が増えるほど条件を満たすので、ウィンドウをずらしながら集計する
+    fn is_sliding(&self) -> bool {
+        match self.correlation_type {
+            CorrelationType::Temporal | CorrelationType::TemporalOrdered => true,
+            _ => self
+                .conditions
+                .iter()
+                .all(|(op, _)| matches!(op, AggregationOperator::Gt | AggregationOperator::Ge)),
+        }
+    }
+
+    // temporalとtemporal_orderedは全てのルールに一致したログがあること、それ以外はconditionの全ての条件を満たすこと
+    fn is_match(&self, count: i64) -> bool {
+        match self.correlation_type {
+            CorrelationType::Temporal | CorrelationType::TemporalOrdered => {
+                count == self.rule_indexes.len() as i64
+            }
+            _ => self
+                .conditions
+                .iter()
+                .all(|(op, cmp_num)| op.compare(count, *cmp_num)),
+        }
+    }
+
+    fn create_result(
+        &self,
+        group: Vec<String>,
+        matched: WindowMatch<CorrelationHit>,
+    ) -> CorrelationResult {
+        CorrelationResult {
+            records: matched.hits.into_iter().map(|(_, hit)| hit).collect(),
+            count: matched.count,
+            group: self.group_by.iter().cloned().zip(group).collect(),
+            logdata: matched.logdata,
+        }
+    }
+
+    // ウィンドウの中のログを相関ルールの種類に応じて集計する
+    fn count(&self, window: &VecDeque<(f64, CorrelationHit)>) -> i64 {
+        match self.correlation_type {
+            CorrelationType::EventCount => window.len() as i64,
+            CorrelationType::ValueCount => count_values(window.iter().map(|(_, hit)| &hit.value)),
+            CorrelationType::Temporal => window
+                .iter()
+                .map(|(_, hit)| hit.rule_index)
+                .collect::<HashSet<usize>>()
+                .len() as i64,
+            CorrelationType::TemporalOrdered => {
+                // rulesの順番に一致したルールの数を、先頭から貪欲に数える
+                let mut matched = 0;
+                for (_, hit) in window {
+                    if self.rule_indexes.get(matched) == Some(&hit.rule_index) {
+                        matched += 1;
+                    }
+                }
+                matched as i64
+            }
+        }
+    }
+
+    /// 検知結果に出力する、相関ルールの条件と集計した値の説明
+    pub fn get_summary(&self, result: &CorrelationResult) -> String {
+        let mut summary = self.correlation_type.to_string();
+        for (op, cmp_num) in &self.conditions {
+            let op = match op {
+                AggregationOperator::Eq => "==",
+                AggregationOperator::Ne => "!=",
+                AggregationOperator::Lt => "<",
+                AggregationOperator::Le => "<=",
+                AggregationOperator::Gt => ">",
+                AggregationOperator::Ge => ">=",
+            };
+            summary.push_str(&format!(" {} {}", op, cmp_num));
+        }
+        let mut details = vec![format!("count: {}", result.count)];
+        details.extend(
+            result
+                .group
+                .iter()
+                .map(|(field, value)| format!("{}: {}", field, value)),
+        );
+        format!("{} ({})", summary, details.join(", "))
+    }
+}
+
+/// 相関ルールから参照されているルールに印を付けます。
+/// 参照されているルール単独の検知結果は、generate: trueの相関ルールから参照されている場合だけ出力する
+pub fn mark_correlated_rules(rules: &mut [RuleNode], correlations: &[CorrelationRule]) {
+    for correlation in correlations {
+        for &index in &correlation.rule_indexes {
+            let rule = &mut rules[index];
+            if !rule.correlated {
+                rule.correlated = true;
+                rule.generate = false;
+            }
+            rule.generate |= correlation.generate;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::EventRecord;
+    use crate::rule::correlation::{CorrelationResult, CorrelationRule, CorrelationState};
+    use crate::rule::rulenode::RuleNode;
+    use crate::test_helpers::{create_logdata, parse_rule_from_str};
+    use hashbrown::HashSet;
+    use yaml_rust::YamlLoader;
+
+    const MINUTE: f64 = 60_000_000_000.0;
+
+    fn create_rules() -> Vec<RuleNode> {
+        ["curl", "xattr", "open"]
+            .iter()
+            .map(|process| {
+                parse_rule_from_str(&format!(
+                    r#"
+        name: {}_execution
+        id: 00000000-0000-0000-0000-00000000000{}
+        detection:
+            selection:
+                process: {}
+            condition: selection
+        "#,
+                    process,
+                    process.len(),
+                    process
+                ))
+            })
+            .collect()
+    }
+
+    fn create_correlation(rules: &[RuleNode], correlation: &str) -> CorrelationRule {
+        let yaml = YamlLoader::load_from_str(correlation).unwrap().remove(0);
+        let mut correlation = CorrelationRule::new("testpath".to_string(), yaml);
+        let result = correlation.init(rules, &HashSet::new());
+        assert!(result.is_ok(), "{:?}", result);
+        correlation
+    }
+
+    // 参照するルールに一致したログを時系列順に集計し、全てのログを読み込んだ後に残っているウィンドウも集計する
+    fn correlate(
+        correlation: &CorrelationRule,
+        mut hits: Vec<(usize, EventRecord)>,
+    ) -> Vec<CorrelationResult> {
+        hits.sort_by(|a, b| a.1.time.total_cmp(&b.1.time));
+        let mut state = CorrelationState::default();
+        let mut results: Vec<CorrelationResult> = hits
+            .iter()
+            .filter_map(|(index, record)| correlation.correlate(&mut state, *index, record))
+            .collect();
+        results.extend(correlation.finish(state));
+        results
+    }
+
+    fn create_hit(index: usize, process: &str, minute: f64, euid: u32) -> (usize, EventRecord) {
+        let mut record = create_logdata(process, "");
+        record.time += minute * MINUTE;
+        record.euid = euid;
+        (index, record)
+    }
+
+    #[test]
+    fn test_correlate_event_count() {
+        let rules = create_rules();
+        let correlation = create_correlation(
+            &rules,
+            r#"
+        correlation:
+            type: event_count
+            rules:
+                - curl_execution
+            group-by:
+                - userID
+            timespan: 5m
+            condition:
+                gte: 3
+        "#,
+        );
+        let hits = vec![
+            create_hit(0, "/usr/bin/curl", 0.0, 501),
+            create_hit(0, "/usr/bin/curl", 1.0, 501),
+            create_hit(0, "/usr/bin/curl", 2.0, 502),
+            create_hit(0, "/usr/bin/curl", 3.0, 501),
+        ];
+        let results = correlate(&correlation, hits);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].records.len(), 3);
+        assert_eq!(
+            correlation.get_summary(&results[0]),
+            "event_count >= 3 (count: 3, userID: 501)"
+        );
+    }
+
+    #[test]
+    fn test_correlate_value_count() {
+        let rules = create_rules();
+        let correlation = create_correlation(
+            &rules,
+            r#"
+        correlation:
+            type: value_count
+            rules:
+                - curl_execution
+                - 00000000-0000-0000-0000-000000000005
+            timespan: 10m
+            condition:
+                gt: 1
+                field: process
+        "#,
+        );
+        let hits = vec![
+            create_hit(0, "/usr/bin/curl", 0.0, 501),
+            create_hit(0, "/usr/bin/curl", 1.0, 501),
+            create_hit(1, "/usr/bin/xattr", 2.0, 501),
+        ];
+        let results = correlate(&correlation, hits);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].count, 2);
+    }
+
+    #[test]
+    fn test_correlate_temporal() {
+        let rules = create_rules();
+        let correlation_str = r#"
+        correlation:
+            type: temporal_ordered
+            rules:
+                - curl_execution
+                - xattr_execution
+                - open_execution
+            group-by:
+                - user
+            aliases:
+                user:
+                    curl_execution: euid
+                    xattr_execution: euid
+                    open_execution: userID
+            timespan: 10m
+        "#;
+        let correlation = create_correlation(&rules, correlation_str);
+        // ダウンロード、xattrによる隔離属性の削除、実行の順番
+        let hits = vec![
+            create_hit(0, "/usr/bin/curl", 0.0, 501),
+            create_hit(1, "/usr/bin/xattr", 1.0, 501),
+            create_hit(2, "/usr/bin/open", 2.0, 501),
+        ];
+        let results = correlate(&correlation, hits.clone());
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].records.len(), 3);
+        assert_eq!(
+            results[0].group,
+            vec![("user".to_string(), "501".to_string())]
+        );
+
+        // 順番が違う場合、temporal_orderedには一致しないが、temporalには一致する
+        let mut reordered = hits.clone();
+        reordered[0].1.time += 5.0 * MINUTE;
+        assert!(correlate(&correlation, reordered.clone()).is_empty());
+        let correlation = create_correlation(
+            &rules,
+            &correlation_str.replace("temporal_ordered", "temporal"),
+        );
+        assert_eq!(correlate(&correlation, reordered).len(), 1);
+
+        // 別のユーザーやtimespanの外のログは組み合わせない
+        let mut other_user = hits.clone();
+        other_user[2].1.euid = 502;
+        assert!(correlate(&correlation, other_user).is_empty());
+        let mut late = hits;
+        late[2].1.time += 20.0 * MINUTE;
+        assert!(correlate(&correlation, late).is_empty());
+    }
+
+    #[test]
+    fn test_init_invalid_correlation() {
+        let rules = create_rules();
+        let correlation_names = HashSet::from(["many_downloads".to_string()]);
+        let init = |correlation: &str| {
+            let yaml = YamlLoader::load_from_str(correlation).unwrap().remove(0);
+            CorrelationRule::new("testpath".to_string(), yaml)
+                .init(&rules, &correlation_names)
+                .unwrap_err()
+        };
+        assert_eq!(
+            init("correlation:\n  type: sequence\n  rules: curl_execution\n  timespan: 5m\n"),
+            vec!["Unknown correlation type. type:sequence"]
+        );
+        let errors = init(
+            r#"
+        correlation:
+            type: value_count
+            rules:
+                - curl_execution
+                - no_such_rule
+            group-by:
+                - NoSuchField
+            condition:
+                gte: ten
+        "#,
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "Referenced rule was not found. rule:no_such_rule",
+                "Found unknown field. key:NoSuchField",
+                "There is no timespan in correlation.",
+                "Correlation condition must be a number. key:gte",
+                "There is no condition in correlation.",
+                "value_count needs a field in the condition.",
+            ]
+        );
+        // 相関ルールから別の相関ルールは参照できない
+        assert_eq!(
+            init(
+                r#"
+        correlation:
+            type: temporal
+            rules:
+                - curl_execution
+                - many_downloads
+            timespan: 5m
+        "#
+            ),
+            vec!["Correlation rules cannot reference other correlation rules. rule:many_downloads"]
+        );
+    }
+}
@@ -0,0 +1,339 @@
+use super::aggregation_parser::{AggregationOperator, AggregationParseInfo};
+use super::fieldmapping::get_event_value;
+use crate::events::EventRecord;
+use hashbrown::{HashMap, HashSet};
+use std::collections::VecDeque;
+
+/// 集計条件を満たした結果
+#[derive(Debug)]
+pub struct AggregationResult {
+    /// 条件を満たした時点のログ
+    pub logdata: EventRecord,
+    /// 集計した値
+    pub count: i64,
+    /// byで指定されたフィールドの値
+    pub by_value: Option<String>,
+}
+
+/// 集計条件のあるルールの、byの値毎のウィンドウ。ログを読み込んだ順に集計する
+#[derive(Default)]
+pub struct AggregationState {
+    windows: HashMap<Option<String>, AggregationWindow<Option<String>>>,
+}
+
+impl AggregationState {
+    /// selectに一致したログを集計し、集計条件を満たした場合はその結果を返します。
+    pub fn add(
+        &mut self,
+        info: &AggregationParseInfo,
+        timeframe: Option<i64>,
+        record: &EventRecord,
+    ) -> Option<AggregationResult> {
+        // byで指定されたフィールドの値でグループ化する
+        let by_value = info
+            .by_field_name
+            .as_ref()
+            .map(|field| get_event_value(record, field).unwrap_or_default());
+        // count(field)の場合は、値の種類を数えるためにフィールドの値だけを保持する
+        let value = info
+            .field_name
+            .as_ref()
+            .and_then(|field| get_event_value(record, field));
+        let sliding = matches!(
+            info.cmp_op,
+            AggregationOperator::Gt | AggregationOperator::Ge
+        );
+        let matched = self
+            .windows
+            .entry(by_value.to_owned())
+            .or_insert_with(|| AggregationWindow::new(timeframe, sliding))
+            .push(
+                record,
+                value,
+                |hits| count_hits(info, hits),
+                |count| info.cmp_op.compare(count, info.cmp_num),
+            )?;
+        Some(AggregationResult {
+            logdata: matched.logdata,
+            count: matched.count,
+            by_value,
+        })
+    }
+
+    /// 全てのログを集計した後に、残っているウィンドウのうち集計条件を満たしたものを時系列順に返します。
+    pub fn finish(self, info: &AggregationParseInfo) -> Vec<AggregationResult> {
+        let mut results: Vec<AggregationResult> = self
+            .windows
+            .into_iter()
+            .filter_map(|(by_value, window)| {
+                let matched = window.close(
+                    |hits| count_hits(info, hits),
+                    |count| info.cmp_op.compare(count, info.cmp_num),
+                )?;
+                Some(AggregationResult {
+                    logdata: matched.logdata,
+                    count: matched.count,
+                    by_value,
+                })
+            })
+            .collect();
+        results.sort_by(|a, b| a.logdata.time.total_cmp(&b.logdata.time));
+        results
+    }
+}
+
+/// 条件を満たしたウィンドウのヒットと集計した値
+pub struct WindowMatch<T> {
+    /// ウィンドウのヒットの時間と値。時系列順に並んでいる
+    pub hits: Vec<(f64, T)>,
+    pub count: i64,
+    /// 検知結果として出力するログ。ウィンドウの最後のヒットのログ
+    pub logdata: EventRecord,
+}
+
+/// 集計中のウィンドウ。ヒットしたログ全体ではなく、ヒットの時間と集計に使う値だけを時系列順に保持する。
+/// timeframeがある場合は、timeframeより古いヒットを新しいヒットを追加する時に捨てる。
+/// slidingがtrueの場合はウィンドウをヒット毎にずらしながら集計し、条件を満たしたらウィンドウを空にする。
+/// 値が増えるほど条件を満たさなくなる場合(<や=等)は、ウィンドウの最初のヒットからtimeframeの間のヒットをまとめて集計する。
+/// timeframeが無い場合は全てのヒットを1つのウィンドウとして、全てのログを読み込んだ後に集計する。
+pub struct AggregationWindow<T> {
+    hits: VecDeque<(f64, T)>,
+    /// ウィンドウの最後のヒットのログ。ウィンドウを閉じる時の検知結果に使う
+    last: Option<EventRecord>,
+    timeframe: Option<i64>,
+    sliding: bool,
+}
+
+impl<T> AggregationWindow<T> {
+    pub fn new(timeframe: Option<i64>, sliding: bool) -> AggregationWindow<T> {
+        AggregationWindow {
+            hits: VecDeque::new(),
+            last: None,
+            timeframe,
+            sliding,
+        }
+    }
+
+    /// ヒットをウィンドウに追加し、条件を満たしたウィンドウがあればそのヒットと集計した値を返します。
+    /// ログはtracev3ファイルの中では時系列順に読み込まれるので、ヒットも概ね時系列順に追加される。
+    /// ウィンドウの最新のヒットよりtimeframe以上古いヒットは、既に捨てたヒットと同じウィンドウなので数えない
+    pub fn push(
+        &mut self,
+        record: &EventRecord,
+        value: T,
+        count: impl Fn(&VecDeque<(f64, T)>) -> i64,
+        is_match: impl Fn(i64) -> bool,
+    ) -> Option<WindowMatch<T>> {
+        let time = record.time;
+        let timeframe = match self.timeframe {
+            Some(timeframe) => timeframe as f64,
+            None => {
+                self.insert(record, value);
+                return None;
+            }
+        };
+        if self.counts_on_push() {
+            let newest = self.hits.back().map_or(time, |(last, _)| last.max(time));
+            let window_start = newest - timeframe;
+            if time < window_start {
+                return None;
+            }
+            while matches!(self.hits.front(), Some((front, _)) if *front < window_start) {
+                self.hits.pop_front();
+            }
+            self.insert(record, value);
+            let value = count(&self.hits);
+            if !is_match(value) {
+                return None;
+            }
+            self.last = None;
+            return Some(WindowMatch {
+                hits: self.hits.drain(..).collect(),
+                count: value,
+                logdata: record.clone(),
+            });
+        }
+
+        // ウィンドウの最初のヒットからtimeframeを過ぎたら、ウィンドウを閉じて新しいウィンドウを始める
+        let matched = match self.hits.front() {
+            Some((start, _)) if time > start + timeframe => self.take_match(&count, &is_match),
+            _ => None,
+        };
+        self.insert(record, value);
+        matched
+    }
+
+    /// 全てのログを集計した後に、残っているウィンドウを集計します。
+    /// slidingの場合はヒットを追加する度に集計しているので、残っているウィンドウは条件を満たさない
+    pub fn close(
+        mut self,
+        count: impl Fn(&VecDeque<(f64, T)>) -> i64,
+        is_match: impl Fn(i64) -> bool,
+    ) -> Option<WindowMatch<T>> {
+        if self.counts_on_push() {
+            return None;
+        }
+        self.take_match(&count, &is_match)
+    }
+
+    // timeframeがあってslidingの場合は、ヒットを追加する度に集計する
+    fn counts_on_push(&self) -> bool {
+        self.sliding && self.timeframe.is_some()
+    }
+
+    // ヒットを時系列順の位置に追加する。最新のヒットのログは検知結果のために保持する
+    fn insert(&mut self, record: &EventRecord, value: T) {
+        let time = record.time;
+        let position = self.hits.partition_point(|(hit_time, _)| *hit_time <= time);
+        self.hits.insert(position, (time, value));
+        if position + 1 == self.hits.len() && !self.counts_on_push() {
+            self.last = Some(record.clone());
+        }
+    }
+
+    // ウィンドウを空にして、条件を満たしていればそのヒットと集計した値を返す
+    fn take_match(
+        &mut self,
+        count: &impl Fn(&VecDeque<(f64, T)>) -> i64,
+        is_match: &impl Fn(i64) -> bool,
+    ) -> Option<WindowMatch<T>> {
+        let value = count(&self.hits);
+        let hits: Vec<(f64, T)> = self.hits.drain(..).collect();
+        let logdata = self.last.take()?;
+        if hits.is_empty() || !is_match(value) {
+            return None;
+        }
+        Some(WindowMatch {
+            hits,
+            count: value,
+            logdata,
+        })
+    }
+}
+
+// count()はヒットの数、count(field)はフィールドの値の種類の数を数える
+fn count_hits(info: &AggregationParseInfo, hits: &VecDeque<(f64, Option<String>)>) -> i64 {
+    match &info.field_name {
+        None => hits.len() as i64,
+        Some(_) => count_values(hits.iter().map(|(_, value)| value)),
+    }
+}
+
+/// フィールドの値の種類の数を数えます。値が無いヒットは数えない
+pub fn count_values<'a>(values: impl Iterator<Item = &'a Option<String>>) -> i64 {
+    values
+        .flatten()
+        .filter(|value| !value.is_empty())
+        .collect::<HashSet<&String>>()
+        .len() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::EventRecord;
+    use crate::rule::aggregation_parser::{parse_aggregation, AggregationParseInfo};
+    use crate::rule::count::{AggregationResult, AggregationState, AggregationWindow};
+    use crate::test_helpers::create_logdata;
+
+    const MINUTE: f64 = 60_000_000_000.0;
+
+    // ログを時系列順に集計し、全てのログを読み込んだ後に残っているウィンドウも集計する
+    fn aggregate(
+        info: &AggregationParseInfo,
+        timeframe: Option<i64>,
+        mut records: Vec<EventRecord>,
+    ) -> Vec<AggregationResult> {
+        records.sort_by(|a, b| a.time.total_cmp(&b.time));
+        let mut state = AggregationState::default();
+        let mut results: Vec<AggregationResult> = records
+            .iter()
+            .filter_map(|record| state.add(info, timeframe, record))
+            .collect();
+        results.extend(state.finish(info));
+        results
+    }
+
+    fn create_records(times: &[f64], euid: u32) -> Vec<EventRecord> {
+        times
+            .iter()
+            .map(|time| {
+                let mut record = create_logdata("/usr/bin/sudo", "incorrect password attempt");
+                record.time += time * MINUTE;
+                record.euid = euid;
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_aggregate_count() {
+        let info = parse_aggregation("selection | count() by userID > 2")
+            .unwrap()
+            .unwrap();
+        let mut records = create_records(&[0.0, 1.0, 2.0, 3.0, 10.0, 11.0], 501);
+        records.extend(create_records(&[0.5, 1.5], 502));
+
+        // timeframe無しの場合はログ全体で集計する
+        let results = aggregate(&info, None, records.clone());
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].count, 6);
+        assert_eq!(results[0].by_value, Some("501".to_string()));
+
+        // 5分以内に3回以上
+        let results = aggregate(&info, Some(5 * MINUTE as i64), records.clone());
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].count, 3);
+        assert_eq!(results[0].logdata.time, records[2].time);
+
+        // 1分以内に3回以上は無い
+        let results = aggregate(&info, Some(MINUTE as i64), records);
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn test_aggregate_count_field() {
+        let info = parse_aggregation("selection | count(userID) >= 2")
+            .unwrap()
+            .unwrap();
+        let mut records = create_records(&[0.0, 1.0, 2.0], 501);
+        records.extend(create_records(&[20.0], 502));
+        assert_eq!(aggregate(&info, None, records.clone()).len(), 1);
+        assert!(aggregate(&info, Some(5 * MINUTE as i64), records).is_empty());
+    }
+
+    #[test]
+    fn test_aggregate_less_than() {
+        let info = parse_aggregation("selection | count() < 3")
+            .unwrap()
+            .unwrap();
+        let records = create_records(&[0.0, 1.0, 2.0, 10.0, 11.0], 501);
+        // 0分からの5分間は3回なので条件を満たさず、10分からの5分間は2回なので条件を満たす
+        let results = aggregate(&info, Some(5 * MINUTE as i64), records.clone());
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].count, 2);
+        assert_eq!(results[0].logdata.time, records[4].time);
+    }
+
+    #[test]
+    fn test_aggregation_window_eviction() {
+        let mut window = AggregationWindow::new(Some(2 * MINUTE as i64), true);
+        let records = create_records(&[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 501);
+        for record in &records {
+            let matched = window.push(record, (), |hits| hits.len() as i64, |count| count > 100);
+            assert!(matched.is_none());
+            // timeframeより古いヒットは捨て、ログ自体は保持しない
+            assert!(window.hits.len() <= 3);
+            assert!(window.last.is_none());
+        }
+        // 最新のヒットよりtimeframe以上古いヒットは数えない
+        let old = &create_records(&[1.0], 501)[0];
+        assert!(window
+            .push(old, (), |hits| hits.len() as i64, |count| count > 0)
+            .is_none());
+        let matched = window
+            .push(&records[6], (), |hits| hits.len() as i64, |count| count > 3)
+            .unwrap();
+        assert_eq!(matched.count, 4);
+        assert!(window.hits.is_empty());
+    }
+}
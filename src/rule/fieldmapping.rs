@@ -0,0 +1,291 @@
+use crate::events::{is_event_field, EventRecord};
+use crate::yml::{app_file_path, parse_field_mapping, read_field_mapping};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use macos_unifiedlogs::unified_log::LogData;
+use regex::Regex;
+use yaml_rust::Yaml;
+
+lazy_static! {
+    pub static ref FIELD_MAPPING: FieldMapping = load_field_mapping();
+}
+
+// ルールのselectionで直接指定できるUnified Logのフィールド名
+// `log show --style json`の出力と同じ名前にしているので、Jamf Protect等のフィルタの条件をそのまま書ける。
+pub const LOGDATA_FIELDS: [&str; 21] = [
+    "subsystem",
+    "category",
+    "process",
+    "processImagePath",
+    "processImageUUID",
+    "sender",
+    "senderImagePath",
+    "senderImageUUID",
+    "eventType",
+    "messageType",
+    "euid",
+    "userID",
+    "pid",
+    "processID",
+    "threadID",
+    "activityIdentifier",
+    "bootUUID",
+    "eventMessage",
+    "formatString",
+    "timezoneName",
+    "timestamp",
+];
+
+/// Sigmaのフィールド名に対応する値を、LogDataのどこから取得するかを表す。
+#[derive(Clone, Debug)]
+pub enum FieldSource {
+    /// LogDataのフィールドの値をそのまま使う
+    LogData(String),
+    /// LogDataのフィールドの値から、正規表現の1つ目のキャプチャグループを抽出して使う
+    Extract(String, Regex),
+    /// Unified Logのメッセージから作成したイベント(process_creation等)では、イベント独自のフィールド(event_data)から取得する。
+    /// イベントに無いフィールドと、それ以外のログでは、元のFieldSourceで取得する
+    Event(String, Option<Box<FieldSource>>),
+}
+
+impl FieldSource {
+    pub fn get_value(&self, event_record: &EventRecord) -> Option<String> {
+        match self {
+            FieldSource::LogData(field) => get_logdata_value(event_record, field),
+            FieldSource::Extract(field, re) => {
+                let value = get_logdata_value(event_record, field)?;
+                let captures = re.captures(&value)?;
+                captures.get(1).map(|m| m.as_str().to_string())
+            }
+            FieldSource::Event(sigma_field, source) => {
+                match event_record.get_event_data(sigma_field) {
+                    Some(value) => Some(value.to_string()),
+                    None => source.as_ref()?.get_value(event_record),
+                }
+            }
+        }
+    }
+}
+
+/// Sigmaのフィールド名(Image, CommandLine等)からLogDataのフィールドへの対応表
+#[derive(Debug, Default)]
+pub struct FieldMapping {
+    mappings: HashMap<String, FieldSource>,
+}
+
+impl FieldMapping {
+    /// field_mapping.ymlの内容からFieldMappingを作成します。
+    /// 値が文字列の場合はLogDataのフィールド名、fieldとregexを持つ連想配列の場合は正規表現による抽出と解釈する。
+    pub fn from_yaml(yaml: &Yaml) -> Result<FieldMapping, Vec<String>> {
+        let mut mappings = HashMap::new();
+        if yaml.is_null() {
+            return Ok(FieldMapping { mappings });
+        }
+        let yaml_hash = match yaml.as_hash() {
+            Some(hash) => hash,
+            None => return Err(vec!["Field mapping must be a hash.".to_string()]),
+        };
+
+        let mut err_msgs = vec![];
+        for (key, value) in yaml_hash.iter() {
+            let sigma_field = key.as_str().unwrap_or_default();
+            let (field, regex) = if let Some(field) = value.as_str() {
+                (field, None)
+            } else {
+                (
+                    value["field"].as_str().unwrap_or_default(),
+                    value["regex"].as_str(),
+                )
+            };
+            if sigma_field.is_empty() || !LOGDATA_FIELDS.contains(&field) {
+                err_msgs.push(format!(
+                    "Invalid field mapping. [sigma field:{sigma_field}, field:{field}]"
+                ));
+                continue;
+            }
+
+            let source = match regex.map(Regex::new) {
+                None => FieldSource::LogData(field.to_string()),
+                Some(Ok(re)) => FieldSource::Extract(field.to_string(), re),
+                Some(Err(_)) => {
+                    err_msgs.push(format!(
+                        "Cannot parse regex. [regex:{}, sigma field:{sigma_field}]",
+                        regex.unwrap()
+                    ));
+                    continue;
+                }
+            };
+            mappings.insert(sigma_field.to_string(), source);
+        }
+
+        if err_msgs.is_empty() {
+            Ok(FieldMapping { mappings })
+        } else {
+            Err(err_msgs)
+        }
+    }
+
+    /// ルールのフィールド名に対応するFieldSourceを取得します。
+    /// 対応表に無い場合はLogDataのフィールド名として解釈し、それでも見つからなければNoneを返す。
+    pub fn resolve(&self, key: &str) -> Option<FieldSource> {
+        let source = if let Some(source) = self.mappings.get(key) {
+            Some(source.clone())
+        } else if LOGDATA_FIELDS.contains(&key) {
+            Some(FieldSource::LogData(key.to_string()))
+        } else {
+            None
+        };
+        if is_event_field(key) {
+            return Some(FieldSource::Event(key.to_string(), source.map(Box::new)));
+        }
+        source
+    }
+}
+
+/// ルールのフィールド名(Sigmaのフィールド名またはUnified Logのフィールド名)に対応する値をLogDataから取得します。
+/// LeafSelectionNodeとLeafMatcherのどちらからも、フィールドの値はこの関数(またはFieldSource::get_value)で取得する。
+pub fn get_event_value(event_record: &EventRecord, key: &str) -> Option<String> {
+    FIELD_MAPPING.resolve(key)?.get_value(event_record)
+}
+
+/// フィールド名の無いキーワードのselectionと比較する、LogDataの主なフィールドの値を取得します。
+/// どのフィールドに含まれるか分からない文字列(IOC等)を、メッセージ・プロセス・サブシステム・カテゴリ・ライブラリからまとめて検索するために使う。
+/// メッセージから作成したイベントでは、イベント独自のフィールドの値も含める。値はコピーせずにログのフィールドを参照する
+pub fn get_keyword_values(event_record: &EventRecord) -> impl Iterator<Item = &String> {
+    [
+        &event_record.message,
+        &event_record.process,
+        &event_record.subsystem,
+        &event_record.category,
+        &event_record.library,
+    ]
+    .into_iter()
+    .chain(event_record.event_data.iter().map(|(_, value)| value))
+}
+
+/// LogDataから、Unified Logのフィールド名に対応する値を取得します。
+pub fn get_logdata_value(event_record: &LogData, field: &str) -> Option<String> {
+    let value = match field {
+        "subsystem" => event_record.subsystem.to_owned(),
+        "category" => event_record.category.to_owned(),
+        "process" => file_name(&event_record.process),
+        "processImagePath" => event_record.process.to_owned(),
+        "processImageUUID" => event_record.process_uuid.to_owned(),
+        "sender" => file_name(&event_record.library),
+        "senderImagePath" => event_record.library.to_owned(),
+        "senderImageUUID" => event_record.library_uuid.to_owned(),
+        "eventType" => event_record.event_type.to_owned(),
+        "messageType" => event_record.log_type.to_owned(),
+        "euid" | "userID" => event_record.euid.to_string(),
+        "pid" | "processID" => event_record.pid.to_string(),
+        "threadID" => event_record.thread_id.to_string(),
+        "activityIdentifier" => event_record.activity_id.to_string(),
+        "bootUUID" => event_record.boot_uuid.to_owned(),
+        "eventMessage" => event_record.message.to_owned(),
+        "formatString" => event_record.raw_message.to_owned(),
+        "timezoneName" => event_record.timezone_name.to_owned(),
+        "timestamp" => Utc
+            .timestamp_nanos(event_record.time as i64)
+            .to_rfc3339_opts(SecondsFormat::Micros, true),
+        _ => return None,
+    };
+    Some(value)
+}
+
+// パスの末尾(プロセス名やライブラリ名)を取得する。`log show`のprocess/senderと同じ値になる。
+fn file_name(path: &str) -> String {
+    path.rsplit('/').next().unwrap_or_default().to_string()
+}
+
+// 実行ファイルに埋め込んだfield_mapping.yml。実行ファイルと同じディレクトリにconfig/field_mapping.ymlが無い場合に使う
+const EMBEDDED_FIELD_MAPPING: &str = include_str!("../../config/field_mapping.yml");
+
+fn load_field_mapping() -> FieldMapping {
+    let mapping = match app_file_path("config/field_mapping.yml") {
+        Some(mapping_file) => read_field_mapping(&mapping_file),
+        None => parse_field_mapping(EMBEDDED_FIELD_MAPPING),
+    };
+    match mapping {
+        Ok(mapping) => mapping,
+        Err(err) => {
+            eprintln!("Failed to load field mapping file. {err}");
+            FieldMapping::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rule::fieldmapping::{get_event_value, FieldMapping, FieldSource, FIELD_MAPPING};
+    use crate::test_helpers::create_logdata;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn test_from_yaml() {
+        let yaml = YamlLoader::load_from_str(
+            r#"
+        Image: processImagePath
+        ParentImage:
+            field: eventMessage
+            regex: 'parent: (\S+)'
+        "#,
+        )
+        .unwrap();
+        let mapping = FieldMapping::from_yaml(&yaml[0]).unwrap();
+        let record = create_logdata("/usr/bin/xattr", "parent: /bin/zsh");
+        let image = mapping.resolve("Image").unwrap();
+        assert_eq!(image.get_value(&record).unwrap(), "/usr/bin/xattr");
+        let parent = mapping.resolve("ParentImage").unwrap();
+        // 作成したイベントでは別のフィールドから取得するため、元のFieldSourceを包んでいる
+        assert!(
+            matches!(&parent, FieldSource::Event(_, Some(source)) if matches!(**source, FieldSource::Extract(_, _)))
+        );
+        assert_eq!(parent.get_value(&record).unwrap(), "/bin/zsh");
+        let record = create_logdata("/usr/bin/xattr", "no parent here");
+        assert!(parent.get_value(&record).is_none());
+        assert!(mapping.resolve("eventMessage").is_some());
+        assert!(mapping.resolve("NoSuchField").is_none());
+    }
+
+    #[test]
+    fn test_from_yaml_invalid() {
+        let yaml = YamlLoader::load_from_str(
+            r#"
+        Image: no_such_field
+        ParentImage:
+            field: eventMessage
+            regex: '('
+        "#,
+        )
+        .unwrap();
+        let errors = FieldMapping::from_yaml(&yaml[0]).unwrap_err();
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn test_get_event_value() {
+        let record = create_logdata("/usr/bin/xattr", "test message");
+        let value = |key| get_event_value(&record, key).unwrap();
+        assert_eq!(value("process"), "xattr");
+        assert_eq!(value("processImagePath"), "/usr/bin/xattr");
+        assert_eq!(value("sender"), "libxpc.dylib");
+        assert_eq!(value("senderImagePath"), "/usr/lib/system/libxpc.dylib");
+        assert_eq!(value("eventMessage"), "test message");
+        assert_eq!(value("messageType"), "Default");
+        assert_eq!(value("pid"), "100");
+        assert_eq!(value("processID"), "100");
+        assert_eq!(value("timestamp"), "2023-03-28T10:40:00.000000Z");
+        assert_eq!(value("Image"), "/usr/bin/xattr");
+        assert!(get_event_value(&record, "message").is_none());
+        // SigmaのUserはユーザー名なので、数値のeuidとは対応させない
+        assert!(get_event_value(&record, "User").is_none());
+    }
+
+    #[test]
+    fn test_default_field_mapping() {
+        for sigma_field in ["Image", "CommandLine", "ParentImage", "TargetFilename"] {
+            assert!(FIELD_MAPPING.resolve(sigma_field).is_some());
+        }
+    }
+}
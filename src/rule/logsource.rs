@@ -0,0 +1,249 @@
+use crate::events::is_synthesized_event;
+use crate::rule::fieldmapping::{get_logdata_value, LOGDATA_FIELDS};
+use crate::yml::{app_file_path, parse_logsource_mapping, read_logsource_mapping};
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use macos_unifiedlogs::unified_log::LogData;
+use std::sync::Arc;
+use yaml_rust::Yaml;
+
+lazy_static! {
+    pub static ref LOGSOURCE_MAPPING: LogsourceMapping = load_logsource_mapping();
+}
+
+/// ルールのlogsourceに対応する、ルールと比較するログの条件
+#[derive(Debug)]
+pub struct LogsourceCondition {
+    /// いずれかに一致すればよい条件の一覧。条件はUnified Logのフィールド名と、そのフィールドが取り得る値の一覧
+    alternatives: Vec<Vec<(String, Vec<String>)>>,
+}
+
+impl LogsourceCondition {
+    /// ログがこのlogsourceのログかどうか判定します。いずれかの条件で、全てのフィールドがいずれかの値に一致すればtrueを返す。
+    /// メッセージから作成したイベントは、eventTypeを持つ条件にだけ一致する
+    pub fn is_match(&self, event_record: &LogData) -> bool {
+        let is_synthesized = is_synthesized_event(&event_record.event_type);
+        self.alternatives.iter().any(|fields| {
+            if is_synthesized && !fields.iter().any(|(field, _)| field == "eventType") {
+                return false;
+            }
+            fields.iter().all(|(field, values)| {
+                let event_value = get_logdata_value(event_record, field).unwrap_or_default();
+                values.iter().any(|value| match value.strip_suffix('*') {
+                    // 末尾が*の値は前方一致
+                    Some(prefix) => event_value.starts_with(prefix),
+                    None => &event_value == value,
+                })
+            })
+        })
+    }
+}
+
+/// Sigmaのlogsourceのcategory/serviceから、ルールと比較するログの条件への対応表
+#[derive(Debug, Default)]
+pub struct LogsourceMapping {
+    mappings: HashMap<String, Arc<LogsourceCondition>>,
+}
+
+impl LogsourceMapping {
+    /// logsource_mapping.ymlの内容からLogsourceMappingを作成します。
+    pub fn from_yaml(yaml: &Yaml) -> Result<LogsourceMapping, Vec<String>> {
+        let mut mappings = HashMap::new();
+        if yaml.is_null() {
+            return Ok(LogsourceMapping { mappings });
+        }
+        let yaml_hash = match yaml.as_hash() {
+            Some(hash) => hash,
+            None => return Err(vec!["Logsource mapping must be a hash.".to_string()]),
+        };
+
+        let mut err_msgs = vec![];
+        for (key, value) in yaml_hash.iter() {
+            let logsource = key.as_str().unwrap_or_default();
+            // 条件は連想配列1つか、いずれかに一致すればよい連想配列の配列
+            let fields_hashes: Option<Vec<_>> = match value {
+                Yaml::Array(values) => values.iter().map(|value| value.as_hash()).collect(),
+                _ => value.as_hash().map(|hash| vec![hash]),
+            };
+            let fields_hashes = match fields_hashes {
+                Some(hashes) if !logsource.is_empty() && !hashes.is_empty() => hashes,
+                _ => {
+                    err_msgs.push(format!(
+                        "Invalid logsource mapping. [logsource:{logsource}]"
+                    ));
+                    continue;
+                }
+            };
+            let mut alternatives = vec![];
+            for fields_hash in fields_hashes {
+                let mut fields = vec![];
+                for (field, values) in fields_hash.iter() {
+                    let field = field.as_str().unwrap_or_default();
+                    // 値は文字列1つか、文字列の配列
+                    let values: Option<Vec<String>> = match values {
+                        Yaml::Array(values) => values
+                            .iter()
+                            .map(|value| value.as_str().map(|value| value.to_string()))
+                            .collect(),
+                        _ => values.as_str().map(|value| vec![value.to_string()]),
+                    };
+                    match values {
+                        Some(values) if LOGDATA_FIELDS.contains(&field) => {
+                            fields.push((field.to_string(), values))
+                        }
+                        _ => err_msgs.push(format!(
+                            "Invalid logsource mapping. [logsource:{logsource}, field:{field}]"
+                        )),
+                    }
+                }
+                alternatives.push(fields);
+            }
+            mappings.insert(
+                logsource.to_string(),
+                Arc::new(LogsourceCondition { alternatives }),
+            );
+        }
+
+        if err_msgs.is_empty() {
+            Ok(LogsourceMapping { mappings })
+        } else {
+            Err(err_msgs)
+        }
+    }
+
+    /// ルールのlogsourceに対応する条件を取得します。categoryを優先し、無ければserviceで探す。
+    /// 対応表に無い場合はNoneを返す。そのルールはtracev3から読んだログとだけ比較する(RuleNode::is_target_logsource)
+    pub fn resolve(&self, logsource: &Yaml) -> Option<Arc<LogsourceCondition>> {
+        ["category", "service"]
+            .iter()
+            .filter_map(|key| logsource[*key].as_str())
+            .find_map(|name| self.mappings.get(name).cloned())
+    }
+}
+
+// 実行ファイルに埋め込んだlogsource_mapping.yml。実行ファイルと同じディレクトリにconfig/logsource_mapping.ymlが無い場合に使う
+const EMBEDDED_LOGSOURCE_MAPPING: &str = include_str!("../../config/logsource_mapping.yml");
+
+fn load_logsource_mapping() -> LogsourceMapping {
+    let mapping = match app_file_path("config/logsource_mapping.yml") {
+        Some(mapping_file) => read_logsource_mapping(&mapping_file),
+        None => parse_logsource_mapping(EMBEDDED_LOGSOURCE_MAPPING),
+    };
+    match mapping {
+        Ok(mapping) => mapping,
+        Err(err) => {
+            eprintln!("Failed to load logsource mapping file. {err}");
+            LogsourceMapping::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rule::logsource::{LogsourceMapping, LOGSOURCE_MAPPING};
+    use crate::test_helpers::create_logdata;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn test_from_yaml() {
+        let yaml = YamlLoader::load_from_str(
+            r#"
+        network_connection:
+            subsystem: com.apple.network*
+        file_event:
+            - eventType: file_event
+            - eventType: logEvent
+              process: lsd
+        authentication:
+            process:
+                - sudo
+                - loginwindow
+            category: default
+        "#,
+        )
+        .unwrap();
+        let mapping = LogsourceMapping::from_yaml(&yaml[0]).unwrap();
+        let logsource = |category: &str| {
+            YamlLoader::load_from_str(&format!("category: {category}")).unwrap()[0].clone()
+        };
+
+        let authentication = mapping.resolve(&logsource("authentication")).unwrap();
+        let mut record = create_logdata("/usr/bin/sudo", "");
+        assert!(authentication.is_match(&record));
+        record.category = "auth".to_string();
+        assert!(!authentication.is_match(&record));
+        assert!(!authentication.is_match(&create_logdata("/bin/ls", "")));
+
+        // 末尾が*の値は前方一致
+        let network = mapping.resolve(&logsource("network_connection")).unwrap();
+        let mut record = create_logdata("/usr/bin/curl", "");
+        assert!(!network.is_match(&record));
+        record.subsystem = "com.apple.network.connection".to_string();
+        assert!(network.is_match(&record));
+        // メッセージから作成したイベントは、eventTypeを条件に持たないlogsourceには一致しない
+        record.event_type = "file_event".to_string();
+        assert!(!network.is_match(&record));
+
+        // 条件の配列は、いずれかに一致すればよい
+        let file_event = mapping.resolve(&logsource("file_event")).unwrap();
+        assert!(file_event.is_match(&record));
+        record.event_type = "logEvent".to_string();
+        assert!(!file_event.is_match(&record));
+        record.process = "/usr/libexec/lsd".to_string();
+        assert!(file_event.is_match(&record));
+
+        // 対応表に無いlogsource
+        assert!(mapping.resolve(&logsource("dns_query")).is_none());
+        let service = YamlLoader::load_from_str("product: macos\nservice: authentication").unwrap()
+            [0]
+        .clone();
+        assert!(mapping.resolve(&service).is_some());
+    }
+
+    #[test]
+    fn test_from_yaml_invalid() {
+        let yaml = YamlLoader::load_from_str(
+            r#"
+        process_creation: eventType
+        file_event:
+            NoSuchField: file_event
+        authentication:
+            process:
+                - sudo
+                - process: su
+        "#,
+        )
+        .unwrap();
+        let errors = LogsourceMapping::from_yaml(&yaml[0]).unwrap_err();
+        assert_eq!(errors.len(), 3);
+
+        let yaml = YamlLoader::load_from_str(
+            r#"
+        process_creation:
+            - eventType: process_creation
+            - process
+        file_event: []
+        "#,
+        )
+        .unwrap();
+        let errors = LogsourceMapping::from_yaml(&yaml[0]).unwrap_err();
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn test_default_logsource_mapping() {
+        let logsource = YamlLoader::load_from_str("product: macos\ncategory: process_creation")
+            .unwrap()[0]
+            .clone();
+        let condition = LOGSOURCE_MAPPING.resolve(&logsource).unwrap();
+        let mut record = create_logdata("/usr/bin/osascript", "");
+        // メッセージから作成したイベントの無いプロセスも検知できるように、tracev3から読んだログとも比較する
+        assert!(condition.is_match(&record));
+        record.event_type = "process_creation".to_string();
+        assert!(condition.is_match(&record));
+        record.event_type = "file_event".to_string();
+        assert!(!condition.is_match(&record));
+        record.event_type = "signpostEvent".to_string();
+        assert!(!condition.is_match(&record));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::rule::fieldmapping::{get_event_value, FIELD_MAPPING};
 use base64::{engine::general_purpose, Engine as _};
 use cidr_utils::cidr::{IpCidr, IpCidrError};
 use nested::Nested;
@@ -7,8 +8,9 @@ use std::str::FromStr;
 use std::{cmp::Ordering, collections::HashMap};
 use yaml_rust::Yaml;
 
+use crate::events::EventRecord;
 use downcast_rs::Downcast;
-use macos_unifiedlogs::unified_log::LogData;
+use itertools::Itertools;
 use memchr::memmem;
 
 // 末端ノードがEventLogの値を比較するロジックを表す。
@@ -16,14 +18,14 @@ use memchr::memmem;
 //
 // 新規にLeafMatcherを実装するクラスを作成した場合、
 // LeafSelectionNodeのget_matchersクラスの戻り値の配列に新規作成したクラスのインスタンスを追加する。
-pub trait LeafMatcher: Downcast {
+pub trait LeafMatcher: Downcast + Send + Sync {
     /// 指定されたkey_listにマッチするLeafMatcherであるかどうか判定する。
     fn is_target_key(&self, key_list: &Nested<String>) -> bool;
 
     /// 引数に指定されたJSON形式のデータがマッチするかどうか判定する。
     /// main.rsでWindows Event LogをJSON形式に変換していて、そのJSON形式のWindowsのイベントログデータがここには来る
     /// 例えば正規表現でマッチするロジックなら、ここに正規表現でマッチさせる処理を書く。
-    fn is_match(&self, event_value: Option<&String>, recinfo: &LogData) -> bool;
+    fn is_match(&self, event_value: Option<&String>, recinfo: &EventRecord) -> bool;
 
     /// 初期化ロジックをここに記載します。
     /// ルールファイルの書き方が間違っている等の原因により、正しくルールファイルからパースできない場合、戻り値のResult型でエラーを返してください。
@@ -48,6 +50,8 @@ pub struct DefaultMatcher {
     fast_match: Option<Vec<FastMatch>>,
     pipes: Vec<PipeElement>,
     key_list: Nested<String>,
+    // cased等が指定されていて、大文字小文字を区別して比較するかどうか
+    case_sensitive: bool,
 }
 
 impl DefaultMatcher {
@@ -57,6 +61,7 @@ impl DefaultMatcher {
             fast_match: None,
             pipes: Vec::new(),
             key_list: Nested::<String>::new(),
+            case_sensitive: false,
         }
     }
 
@@ -71,11 +76,15 @@ impl DefaultMatcher {
 
     /// Hayabusaのルールファイルのフィールド名とそれに続いて指定されるパイプを、正規表現形式の文字列に変換します。
     /// ワイルドカードの文字列を正規表現にする処理もこのメソッドに実装されています。patternにワイルドカードの文字列を指定して、pipesにPipeElement::Wildcardを指定すればOK!!
-    fn from_pattern_to_regex_str(pattern: String, pipes: &[PipeElement]) -> String {
+    fn from_pattern_to_regex_str(
+        pattern: String,
+        pipes: &[PipeElement],
+        ignore_case: bool,
+    ) -> String {
         // パターンをPipeで処理する。
         pipes
             .iter()
-            .fold(pattern, |acc, pipe| pipe.pipe_pattern(acc))
+            .fold(pattern, |acc, pipe| pipe.pipe_pattern(acc, ignore_case))
     }
 
     fn eq_ignore_case(event_value_str: &str, match_str: &str) -> bool {
@@ -151,6 +160,79 @@ impl DefaultMatcher {
         // *を含まない場合は、文字列長マッチに変換
         Some(vec![FastMatch::Exact(s.replace(r"\\", r"\"))])
     }
+
+    // FastMatchで比較する。大文字小文字を区別しない比較でマルチバイト文字を含む場合等、判定できない場合はNoneを返す
+    fn fast_match_one(&self, fast_match: &FastMatch, event_value_str: &str) -> Option<bool> {
+        if self.case_sensitive {
+            return Some(match fast_match {
+                FastMatch::Exact(s) => event_value_str == s,
+                FastMatch::StartsWith(s) => event_value_str.starts_with(s.as_str()),
+                FastMatch::EndsWith(s) => event_value_str.ends_with(s.as_str()),
+                FastMatch::Contains(s) | FastMatch::AllOnly(s) => contains_str(event_value_str, s),
+            });
+        }
+        match fast_match {
+            FastMatch::Exact(s) => Some(Self::eq_ignore_case(event_value_str, s)),
+            FastMatch::StartsWith(s) => Self::starts_with_ignore_case(event_value_str, s),
+            FastMatch::EndsWith(s) => Self::ends_with_ignore_case(event_value_str, s),
+            FastMatch::Contains(s) | FastMatch::AllOnly(s) => {
+                Some(contains_str(&event_value_str.to_lowercase(), s))
+            }
+        }
+    }
+
+    // cidr/exists/fieldref/数値比較のパイプで比較する。それ以外のパイプの場合はNoneを返す
+    fn compare_by_pipe(
+        &self,
+        pipe: &PipeElement,
+        event_value: Option<&String>,
+        recinfo: &EventRecord,
+    ) -> Option<bool> {
+        let number = || event_value.and_then(|value| value.trim().parse::<f64>().ok());
+        match pipe {
+            PipeElement::Cidr(ip_result) => match ip_result {
+                Ok(mut matcher_ip) => {
+                    let val = String::default();
+                    let event_value_str = event_value.unwrap_or(&val);
+                    let event_ip = IpAddr::from_str(event_value_str);
+                    match event_ip {
+                        Ok(target_ip) => Some(matcher_ip.contains(target_ip)),
+                        Err(_) => Some(false), //IPアドレス以外の形式のとき
+                    }
+                }
+                Err(_) => Some(false), //IPアドレス以外の形式のとき
+            },
+            // 値が空のフィールドは存在しないものとして扱う
+            PipeElement::Exists(expected) => {
+                Some(matches!(event_value, Some(value) if !value.is_empty()) == *expected)
+            }
+            PipeElement::Fieldref(field) => match (event_value, get_event_value(recinfo, field)) {
+                (Some(value), Some(other)) => Some(self.compare_fieldref(value, &other)),
+                _ => Some(false),
+            },
+            // 数値に変換できない値はマッチしない
+            PipeElement::Lt(num) => Some(matches!(number(), Some(value) if value < *num)),
+            PipeElement::Lte(num) => Some(matches!(number(), Some(value) if value <= *num)),
+            PipeElement::Gt(num) => Some(matches!(number(), Some(value) if value > *num)),
+            PipeElement::Gte(num) => Some(matches!(number(), Some(value) if value >= *num)),
+            _ => None,
+        }
+    }
+
+    // fieldrefで指定されたフィールドの値と比較する。startswith/endswith/containsが指定されていればそれに従う
+    fn compare_fieldref(&self, value: &str, other: &str) -> bool {
+        let (value, other) = if self.case_sensitive {
+            (value.to_string(), other.to_string())
+        } else {
+            (value.to_lowercase(), other.to_lowercase())
+        };
+        match self.pipes.iter().find(|pipe| pipe.is_string_match()) {
+            Some(PipeElement::Startswith) => value.starts_with(&other),
+            Some(PipeElement::Endswith) => value.ends_with(&other),
+            Some(PipeElement::Contains) => contains_str(&value, &other),
+            _ => value == other,
+        }
+    }
 }
 
 impl LeafMatcher for DefaultMatcher {
@@ -179,7 +261,10 @@ impl LeafMatcher for DefaultMatcher {
             _ => None,
         };
         if yaml_value.is_none() {
-            let errmsg = format!("An unknown error occured. [key:{}]", "");
+            let errmsg = format!(
+                "An unknown error occured. [key:{}]",
+                concat_selection_key(key_list)
+            );
             return Err(vec![errmsg]);
         }
         let pattern = yaml_value.unwrap();
@@ -215,103 +300,60 @@ impl LeafMatcher for DefaultMatcher {
         if !err_msges.is_empty() {
             return Err(err_msges);
         }
-        let n = self.pipes.len();
-        if n == 0 {
-            // パイプがないケース
-            self.fast_match = Self::convert_to_fast_match(&pattern, true);
-        } else if n == 1 {
-            // パイプがあるケース
-            self.fast_match = match &self.pipes[0] {
-                PipeElement::Startswith => {
-                    Self::convert_to_fast_match(format!("{pattern}*").as_str(), true)
-                }
-                PipeElement::Endswith => {
-                    Self::convert_to_fast_match(format!("*{pattern}").as_str(), true)
-                }
-                PipeElement::Contains => {
-                    Self::convert_to_fast_match(format!("*{pattern}*").as_str(), true)
-                }
-                PipeElement::AllOnly => {
-                    Self::convert_to_fast_match(format!("allOnly*{pattern}*").as_str(), true)
-                }
-                _ => None,
-            };
-        } else if n == 2 {
-            if self.pipes[0] == PipeElement::Base64offset && self.pipes[1] == PipeElement::Contains
-            {
-                // |base64offset|containsの場合
-                let val = pattern.as_str();
-                let val_byte = val.as_bytes();
-                let mut fastmatches = vec![];
-                for i in 0..3 {
-                    let mut b64_result = vec![];
-                    let mut target_byte = vec![];
-                    target_byte.resize_with(i, || 0b0);
-                    target_byte.extend_from_slice(val_byte);
-                    b64_result.resize_with(target_byte.len() * 4 / 3 + 4, || 0b0);
-                    general_purpose::STANDARD
-                        .encode_slice(target_byte, &mut b64_result)
-                        .ok();
-                    let convstr_b64 = String::from_utf8(b64_result);
-                    if let Ok(b64_str) = convstr_b64 {
-                        // ここでContainsのfastmatch対応を行う
-                        let filtered_null_chr = b64_str.replace('\0', "");
-                        let b64_offset_contents = match b64_str.find('=').unwrap_or_default() % 4 {
-                            2 => {
-                                if i == 0 {
-                                    filtered_null_chr[..filtered_null_chr.len() - 3].to_string()
-                                } else {
-                                    filtered_null_chr[(i + 1)..filtered_null_chr.len() - 3]
-                                        .to_string()
-                                }
-                            }
-                            3 => {
-                                if i == 0 {
-                                    filtered_null_chr[..filtered_null_chr.len() - 2].to_string()
-                                } else {
-                                    filtered_null_chr.replace('\0', "")
-                                        [(i + 1)..filtered_null_chr.len() - 2]
-                                        .to_string()
-                                }
-                            }
-                            _ => {
-                                if i == 0 {
-                                    filtered_null_chr
-                                } else {
-                                    filtered_null_chr[(i + 1)..].to_string()
-                                }
-                            }
-                        };
-                        if let Some(fm) =
-                            Self::convert_to_fast_match(&format!("*{b64_offset_contents}*"), false)
-                        {
-                            fastmatches.extend(fm);
-                        }
-                    } else {
-                        err_msges.push(format!(
-                            "Failed base64 encoding: {}",
-                            convstr_b64.unwrap_err()
-                        ));
-                    }
-                }
-                if !fastmatches.is_empty() {
-                    self.fast_match = Some(fastmatches);
-                }
-            } else if self.pipes[0] == PipeElement::Contains && self.pipes[1] == PipeElement::All
-            // |contains|allの場合、事前の分岐でAndNodeとしているのでここではcontainsのみとして取り扱う
-            {
-                self.fast_match =
-                    Self::convert_to_fast_match(format!("*{pattern}*").as_str(), true);
-            }
-        } else {
-            let errmsg = format!("Multiple pipe elements cannot be used. key:{}", "");
-            return Err(vec![errmsg]);
+        if let Err(errmsg) = PipeElement::validate(&self.pipes) {
+            return Err(vec![format!(
+                "{} key:{}",
+                errmsg,
+                concat_selection_key(key_list)
+            )]);
         }
-        if self.fast_match.is_some()
-            && matches!(
-                &self.fast_match.as_ref().unwrap()[0],
-                FastMatch::Exact(_) | FastMatch::Contains(_)
+
+        // cased以外にも、base64やutf16でエンコードした値は大文字小文字を区別して比較する
+        self.case_sensitive = self.pipes.iter().any(|pipe| {
+            matches!(
+                pipe,
+                PipeElement::Cased
+                    | PipeElement::Base64
+                    | PipeElement::Base64offset
+                    | PipeElement::Utf16le
+                    | PipeElement::Utf16be
             )
+        });
+        if self.pipes.iter().any(PipeElement::is_comparison) {
+            // cidr/exists/fieldref/数値比較はパターンを正規表現に変換せず、is_matchで直接比較する
+            return Ok(());
+        }
+        // base64offsetでエンコードした値は前後の文字が不定になるため、startswith等の指定が無ければcontainsとして扱う
+        if self.pipes.contains(&PipeElement::Base64offset)
+            && !self.pipes.iter().any(PipeElement::is_string_match)
+        {
+            self.pipes.push(PipeElement::Contains);
+        }
+        // windash/utf16/base64等でパターンを変換する。変換後のパターンのいずれかに一致すればマッチとする
+        let patterns = PipeElement::encode_pattern(&pattern, &self.pipes);
+
+        let is_re = self.pipes.contains(&PipeElement::Re);
+        if !is_re {
+            let wildcard_pattern =
+                |patt: &String| match self.pipes.iter().find(|pipe| pipe.is_string_match()) {
+                    Some(PipeElement::Startswith) => format!("{patt}*"),
+                    Some(PipeElement::Endswith) => format!("*{patt}"),
+                    Some(PipeElement::Contains) => format!("*{patt}*"),
+                    Some(PipeElement::AllOnly) => format!("allOnly*{patt}*"),
+                    _ => patt.to_owned(),
+                };
+            // |contains|allの場合、事前の分岐でAndNodeとしているのでここではcontainsのみとして取り扱う
+            self.fast_match = patterns
+                .iter()
+                .map(|patt| {
+                    Self::convert_to_fast_match(&wildcard_pattern(patt), !self.case_sensitive)
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|fast_matches| fast_matches.into_iter().flatten().collect());
+        }
+        if matches!(&self.fast_match, Some(fast_matches) if fast_matches
+                .iter()
+                .all(|fm| matches!(fm, FastMatch::Exact(_) | FastMatch::Contains(_))))
             && !self.key_list.is_empty()
         {
             // FastMatch::Exact/Contains検索に置き換えられたときは正規表現は不要
@@ -319,19 +361,32 @@ impl LeafMatcher for DefaultMatcher {
         }
         // 正規表現ではない場合、ワイルドカードであることを表す。
         // ワイルドカードは正規表現でマッチングするので、ワイルドカードを正規表現に変換するPipeを内部的に追加することにする。
-        let is_re = self
-            .pipes
-            .iter()
-            .any(|pipe_element| matches!(pipe_element, PipeElement::Re));
         if !is_re {
             self.pipes.push(PipeElement::Wildcard);
         }
 
-        let pattern = DefaultMatcher::from_pattern_to_regex_str(pattern, &self.pipes);
+        let regex_patterns: Vec<String> = patterns
+            .into_iter()
+            .map(|patt| {
+                DefaultMatcher::from_pattern_to_regex_str(patt, &self.pipes, !self.case_sensitive)
+            })
+            .collect();
+        let pattern = if regex_patterns.len() == 1 {
+            regex_patterns[0].to_owned()
+        } else {
+            regex_patterns
+                .iter()
+                .map(|patt| format!("(?:{patt})"))
+                .join("|")
+        };
         // Pipeで処理されたパターンを正規表現に変換
         let re_result = Regex::new(&pattern);
         if re_result.is_err() {
-            let errmsg = format!("Cannot parse regex. [regex:{}, key:{}]", pattern, "");
+            let errmsg = format!(
+                "Cannot parse regex. [regex:{}, key:{}]",
+                pattern,
+                concat_selection_key(key_list)
+            );
             return Err(vec![errmsg]);
         }
         self.re = re_result.ok();
@@ -339,23 +394,11 @@ impl LeafMatcher for DefaultMatcher {
         Ok(())
     }
 
-    fn is_match(&self, event_value: Option<&String>, recinfo: &LogData) -> bool {
-        let pipe: &PipeElement = self.pipes.first().unwrap_or(&PipeElement::Wildcard);
-        let match_result = match pipe {
-            PipeElement::Cidr(ip_result) => match ip_result {
-                Ok(mut matcher_ip) => {
-                    let val = String::default();
-                    let event_value_str = event_value.unwrap_or(&val);
-                    let event_ip = IpAddr::from_str(event_value_str);
-                    match event_ip {
-                        Ok(target_ip) => Some(matcher_ip.contains(target_ip)),
-                        Err(_) => Some(false), //IPアドレス以外の形式のとき
-                    }
-                }
-                Err(_) => Some(false), //IPアドレス以外の形式のとき
-            },
-            _ => None,
-        };
+    fn is_match(&self, event_value: Option<&String>, recinfo: &EventRecord) -> bool {
+        let match_result = self
+            .pipes
+            .iter()
+            .find_map(|pipe| self.compare_by_pipe(pipe, event_value, recinfo));
         if let Some(result) = match_result {
             return result;
         }
@@ -368,13 +411,11 @@ impl LeafMatcher for DefaultMatcher {
 
         // yamlにnullが設定されていた場合
         if self.re.is_none() && self.fast_match.is_none() {
-            // レコード内に対象のフィールドが存在しなければ検知したものとして扱う
-            // for v in self.key_list.iter() {
-            //     if recinfo.get_value(v).is_none() {
-            //         return true;
-            //     }
-            // }
-            return false;
+            // レコード内に対象のフィールドが存在しない(値が空の)場合は検知したものとして扱う
+            return self.key_list.iter().any(|key| {
+                let field = key.split('|').next().unwrap_or_default();
+                get_event_value(recinfo, field).is_none_or(|v| v.is_empty())
+            });
         }
 
         if event_value.is_none() {
@@ -386,23 +427,15 @@ impl LeafMatcher for DefaultMatcher {
             // この場合ただのgrep検索なので、ただ正規表現に一致するかどうか調べればよいだけ
             return self.re.as_ref().unwrap().is_match(event_value_str);
         } else if let Some(fast_matcher) = &self.fast_match {
-            let fast_match_result = if fast_matcher.len() == 1 {
-                match &fast_matcher[0] {
-                    FastMatch::Exact(s) => Some(Self::eq_ignore_case(event_value_str, s)),
-                    FastMatch::StartsWith(s) => Self::starts_with_ignore_case(event_value_str, s),
-                    FastMatch::EndsWith(s) => Self::ends_with_ignore_case(event_value_str, s),
-                    FastMatch::Contains(s) | FastMatch::AllOnly(s) => {
-                        Some(contains_str(&event_value_str.to_lowercase(), s))
-                    }
-                }
-            } else {
-                Some(fast_matcher.iter().any(|fm| match fm {
-                    FastMatch::Contains(s) => contains_str(event_value_str, s),
-                    _ => false,
-                }))
-            };
-            if let Some(is_match) = fast_match_result {
-                return is_match;
+            // 変換後のパターンが複数ある場合は、いずれかに一致すればマッチとする
+            let fast_match_results: Vec<Option<bool>> = fast_matcher
+                .iter()
+                .map(|fm| self.fast_match_one(fm, event_value_str))
+                .collect();
+            if fast_match_results.contains(&Some(true)) {
+                return true;
+            } else if fast_match_results.iter().all(Option::is_some) {
+                return false;
             }
         }
         // 文字数/starts_with/ends_with検索に変換できなかった場合は、正規表現マッチで比較
@@ -418,36 +451,271 @@ enum PipeElement {
     Endswith,
     Contains,
     Re,
+    // re|i, re|m, re|s
+    ReIgnoreCase,
+    ReMultiLine,
+    ReSingleLine,
     Wildcard,
+    Base64,
     Base64offset,
+    // wideはutf16leと同じ
+    Utf16le,
+    Utf16be,
+    Windash,
+    Cased,
     Cidr(Result<IpCidr, IpCidrError>),
+    Exists(bool),
+    Fieldref(String),
+    Lt(f64),
+    Lte(f64),
+    Gt(f64),
+    Gte(f64),
     All,
     AllOnly,
 }
 
+/// windashで-や/と置き換える文字
+const WINDASH_CHARS: [char; 5] = ['-', '/', '\u{2013}', '\u{2014}', '\u{2015}'];
+
 impl PipeElement {
     fn new(key: &str, pattern: &str, key_list: &Nested<String>) -> Result<PipeElement, String> {
+        let parse_number = || {
+            pattern.trim().parse::<f64>().map_err(|_| {
+                format!(
+                    "Cannot parse number. [value:{}, key:{}]",
+                    pattern,
+                    concat_selection_key(key_list)
+                )
+            })
+        };
         let pipe_element = match key {
-            "startswith" => Some(PipeElement::Startswith),
-            "endswith" => Some(PipeElement::Endswith),
-            "contains" => Some(PipeElement::Contains),
-            "re" => Some(PipeElement::Re),
-            "base64offset" => Some(PipeElement::Base64offset),
-            "cidr" => Some(PipeElement::Cidr(IpCidr::from_str(pattern))),
-            "all" => Some(PipeElement::All),
-            "allOnly" => Some(PipeElement::AllOnly),
-            _ => None,
+            "startswith" => PipeElement::Startswith,
+            "endswith" => PipeElement::Endswith,
+            "contains" => PipeElement::Contains,
+            "re" => PipeElement::Re,
+            "i" => PipeElement::ReIgnoreCase,
+            "m" => PipeElement::ReMultiLine,
+            "s" => PipeElement::ReSingleLine,
+            "base64" => PipeElement::Base64,
+            "base64offset" => PipeElement::Base64offset,
+            "utf16le" | "wide" => PipeElement::Utf16le,
+            "utf16be" => PipeElement::Utf16be,
+            "windash" => PipeElement::Windash,
+            "cased" => PipeElement::Cased,
+            "cidr" => PipeElement::Cidr(IpCidr::from_str(pattern)),
+            "exists" => match pattern {
+                "true" => PipeElement::Exists(true),
+                "false" => PipeElement::Exists(false),
+                _ => {
+                    return Err(format!(
+                        "exists must be true or false. [value:{}, key:{}]",
+                        pattern,
+                        concat_selection_key(key_list)
+                    ))
+                }
+            },
+            "fieldref" => {
+                if FIELD_MAPPING.resolve(pattern).is_none() {
+                    return Err(format!(
+                        "Found unknown field. key:{} (fieldref of {})",
+                        pattern,
+                        concat_selection_key(key_list)
+                    ));
+                }
+                PipeElement::Fieldref(pattern.to_string())
+            }
+            "lt" => PipeElement::Lt(parse_number()?),
+            "lte" => PipeElement::Lte(parse_number()?),
+            "gt" => PipeElement::Gt(parse_number()?),
+            "gte" => PipeElement::Gte(parse_number()?),
+            "all" => PipeElement::All,
+            "allOnly" => PipeElement::AllOnly,
+            _ => {
+                return Err(format!(
+                    "An unknown pipe element was specified. pipe:{} key:{}",
+                    key,
+                    concat_selection_key(key_list)
+                ))
+            }
         };
+        Ok(pipe_element)
+    }
 
-        if let Some(elment) = pipe_element {
-            Ok(elment)
-        } else {
-            Err(format!("An unknown pipe element was specified. key:{}", ""))
+    /// 正規表現やワイルドカードに変換せず、is_matchで値を直接比較するパイプかどうか
+    fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            PipeElement::Cidr(_)
+                | PipeElement::Exists(_)
+                | PipeElement::Fieldref(_)
+                | PipeElement::Lt(_)
+                | PipeElement::Lte(_)
+                | PipeElement::Gt(_)
+                | PipeElement::Gte(_)
+        )
+    }
+
+    /// パターンにワイルドカードを足して比較するパイプかどうか
+    fn is_string_match(&self) -> bool {
+        matches!(
+            self,
+            PipeElement::Startswith
+                | PipeElement::Endswith
+                | PipeElement::Contains
+                | PipeElement::AllOnly
+        )
+    }
+
+    /// パターンを別の値に変換するパイプかどうか
+    fn is_encoding(&self) -> bool {
+        matches!(
+            self,
+            PipeElement::Base64
+                | PipeElement::Base64offset
+                | PipeElement::Utf16le
+                | PipeElement::Utf16be
+                | PipeElement::Windash
+        )
+    }
+
+    /// パイプの組み合わせが正しいかチェックします。
+    fn validate(pipes: &[PipeElement]) -> Result<(), String> {
+        let matchers: Vec<&PipeElement> = pipes
+            .iter()
+            .filter(|pipe| {
+                pipe.is_comparison() || pipe.is_string_match() || **pipe == PipeElement::Re
+            })
+            .collect();
+        // 比較方法は1つだけ指定できる。fieldrefだけはstartswith/endswith/containsと組み合わせられる
+        let is_fieldref_match = matchers.len() == 2
+            && matchers
+                .iter()
+                .any(|pipe| matches!(pipe, PipeElement::Fieldref(_)))
+            && matchers.iter().any(|pipe| pipe.is_string_match());
+        if matchers.len() > 1 && !is_fieldref_match {
+            return Err("Multiple pipe elements cannot be used.".to_string());
+        }
+
+        let is_re = pipes.contains(&PipeElement::Re);
+        if !is_re
+            && pipes.iter().any(|pipe| {
+                matches!(
+                    pipe,
+                    PipeElement::ReIgnoreCase
+                        | PipeElement::ReMultiLine
+                        | PipeElement::ReSingleLine
+                )
+            })
+        {
+            return Err("Pipe elements i, m and s can only be used with re.".to_string());
+        }
+        let is_comparison = pipes.iter().any(PipeElement::is_comparison);
+        if pipes.iter().any(PipeElement::is_encoding) && (is_re || is_comparison) {
+            return Err(
+                "Pipe elements that encode the value cannot be used with re, cidr, exists, fieldref or numeric comparisons."
+                    .to_string(),
+            );
+        }
+        let is_fieldref = pipes
+            .iter()
+            .any(|pipe| matches!(pipe, PipeElement::Fieldref(_)));
+        if pipes.contains(&PipeElement::Cased) && (is_re || (is_comparison && !is_fieldref)) {
+            return Err(
+                "Pipe element cased cannot be used with re, cidr, exists or numeric comparisons."
+                    .to_string(),
+            );
         }
+        Ok(())
+    }
+
+    /// windash/utf16/base64等のパイプを指定された順に適用して、パターンを変換します。
+    /// windashやbase64offsetは1つのパターンから複数のパターンを作るため、変換後のパターンの配列を返す。
+    fn encode_pattern(pattern: &str, pipes: &[PipeElement]) -> Vec<String> {
+        let mut values = vec![pattern.as_bytes().to_vec()];
+        for pipe in pipes {
+            values = match pipe {
+                PipeElement::Windash => values
+                    .iter()
+                    .flat_map(|value| {
+                        PipeElement::windash_variants(&String::from_utf8_lossy(value))
+                    })
+                    .map(String::into_bytes)
+                    .collect(),
+                PipeElement::Utf16le => values
+                    .iter()
+                    .map(|value| {
+                        String::from_utf8_lossy(value)
+                            .encode_utf16()
+                            .flat_map(u16::to_le_bytes)
+                            .collect()
+                    })
+                    .collect(),
+                PipeElement::Utf16be => values
+                    .iter()
+                    .map(|value| {
+                        String::from_utf8_lossy(value)
+                            .encode_utf16()
+                            .flat_map(u16::to_be_bytes)
+                            .collect()
+                    })
+                    .collect(),
+                PipeElement::Base64 => values
+                    .iter()
+                    .map(|value| general_purpose::STANDARD.encode(value).into_bytes())
+                    .collect(),
+                PipeElement::Base64offset => values
+                    .iter()
+                    .flat_map(|value| PipeElement::base64offset_variants(value))
+                    .map(String::into_bytes)
+                    .collect(),
+                _ => values,
+            };
+        }
+        values
+            .iter()
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+            .unique()
+            .collect()
+    }
+
+    /// 単語の先頭にある-や/を、WINDASH_CHARSのそれぞれの文字に置き換えたパターンを全て作成します。
+    fn windash_variants(pattern: &str) -> Vec<String> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let is_word = |c: &char| c.is_alphanumeric() || *c == '_';
+        let mut variants = vec![String::default()];
+        for (i, c) in chars.iter().enumerate() {
+            let is_dash = matches!(c, '-' | '/')
+                && (i == 0 || !is_word(&chars[i - 1]))
+                && matches!(chars.get(i + 1), Some(next) if is_word(next));
+            let replacements = if is_dash {
+                &WINDASH_CHARS[..]
+            } else {
+                std::slice::from_ref(c)
+            };
+            variants = variants
+                .iter()
+                .flat_map(|variant| replacements.iter().map(move |r| format!("{variant}{r}")))
+                .collect();
+        }
+        variants
+    }
+
+    /// 値の前に0~2バイト付け足してbase64エンコードし、前後の値によって変わる部分を除いた3パターンを作成します。
+    fn base64offset_variants(value: &[u8]) -> Vec<String> {
+        (0..3)
+            .map(|i| {
+                let mut target = vec![0; i];
+                target.extend_from_slice(value);
+                let encoded = general_purpose::STANDARD.encode(target);
+                let start = [0, 2, 3][i];
+                let end = encoded.len() - [0, 3, 2][(value.len() + i) % 3];
+                encoded.get(start..end).unwrap_or_default().to_string()
+            })
+            .collect()
     }
 
     /// patternをパイプ処理します
-    fn pipe_pattern(&self, pattern: String) -> String {
+    fn pipe_pattern(&self, pattern: String, ignore_case: bool) -> String {
         // enumでポリモーフィズムを実装すると、一つのメソッドに全部の型の実装をする感じになる。Java使い的にはキモイ感じがする。
         let fn_add_asterisk_end = |patt: String| {
             if patt.ends_with("//*") {
@@ -482,16 +750,23 @@ impl PipeElement {
             // endswithの場合はpatternの最初にwildcardを足すことで対応する
             PipeElement::Endswith => fn_add_asterisk_begin(pattern),
             // containsの場合はpatternの前後にwildcardを足すことで対応する
-            PipeElement::Contains => fn_add_asterisk_end(fn_add_asterisk_begin(pattern)),
+            // |allだけのキーワードも、レコード全体のテキストに含まれるかどうかで判定する
+            PipeElement::Contains | PipeElement::AllOnly => {
+                fn_add_asterisk_end(fn_add_asterisk_begin(pattern))
+            }
             // WildCardは正規表現に変換する。
-            PipeElement::Wildcard => PipeElement::pipe_pattern_wildcard(pattern),
+            PipeElement::Wildcard => PipeElement::pipe_pattern_wildcard(pattern, ignore_case),
+            // re|i, re|m, re|sは正規表現のフラグを付与する
+            PipeElement::ReIgnoreCase => format!("(?i){pattern}"),
+            PipeElement::ReMultiLine => format!("(?m){pattern}"),
+            PipeElement::ReSingleLine => format!("(?s){pattern}"),
             _ => pattern,
         }
     }
 
     /// PipeElement::Wildcardのパイプ処理です。
     /// pipe_pattern()に含めて良い処理ですが、複雑な処理になってしまったので別関数にしました。
-    fn pipe_pattern_wildcard(pattern: String) -> String {
+    fn pipe_pattern_wildcard(pattern: String, ignore_case: bool) -> String {
         let wildcards = vec!["*", "?"];
 
         // patternをwildcardでsplitした結果をpattern_splitsに入れる
@@ -567,10 +842,19 @@ impl PipeElement {
 
         // sigmaのwildcardはcase insensitive
         // なので、正規表現の先頭にcase insensitiveであることを表す記号を付与
-        "(?i)".to_string() + &ret
+        if ignore_case {
+            "(?i)".to_string() + &ret
+        } else {
+            ret
+        }
     }
 }
 
 fn contains_str(input: &str, check: &str) -> bool {
     memmem::find(input.as_bytes(), check.as_bytes()).is_some()
-}
\ No newline at end of file
+}
+
+/// エラーメッセージ用に、selectionのキーを連結した文字列を作成します。
+pub fn concat_selection_key(key_list: &Nested<String>) -> String {
+    key_list.iter().join(" | ")
+}
@@ -1,7 +1,10 @@
+use crate::events::{is_synthesized_event, EventRecord};
+use crate::rule::aggregation_parser::{self, AggregationParseInfo};
+use crate::rule::count::{AggregationResult, AggregationState};
+use crate::rule::logsource::{LogsourceCondition, LOGSOURCE_MAPPING};
 use crate::rule::selectionnodes::SelectionNode;
 use crate::rule::{condition_parser, selectionnodes};
 use hashbrown::HashMap;
-use macos_unifiedlogs::unified_log::LogData;
 use nested::Nested;
 use std::fmt::Debug;
 use std::sync::Arc;
@@ -10,12 +13,20 @@ use yaml_rust::Yaml;
 pub struct RuleNode {
     pub rulepath: String,
     pub yaml: Yaml,
+    /// 相関ルールから参照されているかどうか。参照されている場合は、一致したログを相関ルールで集計する
+    pub correlated: bool,
+    /// このルール単独の検知結果を出力するかどうか
+    pub generate: bool,
     detection: DetectionNode,
+    /// logsourceに対応するログの条件。Noneの場合は全てのログと比較する
+    logsource: Option<Arc<LogsourceCondition>>,
 }
 
 struct DetectionNode {
     pub name_to_selection: HashMap<String, Arc<Box<dyn SelectionNode>>>,
     pub condition: Option<Box<dyn SelectionNode>>,
+    pub aggregation: Option<AggregationParseInfo>,
+    pub timeframe: Option<i64>,
 }
 
 impl RuleNode {
@@ -23,7 +34,10 @@ impl RuleNode {
         RuleNode {
             rulepath: rule_path,
             yaml: yaml_data,
+            correlated: false,
+            generate: true,
             detection: DetectionNode::new(),
+            logsource: None,
         }
     }
 
@@ -35,6 +49,7 @@ impl RuleNode {
         if let Err(err_detail) = detection_result {
             errmsgs.extend(err_detail);
         }
+        self.logsource = LOGSOURCE_MAPPING.resolve(&self.yaml["logsource"]);
 
         if errmsgs.is_empty() {
             Ok(())
@@ -43,9 +58,45 @@ impl RuleNode {
         }
     }
 
-    pub fn select(&mut self, event_record: &LogData) -> bool {
+    pub fn select(&self, event_record: &EventRecord) -> bool {
         self.detection.select(event_record)
     }
+
+    /// ログがこのルールのlogsourceのログかどうか判定します。logsourceの条件に一致しないログはselectで比較しない
+    /// logsourceが対応表に無いルールは、tracev3から読んだログとだけ比較する(元のログとメッセージから作成したイベントで二重に検知しない)
+    pub fn is_target_logsource(&self, event_record: &EventRecord) -> bool {
+        match &self.logsource {
+            Some(logsource) => logsource.is_match(event_record),
+            None => !is_synthesized_event(&event_record.event_type),
+        }
+    }
+
+    /// conditionに集計条件があるかどうか。集計条件があるルールでは、selectに一致したログをaggregateで集計する
+    pub fn has_aggregation(&self) -> bool {
+        self.detection.aggregation.is_some()
+    }
+
+    pub fn get_aggregation(&self) -> Option<&AggregationParseInfo> {
+        self.detection.aggregation.as_ref()
+    }
+
+    /// selectに一致したログをstateのウィンドウで集計し、集計条件を満たした場合はその結果を返します。
+    pub fn aggregate(
+        &self,
+        state: &mut AggregationState,
+        event_record: &EventRecord,
+    ) -> Option<AggregationResult> {
+        let aggregation = self.detection.aggregation.as_ref()?;
+        state.add(aggregation, self.detection.timeframe, event_record)
+    }
+
+    /// 全てのログを集計した後に、stateに残っているウィンドウのうち集計条件を満たしたものを返します。
+    pub fn finish_aggregation(&self, state: AggregationState) -> Vec<AggregationResult> {
+        match &self.detection.aggregation {
+            Some(aggregation) => state.finish(aggregation),
+            None => vec![],
+        }
+    }
 }
 
 impl DetectionNode {
@@ -53,6 +104,8 @@ impl DetectionNode {
         DetectionNode {
             name_to_selection: HashMap::new(),
             condition: None,
+            aggregation: None,
+            timeframe: None,
         }
     }
 
@@ -86,6 +139,18 @@ impl DetectionNode {
             self.condition = Some(compile_result.unwrap());
         }
 
+        // パイプ以降の集計条件とtimeframeをパースする
+        match aggregation_parser::parse_aggregation(condition_str) {
+            Ok(aggregation) => self.aggregation = aggregation,
+            Err(err_msg) => err_msgs.push(err_msg),
+        }
+        if let Some(timeframe) = detection_yaml["timeframe"].as_str() {
+            match aggregation_parser::parse_timeframe(timeframe) {
+                Ok(timeframe) => self.timeframe = Some(timeframe),
+                Err(err_msg) => err_msgs.push(err_msg),
+            }
+        }
+
         if err_msgs.is_empty() {
             Ok(())
         } else {
@@ -93,7 +158,7 @@ impl DetectionNode {
         }
     }
 
-    pub fn select(&self, event_record: &LogData) -> bool {
+    pub fn select(&self, event_record: &EventRecord) -> bool {
         if self.condition.is_none() {
             return false;
         }
@@ -214,3 +279,347 @@ impl DetectionNode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::rule::rulenode::RuleNode;
+    use crate::test_helpers::{create_logdata, parse_rule_from_str};
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn test_select_leaf_node() {
+        let rule_str = r#"
+        detection:
+            selection:
+                processImagePath|endswith: '/xattr'
+                eventMessage|contains: 'com.apple.quarantine'
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        let hit = create_logdata("/usr/bin/xattr", "xattr -d com.apple.quarantine a.app");
+        assert!(rule_node.select(&hit));
+        let miss = create_logdata("/usr/bin/xattr", "xattr -l a.app");
+        assert!(!rule_node.select(&miss));
+        let miss = create_logdata("/bin/ls", "com.apple.quarantine");
+        assert!(!rule_node.select(&miss));
+    }
+
+    #[test]
+    fn test_select_numeric_field() {
+        let rule_str = r#"
+        detection:
+            selection:
+                pid: 100
+                euid: 0
+            condition: selection
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        assert!(rule_node.select(&create_logdata("/bin/ls", "")));
+    }
+
+    #[test]
+    fn test_select_null_field() {
+        let rule_str = r#"
+        detection:
+            selection:
+                process: xattr
+                subsystem: null
+            condition: selection
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        let mut record = create_logdata("/usr/bin/xattr", "");
+        assert!(!rule_node.select(&record));
+        record.subsystem = String::default();
+        assert!(rule_node.select(&record));
+    }
+
+    #[test]
+    fn test_select_sigma_field() {
+        let rule_str = r#"
+        detection:
+            selection:
+                Image|endswith: '/xattr'
+                CommandLine|contains|all:
+                    - '-r'
+                    - 'com.apple.quarantine'
+            condition: selection
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        let hit = create_logdata("/usr/bin/xattr", "xattr -r -d com.apple.quarantine a.app");
+        assert!(rule_node.select(&hit));
+        let miss = create_logdata("/usr/bin/xattr", "xattr -d com.apple.quarantine a.app");
+        assert!(!rule_node.select(&miss));
+    }
+
+    #[test]
+    fn test_select_keywords() {
+        let rule_str = r#"
+        detection:
+            keywords:
+                - 'evil.example.com'
+                - 'libEvil*.dylib'
+            condition: keywords
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        // フィールド名の無いキーワードは、メッセージ・プロセス・サブシステム・カテゴリ・ライブラリのいずれかに含まれればマッチする
+        let hit = create_logdata("/usr/bin/curl", "connect to EVIL.example.com:443");
+        assert!(rule_node.select(&hit));
+        let mut hit = create_logdata("/usr/bin/curl", "");
+        hit.library = "/tmp/libEvilHook.dylib".to_string();
+        assert!(rule_node.select(&hit));
+        let miss = create_logdata("/usr/bin/curl", "connect to example.com:443");
+        assert!(!rule_node.select(&miss));
+
+        let rule_node = parse_rule_from_str(
+            &rule_str.replace("keywords:\n", "keywords:\n                '|all':\n"),
+        );
+        let mut hit = create_logdata("/usr/bin/curl", "evil.example.com");
+        assert!(!rule_node.select(&hit));
+        hit.library = "/tmp/libEvilHook.dylib".to_string();
+        assert!(rule_node.select(&hit));
+    }
+
+    #[test]
+    fn test_select_encoded_value() {
+        let rule_str = r#"
+        detection:
+            selection:
+                eventMessage|base64|contains: 'osascript'
+            condition: selection
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        assert!(rule_node.select(&create_logdata("/bin/sh", "echo b3Nhc2NyaXB0 | base64 -d")));
+        // エンコードした値は大文字小文字を区別する
+        assert!(!rule_node.select(&create_logdata("/bin/sh", "echo B3NHC2NYAXB0 | base64 -d")));
+
+        // 値の途中からエンコードされていてもマッチする
+        let rule_node = parse_rule_from_str(&rule_str.replace("base64|", "base64offset|"));
+        assert!(rule_node.select(&create_logdata("/bin/sh", "ZWNobyBvc2FzY3JpcHQgLWU=")));
+
+        let rule_node = parse_rule_from_str(&rule_str.replace("base64|", "wide|base64offset|"));
+        assert!(rule_node.select(&create_logdata("/bin/sh", "eHhvAHMAYQBzAGMAcgBpAHAAdAA=")));
+        assert!(!rule_node.select(&create_logdata("/bin/sh", "ZWNobyBvc2FzY3JpcHQgLWU=")));
+    }
+
+    #[test]
+    fn test_select_windash() {
+        let rule_str = r#"
+        detection:
+            selection:
+                eventMessage|windash|contains: 'osascript -e'
+            condition: selection
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        assert!(rule_node.select(&create_logdata("/bin/sh", "osascript -e 'beep'")));
+        assert!(rule_node.select(&create_logdata("/bin/sh", "osascript /e 'beep'")));
+        assert!(rule_node.select(&create_logdata("/bin/sh", "osascript \u{2013}e 'beep'")));
+        assert!(!rule_node.select(&create_logdata("/bin/sh", "osascript e 'beep'")));
+    }
+
+    #[test]
+    fn test_select_cased_and_re_flags() {
+        let rule_str = r#"
+        detection:
+            selection:
+                eventMessage|contains|cased: 'Password'
+            condition: selection
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        assert!(rule_node.select(&create_logdata("/usr/bin/sudo", "Password:")));
+        assert!(!rule_node.select(&create_logdata("/usr/bin/sudo", "password:")));
+
+        let rule_str = r#"
+        detection:
+            selection:
+                eventMessage|re: '.*PASSWORD.*'
+            condition: selection
+        "#;
+        let record = create_logdata("/usr/bin/sudo", "incorrect password attempt");
+        assert!(!parse_rule_from_str(rule_str).select(&record));
+        let rule_node = parse_rule_from_str(&rule_str.replace("re:", "re|i:"));
+        assert!(rule_node.select(&record));
+
+        // re|sの場合、.が改行にもマッチする
+        let record = create_logdata("/usr/bin/sudo", "incorrect\npassword attempt");
+        let rule_node = parse_rule_from_str(&rule_str.replace("re:", "re|i:"));
+        assert!(!rule_node.select(&record));
+        let rule_node = parse_rule_from_str(&rule_str.replace("re:", "re|i|s:"));
+        assert!(rule_node.select(&record));
+    }
+
+    #[test]
+    fn test_select_exists_fieldref_numeric() {
+        let rule_str = r#"
+        detection:
+            selection:
+                subsystem|exists: false
+                eventMessage|fieldref|contains: process
+                pid|gt: 50
+                euid|lt: 501
+            condition: selection
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        let mut record = create_logdata("/usr/bin/xattr", "xattr -d a.app");
+        assert!(!rule_node.select(&record));
+        record.subsystem = String::default();
+        assert!(rule_node.select(&record));
+        record.message = "ls a.app".to_string();
+        assert!(!rule_node.select(&record));
+        record.message = "XATTR -d a.app".to_string();
+        record.euid = 501;
+        assert!(!rule_node.select(&record));
+        record.euid = 0;
+        record.pid = 50;
+        assert!(!rule_node.select(&record));
+    }
+
+    #[test]
+    fn test_init_unknown_field() {
+        let rule_str = r#"
+        detection:
+            selection:
+                NoSuchField: 'value'
+            condition: selection
+        "#;
+        let rule_yaml = YamlLoader::load_from_str(rule_str).unwrap();
+        let mut rule_node = RuleNode::new("testpath".to_string(), rule_yaml[0].clone());
+        let errors = rule_node.init().unwrap_err();
+        assert_eq!(errors, vec!["Found unknown field. key:NoSuchField"]);
+    }
+
+    #[test]
+    fn test_init_unknown_pipe() {
+        let rule_str = r#"
+        detection:
+            selection:
+                process|foo: 'xattr'
+            condition: selection
+        "#;
+        let rule_yaml = YamlLoader::load_from_str(rule_str).unwrap();
+        let mut rule_node = RuleNode::new("testpath".to_string(), rule_yaml[0].clone());
+        let errors = rule_node.init().unwrap_err();
+        assert_eq!(
+            errors,
+            vec!["An unknown pipe element was specified. pipe:foo key:process|foo"]
+        );
+
+        // 組み合わせられないパイプ
+        for selection in [
+            "process|startswith|endswith: 'xattr'",
+            "process|contains|i: 'xattr'",
+            "process|re|base64: 'xattr'",
+            "pid|gt: 'xattr'",
+            "process|exists: 'xattr'",
+            "process|fieldref: NoSuchField",
+        ] {
+            let rule_yaml =
+                YamlLoader::load_from_str(&rule_str.replace("process|foo: 'xattr'", selection))
+                    .unwrap();
+            let mut rule_node = RuleNode::new("testpath".to_string(), rule_yaml[0].clone());
+            assert!(rule_node.init().is_err(), "{}", selection);
+        }
+    }
+
+    #[test]
+    fn test_select_of_them() {
+        let rule_str = r#"
+        detection:
+            selection_xattr:
+                process: xattr
+            selection_ls:
+                process: ls
+            _filter:
+                eventMessage|contains: 'quarantine'
+            condition: 1 of them
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        assert!(rule_node.select(&create_logdata("/usr/bin/xattr", "")));
+        assert!(rule_node.select(&create_logdata("/bin/ls", "")));
+        // _で始まるselectionはthemに含まない
+        assert!(!rule_node.select(&create_logdata("/bin/cat", "quarantine")));
+
+        let rule_node = parse_rule_from_str(
+            &rule_str.replace("condition: 1 of them", "condition: all of them"),
+        );
+        assert!(!rule_node.select(&create_logdata("/usr/bin/xattr", "")));
+    }
+
+    #[test]
+    fn test_select_n_of_selection() {
+        let rule_str = r#"
+        detection:
+            selection_cli_1:
+                eventMessage|contains: '-r'
+            selection_cli_2:
+                eventMessage|contains: '-d'
+            selection_cli_3:
+                eventMessage|contains: 'com.apple.quarantine'
+            selection_img|endswith:
+                processImagePath|endswith: '/xattr'
+            condition: selection_img|endswith and 2 of selection_cli_*
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        let hit = create_logdata("/usr/bin/xattr", "xattr -d com.apple.quarantine a.app");
+        assert!(rule_node.select(&hit));
+        let miss = create_logdata("/usr/bin/xattr", "xattr -d a.app");
+        assert!(!rule_node.select(&miss));
+        let miss = create_logdata("/bin/ls", "-r -d com.apple.quarantine");
+        assert!(!rule_node.select(&miss));
+
+        // ワイルドカードの無いパターンは、そのselectionだけに一致する
+        let rule_node = parse_rule_from_str(&rule_str.replace(
+            "2 of selection_cli_*",
+            "1 of selection_cli_3 and not 1 of selection_cli_1",
+        ));
+        assert!(rule_node.select(&hit));
+        let miss = create_logdata("/usr/bin/xattr", "xattr -r com.apple.quarantine a.app");
+        assert!(!rule_node.select(&miss));
+    }
+
+    #[test]
+    fn test_select_multiple_of_conditions() {
+        let rule_str = r#"
+        detection:
+            selection_cli_1:
+                eventMessage|contains: '-r'
+            selection_cli_2:
+                eventMessage|contains: '-d'
+            selection_cli_3:
+                eventMessage|contains: 'com.apple.quarantine'
+            selection_img:
+                processImagePath|endswith: '/xattr'
+            condition: 2 of selection_cli_* and 1 of *
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        assert!(rule_node.select(&create_logdata(
+            "/usr/bin/xattr",
+            "xattr -d com.apple.quarantine a.app"
+        )));
+        assert!(!rule_node.select(&create_logdata("/usr/bin/xattr", "xattr -d a.app")));
+
+        // 前のofで追加したノードは後のofのワイルドカードに含まない(selectionは4つだけ)
+        let rule_yaml =
+            YamlLoader::load_from_str(&rule_str.replace("and 1 of *", "and 5 of *")).unwrap();
+        let mut rule_node = RuleNode::new("testpath".to_string(), rule_yaml[0].clone());
+        assert!(rule_node.init().is_err());
+    }
+
+    #[test]
+    fn test_init_invalid_of_condition() {
+        for condition in ["4 of selection_*", "0 of selection_*", "1 of filter*"] {
+            let rule_str = format!(
+                r#"
+        detection:
+            selection_1:
+                process: xattr
+            selection_2:
+                process: ls
+            condition: {}
+        "#,
+                condition
+            );
+            let rule_yaml = YamlLoader::load_from_str(&rule_str).unwrap();
+            let mut rule_node = RuleNode::new("testpath".to_string(), rule_yaml[0].clone());
+            assert!(rule_node.init().is_err(), "{}", condition);
+        }
+    }
+}
@@ -1,16 +1,16 @@
-use crate::rule::matchers::DefaultMatcher;
+use crate::events::EventRecord;
+use crate::rule::fieldmapping::{get_keyword_values, FieldSource, FIELD_MAPPING};
+use crate::rule::matchers;
 use downcast_rs::Downcast;
-use macos_unifiedlogs::unified_log::LogData;
 use nested::Nested;
 use std::{sync::Arc, vec};
 use yaml_rust::Yaml;
-use crate::rule::matchers;
 
 // Ruleファイルの detection- selection配下のノードはこのtraitを実装する。
-pub trait SelectionNode: Downcast {
+pub trait SelectionNode: Downcast + Send + Sync {
     // 引数で指定されるイベントログのレコードが、条件に一致するかどうかを判定する
     // このトレイトを実装する構造体毎に適切な判定処理を書く必要がある。
-    fn select(&self, event_record: &LogData) -> bool;
+    fn select(&self, event_record: &EventRecord) -> bool;
 
     // 初期化処理を行う
     // 戻り値としてエラーを返却できるようになっているので、Ruleファイルが間違っていて、SelectionNodeを構成出来ない時はここでエラーを出す
@@ -40,7 +40,7 @@ impl AndSelectionNode {
 }
 
 impl SelectionNode for AndSelectionNode {
-    fn select(&self, event_record: &LogData) -> bool {
+    fn select(&self, event_record: &EventRecord) -> bool {
         self.child_nodes
             .iter()
             .all(|child_node| child_node.select(event_record))
@@ -110,7 +110,7 @@ impl AllSelectionNode {
 }
 
 impl SelectionNode for AllSelectionNode {
-    fn select(&self, event_record: &LogData) -> bool {
+    fn select(&self, event_record: &EventRecord) -> bool {
         self.child_nodes
             .iter()
             .all(|child_node| child_node.select(event_record))
@@ -180,7 +180,7 @@ impl OrSelectionNode {
 }
 
 impl SelectionNode for OrSelectionNode {
-    fn select(&self, event_record: &LogData) -> bool {
+    fn select(&self, event_record: &EventRecord) -> bool {
         self.child_nodes
             .iter()
             .any(|child_node| child_node.select(event_record))
@@ -248,7 +248,7 @@ impl NotSelectionNode {
 }
 
 impl SelectionNode for NotSelectionNode {
-    fn select(&self, event_record: &LogData) -> bool {
+    fn select(&self, event_record: &EventRecord) -> bool {
         !self.node.select(event_record)
     }
 
@@ -282,7 +282,7 @@ impl RefSelectionNode {
 }
 
 impl SelectionNode for RefSelectionNode {
-    fn select(&self, event_record: &LogData) -> bool {
+    fn select(&self, event_record: &EventRecord) -> bool {
         self.selection_node.select(event_record)
     }
 
@@ -299,10 +299,55 @@ impl SelectionNode for RefSelectionNode {
     }
 }
 
+/// conditionの「N of selection*」を表すノード。N個以上のselectionに一致した場合に条件に一致したと判定する
+pub struct CountSelectionNode {
+    count: usize,
+    selection_nodes: Vec<Arc<Box<dyn SelectionNode>>>,
+}
+
+impl CountSelectionNode {
+    pub fn new(
+        count: usize,
+        selection_nodes: Vec<Arc<Box<dyn SelectionNode>>>,
+    ) -> CountSelectionNode {
+        CountSelectionNode {
+            count,
+            selection_nodes,
+        }
+    }
+}
+
+impl SelectionNode for CountSelectionNode {
+    fn select(&self, event_record: &EventRecord) -> bool {
+        self.selection_nodes
+            .iter()
+            .filter(|selection_node| selection_node.select(event_record))
+            .take(self.count)
+            .count()
+            == self.count
+    }
+
+    fn init(&mut self) -> Result<(), Vec<String>> {
+        Ok(())
+    }
+
+    fn get_childs(&self) -> Vec<&dyn SelectionNode> {
+        self.selection_nodes
+            .iter()
+            .map(|selection_node| selection_node.as_ref().as_ref())
+            .collect()
+    }
+
+    fn get_descendants(&self) -> Vec<&dyn SelectionNode> {
+        self.get_childs()
+    }
+}
+
 pub struct LeafSelectionNode {
     key: String,
     key_list: Nested<String>,
     select_value: Yaml,
+    field: Option<FieldSource>,
     pub matcher: Option<Box<dyn matchers::LeafMatcher>>,
 }
 
@@ -312,6 +357,7 @@ impl LeafSelectionNode {
             key: String::default(),
             key_list: keys,
             select_value: value_yaml,
+            field: None,
             matcher: None,
         }
     }
@@ -336,15 +382,70 @@ impl LeafSelectionNode {
         let topkey = &self.key_list[0];
         topkey.split('|').next().unwrap_or_default().to_string()
     }
+
+    /// matchers::LeafMatcherの一覧を取得する。
+    /// 上から順番に調べて、一番始めに一致したMatcherが適用される
+    fn get_matchers(&self) -> Vec<Box<dyn matchers::LeafMatcher>> {
+        vec![Box::new(matchers::DefaultMatcher::new())]
+    }
 }
 
 impl SelectionNode for LeafSelectionNode {
-    fn select(&self, event_record: &LogData) -> bool {
-        true
+    fn select(&self, event_record: &EventRecord) -> bool {
+        let matcher = match &self.matcher {
+            Some(matcher) => matcher,
+            None => return false,
+        };
+
+        // フィールド名の無いキーワードは、レコードの主なフィールドのいずれかに一致すればよい
+        if self.key.is_empty() {
+            return get_keyword_values(event_record)
+                .any(|value| matcher.is_match(Some(value), event_record));
+        }
+        let event_value = self
+            .field
+            .as_ref()
+            .and_then(|field| field.get_value(event_record));
+        matcher.is_match(event_value.as_ref(), event_record)
     }
 
     fn init(&mut self) -> Result<(), Vec<String>> {
-        Ok(())
+        let match_key_list = self.key_list.clone();
+        let matchers = self.get_matchers();
+        self.matcher = matchers
+            .into_iter()
+            .find(|matcher| matcher.is_target_key(&match_key_list));
+
+        // 一致するmatcherが見つからないエラー
+        if self.matcher.is_none() {
+            return Err(vec![format!(
+                "Found unknown key. key:{}",
+                matchers::concat_selection_key(&self.key_list)
+            )]);
+        }
+
+        if self.select_value.is_badvalue() {
+            return Err(vec![format!(
+                "Cannot parse yml file. key:{}",
+                matchers::concat_selection_key(&self.key_list)
+            )]);
+        }
+
+        self.key = self._create_key();
+        if !self.key.is_empty() {
+            // Sigmaのフィールド名をLogDataのフィールドに対応付ける
+            self.field = FIELD_MAPPING.resolve(&self.key);
+            if self.field.is_none() {
+                return Err(vec![format!(
+                    "Found unknown field. key:{}",
+                    matchers::concat_selection_key(&self.key_list)
+                )]);
+            }
+        }
+        self.matcher
+            .as_mut()
+            .unwrap()
+            .init(&match_key_list, &self.select_value)
     }
 
     fn get_childs(&self) -> Vec<&dyn SelectionNode> {
@@ -354,4 +455,4 @@ impl SelectionNode for LeafSelectionNode {
     fn get_descendants(&self) -> Vec<&dyn SelectionNode> {
         vec![]
     }
-}
\ No newline at end of file
+}
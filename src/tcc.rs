@@ -0,0 +1,298 @@
+use crate::events::{EventRecord, TCC};
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use macos_unifiedlogs::unified_log::LogData;
+use regex::Regex;
+
+// Subsystem of the messages logged by tccd.
+// tccd logs each privacy permission request (camera, microphone, Full Disk Access, Accessibility, ...)
+// as several messages linked by msgID, and the decision last:
+//   AUTHREQ_CTX: msgID=401.12, function=TCCAccessRequest, service=kTCCServiceAccessibility, preflight=yes, query=1, client_dict=(null), daemon_dict=0x600003b1c0c0
+//   AUTHREQ_ATTRIBUTION: msgID=401.12, attribution={responsible={TCCDProcess: identifier=com.googlecode.iterm2, pid=3001, auid=501, euid=501, binary_path=/Applications/iTerm.app/Contents/MacOS/iTerm2}, accessing={TCCDProcess: identifier=com.apple.osascript, pid=5612, auid=501, euid=501, binary_path=/usr/bin/osascript}, },
+//   AUTHREQ_SUBJECT: msgID=401.12, subject=com.googlecode.iterm2,
+//   AUTHREQ_RESULT: msgID=401.12, authValue=2, authReason=4, authVersion=1, desired_auth=0, error=(null),
+const TCC_SUBSYSTEM: &str = "com.apple.TCC";
+
+lazy_static! {
+    static ref AUTHREQ_RE: Regex =
+        Regex::new(r"^\s*AUTHREQ_(?P<kind>[A-Z]+): msgID=(?P<msg_id>[^,\s]+),\s*(?P<fields>.*)$")
+            .unwrap();
+    static ref SERVICE_RE: Regex = Regex::new(r"\bservice=(?P<service>[^,\s]+)").unwrap();
+    static ref SUBJECT_RE: Regex = Regex::new(r"\bsubject=(?P<subject>[^,]+)").unwrap();
+    static ref PROCESS_RE: Regex =
+        Regex::new(r"\b(?P<role>\w+)=\{TCCDProcess: (?P<fields>[^}]*)\}").unwrap();
+    static ref PROCESS_FIELD_RE: Regex =
+        Regex::new(r"(?:^|,\s*)(?P<key>\w+)=(?P<value>[^,]*)").unwrap();
+    static ref RESULT_RE: Regex =
+        Regex::new(r"\bauthValue=(?P<value>\d+),\s*authReason=(?P<reason>\d+)").unwrap();
+}
+
+// A process in the attribution chain of a request (ex: the responsible app and the process accessing the service)
+#[derive(Clone, Debug, Default)]
+struct TccProcess {
+    role: String,
+    // Bundle id, or the path of an executable without one
+    identifier: String,
+    binary_path: String,
+    pid: u64,
+    euid: u32,
+}
+
+// Messages of a request seen before its result
+#[derive(Debug, Default)]
+struct TccRequest {
+    service: String,
+    subject: Option<String>,
+    attribution: Vec<TccProcess>,
+}
+
+// Whether a log entry is logged by tccd. These entries are fed to the TccParser even outside of the time range,
+// since the messages of a request in the range may be logged before it
+pub fn is_tcc_message(data: &LogData) -> bool {
+    data.subsystem == TCC_SUBSYSTEM
+}
+
+// Joins the messages of each TCC request into one tcc event, created when the result is logged.
+// The messages of a request are logged by the same tccd process, so they are linked by its pid and the msgID.
+// One parser reads the log entries of a whole run in order, since a request may span two tracev3 files
+#[derive(Debug, Default)]
+pub struct TccParser {
+    requests: HashMap<(u64, String), TccRequest>,
+}
+
+impl TccParser {
+    // Read a log entry. Returns the tcc event when the entry is the result of a request
+    pub fn parse(&mut self, data: &LogData) -> Option<EventRecord> {
+        if !is_tcc_message(data) {
+            return None;
+        }
+        let captures = AUTHREQ_RE.captures(&data.message)?;
+        let key = (data.pid, captures["msg_id"].to_string());
+        let fields = &captures["fields"];
+        match &captures["kind"] {
+            "CTX" => {
+                let service = SERVICE_RE.captures(fields)?;
+                self.requests.entry(key).or_default().service = service["service"].to_string();
+                None
+            }
+            "ATTRIBUTION" => {
+                self.requests.entry(key).or_default().attribution = parse_attribution(fields);
+                None
+            }
+            "SUBJECT" => {
+                let subject = SUBJECT_RE.captures(fields)?;
+                self.requests.entry(key).or_default().subject =
+                    Some(subject["subject"].trim().to_string());
+                None
+            }
+            "RESULT" => {
+                let request = self.requests.remove(&key)?;
+                let result = RESULT_RE.captures(fields)?;
+                Some(create_tcc_event(
+                    data,
+                    request,
+                    &result["value"],
+                    &result["reason"],
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn parse_attribution(fields: &str) -> Vec<TccProcess> {
+    PROCESS_RE
+        .captures_iter(fields)
+        .map(|process| {
+            let mut tcc_process = TccProcess {
+                role: process["role"].to_string(),
+                ..TccProcess::default()
+            };
+            for field in PROCESS_FIELD_RE.captures_iter(&process["fields"]) {
+                let value = field["value"].trim();
+                match &field["key"] {
+                    "identifier" => tcc_process.identifier = value.to_string(),
+                    "binary_path" => tcc_process.binary_path = value.to_string(),
+                    "pid" => tcc_process.pid = value.parse().unwrap_or_default(),
+                    "euid" => tcc_process.euid = value.parse().unwrap_or_default(),
+                    _ => {}
+                }
+            }
+            tcc_process
+        })
+        .collect()
+}
+
+fn create_tcc_event(
+    data: &LogData,
+    request: TccRequest,
+    auth_value: &str,
+    auth_reason: &str,
+) -> EventRecord {
+    // TCC attributes a request to the responsible app (ex: the terminal an unbundled tool runs in)
+    let client = request
+        .attribution
+        .iter()
+        .find(|process| process.role == "responsible")
+        .or_else(|| {
+            request
+                .attribution
+                .iter()
+                .find(|process| process.role == "accessing")
+        })
+        .or_else(|| request.attribution.first())
+        .cloned()
+        .unwrap_or_default();
+    let attribution_chain = request
+        .attribution
+        .iter()
+        .map(|process| {
+            if process.binary_path.is_empty() || process.binary_path == process.identifier {
+                format!("{}: {}", process.role, process.identifier)
+            } else {
+                format!(
+                    "{}: {} ({})",
+                    process.role, process.identifier, process.binary_path
+                )
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" > ");
+    // The other fields are those of the result message, to show where the event came from
+    EventRecord {
+        data: LogData {
+            event_type: TCC.to_string(),
+            ..data.clone()
+        },
+        event_data: vec![
+            ("Service", request.service),
+            ("Client", request.subject.unwrap_or(client.identifier)),
+            ("AuthValue", auth_value_name(auth_value)),
+            ("AuthReason", auth_reason_name(auth_reason)),
+            ("AttributionChain", attribution_chain),
+            ("Image", client.binary_path),
+            ("User", client.euid.to_string()),
+            ("ProcessId", client.pid.to_string()),
+        ],
+    }
+}
+
+// Names of the authValue codes, as stored in the auth_value column of TCC.db. Unknown codes are kept as numbers
+fn auth_value_name(code: &str) -> String {
+    let name = match code {
+        "0" => "Denied",
+        "1" => "Unknown",
+        "2" => "Allowed",
+        "3" => "Limited",
+        _ => code,
+    };
+    name.to_string()
+}
+
+// Names of the authReason codes, as stored in the auth_reason column of TCC.db. Unknown codes are kept as numbers
+fn auth_reason_name(code: &str) -> String {
+    let name = match code {
+        "0" => "None",
+        "1" => "Error",
+        "2" => "User Consent",
+        "3" => "User Set",
+        "4" => "System Set",
+        "5" => "Service Policy",
+        "6" => "MDM Policy",
+        "7" => "Override Policy",
+        "8" => "Missing Usage String",
+        "9" => "Prompt Timeout",
+        "10" => "Preflight Unknown",
+        "11" => "Entitled",
+        "12" => "App Type Policy",
+        _ => code,
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::TCC;
+    use crate::rule::fieldmapping::get_event_value;
+    use crate::tcc::TccParser;
+    use crate::test_helpers::{create_logdata, create_tccd_logdata, parse_rule_from_str};
+
+    #[test]
+    fn test_parse_tcc_request() {
+        let mut parser = TccParser::default();
+        let messages = [
+            "AUTHREQ_CTX: msgID=401.12, function=TCCAccessRequest, service=kTCCServiceAccessibility, preflight=yes, query=1, client_dict=(null), daemon_dict=0x600003b1c0c0",
+            "AUTHREQ_ATTRIBUTION: msgID=401.12, attribution={responsible={TCCDProcess: identifier=com.googlecode.iterm2, pid=3001, auid=501, euid=501, binary_path=/Applications/iTerm.app/Contents/MacOS/iTerm2}, accessing={TCCDProcess: identifier=/tmp/agent, pid=5612, auid=501, euid=501, binary_path=/tmp/agent}, },",
+            // 別のリクエストのメッセージが間に入っても、msgIDで区別する
+            "AUTHREQ_CTX: msgID=401.13, function=TCCAccessRequest, service=kTCCServiceMicrophone, preflight=no, query=1, client_dict=(null), daemon_dict=0x600003b1c0c0",
+            "AUTHREQ_SUBJECT: msgID=401.12, subject=com.googlecode.iterm2,",
+        ];
+        for message in messages {
+            assert!(parser.parse(&create_tccd_logdata(message)).is_none());
+        }
+        let result = create_tccd_logdata(
+            "AUTHREQ_RESULT: msgID=401.12, authValue=2, authReason=4, authVersion=1, desired_auth=0, error=(null),",
+        );
+        let event = parser.parse(&result).unwrap();
+        assert_eq!(event.event_type, TCC);
+        let value = |key| get_event_value(&event, key).unwrap();
+        assert_eq!(value("Service"), "kTCCServiceAccessibility");
+        assert_eq!(value("Client"), "com.googlecode.iterm2");
+        assert_eq!(value("AuthValue"), "Allowed");
+        assert_eq!(value("AuthReason"), "System Set");
+        assert_eq!(
+            value("AttributionChain"),
+            "responsible: com.googlecode.iterm2 (/Applications/iTerm.app/Contents/MacOS/iTerm2) > accessing: /tmp/agent"
+        );
+        assert_eq!(
+            value("Image"),
+            "/Applications/iTerm.app/Contents/MacOS/iTerm2"
+        );
+        assert_eq!(value("ProcessId"), "3001");
+        assert_eq!(value("User"), "501");
+        // Unified Logのフィールドは結果のメッセージのまま
+        assert_eq!(event.message, result.message);
+        assert_eq!(event.process, result.process);
+        assert_eq!(event.subsystem, "com.apple.TCC");
+        // 結果を出力したリクエストは破棄する
+        assert!(parser.parse(&result).is_none());
+
+        // tccのルールで検知できる
+        let rule_node = parse_rule_from_str(
+            r#"
+        detection:
+            selection:
+                Service: kTCCServiceAccessibility
+                AuthValue: Allowed
+                AttributionChain|contains: '/tmp/'
+            condition: selection
+        "#,
+        );
+        assert!(rule_node.select(&event));
+    }
+
+    #[test]
+    fn test_parse_tcc_ignored() {
+        let mut parser = TccParser::default();
+        // 結果だけのリクエストや、TCC以外のサブシステムのメッセージからは作成しない
+        let result = "AUTHREQ_RESULT: msgID=401.14, authValue=0, authReason=2, authVersion=1, desired_auth=0, error=(null),";
+        assert!(parser.parse(&create_tccd_logdata(result)).is_none());
+        let ctx = "AUTHREQ_CTX: msgID=401.14, function=TCCAccessRequest, service=kTCCServiceCamera, preflight=no, query=1, client_dict=(null), daemon_dict=0x0";
+        assert!(parser.parse(&create_logdata("/usr/bin/log", ctx)).is_none());
+        assert!(parser
+            .parse(&create_logdata("/usr/bin/log", result))
+            .is_none());
+
+        // コードに名前が無い値は数値のまま
+        parser.parse(&create_tccd_logdata(ctx));
+        let event = parser
+            .parse(&create_tccd_logdata(
+                "AUTHREQ_RESULT: msgID=401.14, authValue=0, authReason=99, authVersion=1, desired_auth=0, error=(null),",
+            ))
+            .unwrap();
+        let value = |key| get_event_value(&event, key).unwrap();
+        assert_eq!(value("AuthValue"), "Denied");
+        assert_eq!(value("AuthReason"), "99");
+        assert_eq!(value("AttributionChain"), "");
+    }
+}
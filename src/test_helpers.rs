@@ -0,0 +1,48 @@
+// テストで共通に使うルールとログの作成処理
+use crate::events::EventRecord;
+use crate::rule::rulenode::RuleNode;
+use macos_unifiedlogs::unified_log::LogData;
+use yaml_rust::YamlLoader;
+
+pub const TCCD_PATH: &str = "/System/Library/PrivateFrameworks/TCC.framework/Support/tccd";
+
+pub fn parse_rule_from_str(rule_str: &str) -> RuleNode {
+    let rule_yaml = YamlLoader::load_from_str(rule_str);
+    assert!(rule_yaml.is_ok());
+    let rule_yamls = rule_yaml.unwrap();
+    let mut rule_yaml = rule_yamls.into_iter();
+    let mut rule_node = RuleNode::new("testpath".to_string(), rule_yaml.next().unwrap());
+    assert!(rule_node.init().is_ok());
+    rule_node
+}
+
+pub fn create_logdata(process: &str, message: &str) -> EventRecord {
+    EventRecord::from(LogData {
+        subsystem: "com.apple.xpc".to_string(),
+        thread_id: 1,
+        pid: 100,
+        euid: 0,
+        library: "/usr/lib/system/libxpc.dylib".to_string(),
+        library_uuid: String::default(),
+        activity_id: 0,
+        time: 1_680_000_000_000_000_000.0,
+        category: "default".to_string(),
+        event_type: "logEvent".to_string(),
+        log_type: "Default".to_string(),
+        process: process.to_string(),
+        process_uuid: String::default(),
+        message: message.to_string(),
+        raw_message: String::default(),
+        boot_uuid: String::default(),
+        timezone_name: "UTC".to_string(),
+    })
+}
+
+// tccdのログ。TCCのメッセージはcom.apple.TCCのaccessカテゴリに出力される
+pub fn create_tccd_logdata(message: &str) -> EventRecord {
+    let mut data = create_logdata(TCCD_PATH, message);
+    data.subsystem = "com.apple.TCC".to_string();
+    data.category = "access".to_string();
+    data.pid = 401;
+    data
+}
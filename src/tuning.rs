@@ -0,0 +1,222 @@
+use crate::events::EventRecord;
+use crate::rule::rulenode::RuleNode;
+use crate::yml::{app_file_path, get_string_list};
+use hashbrown::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use yaml_rust::yaml::Hash;
+use yaml_rust::{Yaml, YamlLoader};
+
+// What to do with a detection that matches a suppression
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SuppressionAction {
+    // Remove the detection from the results
+    #[default]
+    Drop,
+    // Keep the detection, marked as suppressed
+    Tag,
+}
+
+// A false positive to suppress. Applies to the listed rule ids, or to every rule if none are listed
+struct Suppression {
+    rule_ids: Vec<String>,
+    node: RuleNode,
+}
+
+// Rules to disable and false positives to suppress, loaded from the tuning file
+#[derive(Default)]
+pub struct Tuning {
+    disabled_rules: HashSet<String>,
+    suppressions: Vec<Suppression>,
+    pub action: SuppressionAction,
+}
+
+impl Tuning {
+    pub fn from_yaml(yaml: &Yaml) -> Result<Tuning, Vec<String>> {
+        let mut tuning = Tuning::default();
+        if yaml.is_null() {
+            return Ok(tuning);
+        }
+        if yaml.as_hash().is_none() {
+            return Err(vec!["Tuning file must be a hash.".to_string()]);
+        }
+
+        let mut err_msgs = vec![];
+        tuning.disabled_rules = get_string_list(&yaml["disabled_rules"])
+            .into_iter()
+            .collect();
+        tuning.action = match yaml["action"].as_str() {
+            None | Some("drop") => SuppressionAction::Drop,
+            Some("tag") => SuppressionAction::Tag,
+            Some(action) => {
+                err_msgs.push(format!(
+                    "Unknown suppression action. action:{} (drop or tag)",
+                    action
+                ));
+                SuppressionAction::Drop
+            }
+        };
+
+        let suppressions = yaml["suppressions"].as_vec().cloned().unwrap_or_default();
+        for (index, suppression) in suppressions.iter().enumerate() {
+            match parse_suppression(index, suppression) {
+                Ok(suppression) => tuning.suppressions.push(suppression),
+                Err(err) => err_msgs.extend(err),
+            }
+        }
+
+        if err_msgs.is_empty() {
+            Ok(tuning)
+        } else {
+            Err(err_msgs)
+        }
+    }
+
+    // Whether the rule is listed in disabled_rules
+    pub fn is_disabled(&self, rule_yaml: &Yaml) -> bool {
+        matches!(rule_yaml["id"].as_str(), Some(id) if self.disabled_rules.contains(id))
+    }
+
+    // Whether a detection of the rule for the log entry is a known false positive
+    pub fn is_suppressed(&self, rule_id: &str, data: &EventRecord) -> bool {
+        self.suppressions.iter().any(|suppression| {
+            (suppression.rule_ids.is_empty() || suppression.rule_ids.iter().any(|id| id == rule_id))
+                && suppression.node.select(data)
+        })
+    }
+}
+
+// A suppression is either a Sigma-style selection or a process/subsystem pair.
+// Both are compiled into a rule with a single selection, so the same matchers as the rules are used
+fn parse_suppression(index: usize, yaml: &Yaml) -> Result<Suppression, Vec<String>> {
+    let selection = if !yaml["selection"].is_badvalue() {
+        yaml["selection"].to_owned()
+    } else {
+        let mut selection = Hash::new();
+        if let Some(process) = yaml["process"].as_str() {
+            // A full path is compared with the image path, otherwise with the process name
+            let field = if process.contains('/') {
+                "processImagePath"
+            } else {
+                "process"
+            };
+            selection.insert(
+                Yaml::String(field.to_string()),
+                Yaml::String(process.to_string()),
+            );
+        }
+        if let Some(subsystem) = yaml["subsystem"].as_str() {
+            selection.insert(
+                Yaml::String("subsystem".to_string()),
+                Yaml::String(subsystem.to_string()),
+            );
+        }
+        if selection.is_empty() {
+            return Err(vec![format!(
+                "Suppression needs a selection or a process/subsystem. index:{}",
+                index
+            )]);
+        }
+        Yaml::Hash(selection)
+    };
+
+    let mut detection = Hash::new();
+    detection.insert(Yaml::String("selection".to_string()), selection);
+    detection.insert(
+        Yaml::String("condition".to_string()),
+        Yaml::String("selection".to_string()),
+    );
+    let mut rule = Hash::new();
+    rule.insert(Yaml::String("detection".to_string()), Yaml::Hash(detection));
+
+    let mut node = RuleNode::new(format!("suppressions[{}]", index), Yaml::Hash(rule));
+    node.init().map_err(|err_msgs| {
+        err_msgs
+            .into_iter()
+            .map(|msg| format!("Invalid suppression. index:{} {}", index, msg))
+            .collect::<Vec<String>>()
+    })?;
+    Ok(Suppression {
+        rule_ids: get_string_list(&yaml["rule"]),
+        node,
+    })
+}
+
+// Load the tuning file. Without --tuning, config/tuning.yml next to the executable is used if it exists
+pub fn load_tuning(path: Option<&PathBuf>) -> Result<Tuning, Box<dyn Error>> {
+    let default_path = app_file_path("config/tuning.yml");
+    let path = match (path, &default_path) {
+        (Some(path), _) => path.as_path(),
+        (None, Some(default_path)) => default_path.as_path(),
+        (None, None) => return Ok(Tuning::default()),
+    };
+    let file_content = fs::read_to_string(path)
+        .map_err(|err| format!("Cannot read {}. {}", path.display(), err))?;
+    let yaml_contents = YamlLoader::load_from_str(&file_content)?;
+    let tuning = Tuning::from_yaml(yaml_contents.first().unwrap_or(&Yaml::Null))
+        .map_err(|err_msgs| err_msgs.join(" "))?;
+    Ok(tuning)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_helpers::create_logdata;
+    use crate::tuning::{SuppressionAction, Tuning};
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn test_tuning_from_yaml() {
+        let yaml = YamlLoader::load_from_str(
+            r#"
+        disabled_rules:
+            - 0c1ffcf9-efa9-436e-ab68-23a9496ebf5b
+        action: tag
+        suppressions:
+            - rule: f5141b6d-9f42-41c6-a7bf-2a780678b29b
+              process: /usr/local/jamf/bin/jamf
+            - process: xattr
+              subsystem: com.apple.xpc
+            - selection:
+                  processImagePath|startswith: '/Applications/EDR.app/'
+        "#,
+        )
+        .unwrap();
+        let tuning = Tuning::from_yaml(&yaml[0]).unwrap();
+        assert_eq!(tuning.action, SuppressionAction::Tag);
+
+        let rule = YamlLoader::load_from_str("id: 0c1ffcf9-efa9-436e-ab68-23a9496ebf5b").unwrap();
+        assert!(tuning.is_disabled(&rule[0]));
+        let rule = YamlLoader::load_from_str("id: f5141b6d-9f42-41c6-a7bf-2a780678b29b").unwrap();
+        assert!(!tuning.is_disabled(&rule[0]));
+
+        // ルールidを指定したsuppressionは、そのルールにだけ適用する
+        let jamf = create_logdata("/usr/local/jamf/bin/jamf", "test");
+        assert!(tuning.is_suppressed("f5141b6d-9f42-41c6-a7bf-2a780678b29b", &jamf));
+        assert!(!tuning.is_suppressed("0c1ffcf9-efa9-436e-ab68-23a9496ebf5b", &jamf));
+        // process/subsystemの組み合わせ
+        let xattr = create_logdata("/usr/bin/xattr", "test");
+        assert!(tuning.is_suppressed("0c1ffcf9-efa9-436e-ab68-23a9496ebf5b", &xattr));
+        // Sigmaのselection
+        let edr = create_logdata("/Applications/EDR.app/Contents/MacOS/edr", "test");
+        assert!(tuning.is_suppressed("0c1ffcf9-efa9-436e-ab68-23a9496ebf5b", &edr));
+        let ls = create_logdata("/bin/ls", "test");
+        assert!(!tuning.is_suppressed("0c1ffcf9-efa9-436e-ab68-23a9496ebf5b", &ls));
+    }
+
+    #[test]
+    fn test_tuning_from_yaml_invalid() {
+        let yaml = YamlLoader::load_from_str(
+            r#"
+        action: ignore
+        suppressions:
+            - rule: f5141b6d-9f42-41c6-a7bf-2a780678b29b
+            - selection:
+                  NoSuchField: test
+        "#,
+        )
+        .unwrap();
+        let errors = Tuning::from_yaml(&yaml[0]).err().unwrap();
+        assert_eq!(errors.len(), 3);
+    }
+}
@@ -0,0 +1,471 @@
+use crate::rule::correlation::{get_rule_names, is_correlation_rule, CorrelationRule};
+use crate::rule::fieldmapping::FIELD_MAPPING;
+use crate::rule::rulenode::RuleNode;
+use crate::yml::{read_yaml_files, RuleError, RULE_LEVELS, RULE_STATUSES};
+use hashbrown::{HashMap, HashSet};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::path::Path;
+use yaml_rust::Yaml;
+
+lazy_static! {
+    static ref UUID_REGEX: Regex = Regex::new(
+        r"^[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$"
+    )
+    .unwrap();
+}
+
+// A problem found in a rule. kind is one of yaml, compile, metadata, duplicate_id and unmapped_field
+struct Finding {
+    kind: &'static str,
+    message: String,
+}
+
+impl Finding {
+    fn new(kind: &'static str, message: String) -> Finding {
+        Finding { kind, message }
+    }
+}
+
+// Result of validating a single rule
+struct RuleReport {
+    path: String,
+    title: Option<String>,
+    id: Option<String>,
+    unmapped_fields: Vec<String>,
+    findings: Vec<Finding>,
+}
+
+impl RuleReport {
+    fn to_json(&self) -> Value {
+        json!({
+            "path": self.path,
+            "title": self.title,
+            "id": self.id,
+            "valid": self.findings.is_empty(),
+            "unmapped_fields": self.unmapped_fields,
+            "errors": self.findings.iter().map(|finding| json!({
+                "type": finding.kind,
+                "message": finding.message,
+            })).collect::<Vec<Value>>(),
+        })
+    }
+}
+
+// Validate every rule in a directory and return the report.
+// Each rule is compiled in the same way as when creating a timeline, and the Sigma metadata,
+// duplicate ids and the fields without a Unified Log mapping are checked as well.
+// Correlation rules are checked against the valid rules of the same directory.
+pub fn validate_rules(dir: &Path) -> Result<Value, Box<dyn Error>> {
+    if !dir.is_dir() {
+        return Err(format!("Rules directory not found: {}", dir.display()).into());
+    }
+    let mut rule_errors = vec![];
+    let yaml = read_yaml_files(dir, &mut rule_errors)?;
+
+    let mut reports: Vec<RuleReport> = rule_errors.into_iter().map(yaml_report).collect();
+    let (correlation_yaml, rule_yaml): (Vec<_>, Vec<_>) = yaml
+        .into_iter()
+        .partition(|(_, yaml)| is_correlation_rule(yaml));
+    let mut rule_nodes = vec![];
+    for (path, yaml) in rule_yaml {
+        reports.push(rule_report(path, yaml, &mut rule_nodes));
+    }
+    let correlation_names: HashSet<String> = correlation_yaml
+        .iter()
+        .flat_map(|(_, yaml)| get_rule_names(yaml))
+        .collect();
+    for (path, yaml) in correlation_yaml {
+        reports.push(correlation_report(
+            path,
+            yaml,
+            &rule_nodes,
+            &correlation_names,
+        ));
+    }
+    check_duplicate_ids(&mut reports);
+    reports.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let invalid = reports
+        .iter()
+        .filter(|report| !report.findings.is_empty())
+        .count();
+    Ok(json!({
+        "directory": dir.display().to_string(),
+        "summary": {
+            "total": reports.len(),
+            "valid": reports.len() - invalid,
+            "invalid": invalid,
+        },
+        "rules": reports.iter().map(RuleReport::to_json).collect::<Vec<Value>>(),
+    }))
+}
+
+// Report for a rule file that could not be parsed as YAML
+fn yaml_report(rule_error: RuleError) -> RuleReport {
+    RuleReport {
+        path: rule_error.path,
+        title: None,
+        id: None,
+        unmapped_fields: vec![],
+        findings: rule_error
+            .messages
+            .into_iter()
+            .map(|message| Finding::new("yaml", message))
+            .collect(),
+    }
+}
+
+// Report for a rule. The rule is added to rule_nodes if it compiles, so that correlation rules can reference it
+fn rule_report(path: String, yaml: Yaml, rule_nodes: &mut Vec<RuleNode>) -> RuleReport {
+    let mut findings = check_metadata(&yaml, true);
+    let unmapped_fields: Vec<String> = get_detection_fields(&yaml["detection"])
+        .into_iter()
+        .filter(|field| FIELD_MAPPING.resolve(field).is_none())
+        .collect();
+    findings.extend(unmapped_fields.iter().map(|field| {
+        Finding::new(
+            "unmapped_field",
+            format!("Field has no Unified Log mapping. field:{}", field),
+        )
+    }));
+
+    let title = yaml["title"].as_str().map(|title| title.to_string());
+    let id = yaml["id"].as_str().map(|id| id.to_string());
+    let mut rule = RuleNode::new(path.to_owned(), yaml);
+    match rule.init() {
+        Ok(_) => rule_nodes.push(rule),
+        // The fields without a mapping are already reported as unmapped_field
+        Err(messages) => findings.extend(
+            messages
+                .into_iter()
+                .filter(|message| !is_unmapped_field_error(message, &unmapped_fields))
+                .map(|message| Finding::new("compile", message)),
+        ),
+    }
+    RuleReport {
+        path,
+        title,
+        id,
+        unmapped_fields,
+        findings,
+    }
+}
+
+// Whether a compile error is the one RuleNode::init reports for a field without a mapping
+fn is_unmapped_field_error(message: &str, unmapped_fields: &[String]) -> bool {
+    let key = match message.strip_prefix("Found unknown field. key:") {
+        Some(key) => key,
+        None => return false,
+    };
+    let field = key.split('|').next().unwrap_or_default();
+    unmapped_fields.iter().any(|unmapped| unmapped == field)
+}
+
+// Report for a correlation rule. A correlation rule has no detection or logsource
+fn correlation_report(
+    path: String,
+    yaml: Yaml,
+    rule_nodes: &[RuleNode],
+    correlation_names: &HashSet<String>,
+) -> RuleReport {
+    let mut findings = check_metadata(&yaml, false);
+    let title = yaml["title"].as_str().map(|title| title.to_string());
+    let id = yaml["id"].as_str().map(|id| id.to_string());
+    let mut correlation = CorrelationRule::new(path, yaml);
+    if let Err(messages) = correlation.init(rule_nodes, correlation_names) {
+        findings.extend(
+            messages
+                .into_iter()
+                .map(|message| Finding::new("compile", message)),
+        );
+    }
+    RuleReport {
+        path: correlation.rulepath,
+        title,
+        id,
+        unmapped_fields: vec![],
+        findings,
+    }
+}
+
+// Check the Sigma metadata required to triage the detections
+fn check_metadata(yaml: &Yaml, needs_logsource: bool) -> Vec<Finding> {
+    let mut findings = vec![];
+    let mut missing = |key: &str| {
+        findings.push(Finding::new(
+            "metadata",
+            format!("Required field is missing. field:{}", key),
+        ))
+    };
+    if yaml["title"].as_str().unwrap_or_default().is_empty() {
+        missing("title");
+    }
+    if yaml["id"].as_str().is_none() {
+        missing("id");
+    }
+    if yaml["level"].as_str().is_none() {
+        missing("level");
+    }
+    if yaml["status"].as_str().is_none() {
+        missing("status");
+    }
+    if needs_logsource && !matches!(yaml["logsource"].as_hash(), Some(hash) if !hash.is_empty()) {
+        missing("logsource");
+    }
+
+    if let Some(id) = yaml["id"].as_str() {
+        if !UUID_REGEX.is_match(id) {
+            findings.push(Finding::new(
+                "metadata",
+                format!("id is not a UUID. id:{}", id),
+            ));
+        }
+    }
+    if let Some(level) = yaml["level"].as_str() {
+        if !RULE_LEVELS.contains(&level) {
+            findings.push(Finding::new(
+                "metadata",
+                format!("Unknown level. level:{}", level),
+            ));
+        }
+    }
+    if let Some(status) = yaml["status"].as_str() {
+        if !RULE_STATUSES.contains(&status) {
+            findings.push(Finding::new(
+                "metadata",
+                format!("Unknown status. status:{}", status),
+            ));
+        }
+    }
+    findings
+}
+
+// Field names used in the selections of a detection. Keyword selections have no field names
+fn get_detection_fields(detection_yaml: &Yaml) -> Vec<String> {
+    let mut fields = vec![];
+    if let Some(detection_hash) = detection_yaml.as_hash() {
+        for (name, selection) in detection_hash {
+            if matches!(name.as_str(), Some("condition") | Some("timeframe")) {
+                continue;
+            }
+            collect_selection_fields(selection, &mut fields);
+        }
+    }
+    fields
+}
+
+fn collect_selection_fields(selection: &Yaml, fields: &mut Vec<String>) {
+    match selection {
+        Yaml::Hash(hash) => {
+            for key in hash.keys() {
+                let field = key
+                    .as_str()
+                    .unwrap_or_default()
+                    .split('|')
+                    .next()
+                    .unwrap_or_default();
+                if !field.is_empty() && !fields.iter().any(|f| f == field) {
+                    fields.push(field.to_string());
+                }
+            }
+        }
+        Yaml::Array(selections) => {
+            for selection in selections {
+                collect_selection_fields(selection, fields);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Rules sharing an id cannot be told apart in the detection results
+fn check_duplicate_ids(reports: &mut [RuleReport]) {
+    let mut id_to_paths: HashMap<String, Vec<String>> = HashMap::new();
+    for report in reports.iter() {
+        if let Some(id) = &report.id {
+            id_to_paths
+                .entry(id.to_owned())
+                .or_default()
+                .push(report.path.to_owned());
+        }
+    }
+    for report in reports.iter_mut() {
+        let paths = match report.id.as_ref().and_then(|id| id_to_paths.get(id)) {
+            Some(paths) if paths.len() > 1 => paths,
+            _ => continue,
+        };
+        let others: Vec<&str> = paths
+            .iter()
+            .filter(|path| **path != report.path)
+            .map(|path| path.as_str())
+            .collect();
+        report.findings.push(Finding::new(
+            "duplicate_id",
+            format!(
+                "id is also used by other rules. id:{} rules:{}",
+                report.id.as_deref().unwrap_or_default(),
+                others.join(", ")
+            ),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::validate::validate_rules;
+    use std::fs;
+
+    #[test]
+    fn test_validate_rules() {
+        let dir = std::env::temp_dir().join("macos-log-dfir-test-validate-rules");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let valid_rule = r#"
+title: Gatekeeper Bypass via Xattr
+id: f5141b6d-9f42-41c6-a7bf-2a780678b29b
+status: test
+level: low
+logsource:
+    category: process_creation
+    product: macos
+detection:
+    selection:
+        Image|endswith: '/xattr'
+    condition: selection
+"#;
+        fs::write(dir.join("a_valid.yml"), valid_rule).unwrap();
+        fs::write(
+            dir.join("b_duplicate.yml"),
+            valid_rule.replace("/xattr", "/xattr2"),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("c_invalid.yml"),
+            r#"
+title: Invalid rule
+id: not-a-uuid
+level: severe
+logsource:
+    product: macos
+detection:
+    selection:
+        NoSuchField: 'test'
+    condition: selection and
+"#,
+        )
+        .unwrap();
+        fs::write(dir.join("d_broken.yml"), "title: [broken\n").unwrap();
+        fs::write(
+            dir.join("e_condition.yml"),
+            valid_rule
+                .replace("f5141b6d", "e5141b6d")
+                .replace("condition: selection", "condition: selection and"),
+        )
+        .unwrap();
+
+        let report = validate_rules(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(report["summary"]["total"], 5);
+        assert_eq!(report["summary"]["valid"], 0);
+        assert_eq!(report["summary"]["invalid"], 5);
+        let rules = report["rules"].as_array().unwrap();
+        let error_types = |index: usize| -> Vec<String> {
+            rules[index]["errors"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|error| error["type"].as_str().unwrap().to_string())
+                .collect()
+        };
+        // 同じidのルールは両方ともエラーになる
+        assert_eq!(error_types(0), vec!["duplicate_id"]);
+        assert_eq!(error_types(1), vec!["duplicate_id"]);
+        // status無し、UUIDでないid、不明なlevel、マッピングの無いフィールド
+        let types = error_types(2);
+        assert_eq!(types.iter().filter(|t| *t == "metadata").count(), 3);
+        assert!(types.contains(&"unmapped_field".to_string()));
+        assert_eq!(rules[2]["unmapped_fields"][0], "NoSuchField");
+        // マッピングの無いフィールドは、コンパイルエラーとしては重複して報告しない
+        assert!(!types.contains(&"compile".to_string()));
+        assert_eq!(error_types(3), vec!["yaml"]);
+        // conditionの誤り
+        assert_eq!(error_types(4), vec!["compile"]);
+    }
+
+    #[test]
+    fn test_validate_correlation_rules() {
+        let dir = std::env::temp_dir().join("macos-log-dfir-test-validate-correlation-rules");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("a_rule.yml"),
+            r#"
+title: Failed sudo attempt
+id: 5b5e9a8b-7f0e-4e0f-9c5a-2a8e1b0c6d3f
+name: failed_sudo
+status: test
+level: low
+logsource:
+    product: macos
+detection:
+    selection:
+        process: sudo
+        eventMessage|contains: 'incorrect password attempt'
+    condition: selection
+"#,
+        )
+        .unwrap();
+        let correlation = r#"
+title: Many failed sudo attempts
+id: 0e95725d-7320-415d-80f7-004da920fc11
+status: test
+level: high
+correlation:
+    type: event_count
+    rules:
+        - failed_sudo
+    group-by:
+        - userID
+    timespan: 5m
+    condition:
+        gte: 20
+"#;
+        fs::write(dir.join("b_correlation.yml"), correlation).unwrap();
+        fs::write(
+            dir.join("c_correlation.yml"),
+            correlation
+                .replace("0e95725d", "1e95725d")
+                .replace("failed_sudo", "no_such_rule"),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("d_correlation.yml"),
+            correlation
+                .replace("0e95725d", "2e95725d")
+                .replace("failed_sudo", "0e95725d-7320-415d-80f7-004da920fc11"),
+        )
+        .unwrap();
+
+        let report = validate_rules(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // 相関ルールにはlogsourceが無くてもよい
+        assert_eq!(report["summary"]["valid"], 2);
+        assert_eq!(report["summary"]["invalid"], 2);
+        let rules = report["rules"].as_array().unwrap();
+        assert_eq!(rules[2]["errors"][0]["type"], "compile");
+        assert_eq!(
+            rules[2]["errors"][0]["message"],
+            "Referenced rule was not found. rule:no_such_rule"
+        );
+        // 相関ルールから別の相関ルールは参照できない
+        assert_eq!(
+            rules[3]["errors"][0]["message"],
+            "Correlation rules cannot reference other correlation rules. rule:0e95725d-7320-415d-80f7-004da920fc11"
+        );
+    }
+}
@@ -1,32 +1,174 @@
+use crate::rule::fieldmapping::FieldMapping;
+use crate::rule::logsource::LogsourceMapping;
+use hashbrown::HashMap;
+use include_dir::{include_dir, Dir, DirEntry};
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use yaml_rust::{Yaml, YamlLoader};
 
-pub fn read_yaml_files(dir: &Path) -> Result<Vec<(String, Yaml)>, Box<dyn std::error::Error>> {
+// Rules compiled into the executable. Used when no rules directory is available
+static EMBEDDED_RULES: Dir = include_dir!("$CARGO_MANIFEST_DIR/rules/sigma/macos");
+
+// Rule path prefix of the embedded rules
+pub const EMBEDDED_RULES_PREFIX: &str = "<embedded>";
+
+// Values of the level and status fields defined in the Sigma specification
+pub const RULE_LEVELS: [&str; 5] = ["informational", "low", "medium", "high", "critical"];
+pub const RULE_STATUSES: [&str; 5] = [
+    "stable",
+    "test",
+    "experimental",
+    "deprecated",
+    "unsupported",
+];
+
+// A rule file that could not be loaded, with the reasons
+#[derive(Debug)]
+pub struct RuleError {
+    pub path: String,
+    pub messages: Vec<String>,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.messages.join(" "))
+    }
+}
+
+// Load the rules to apply.
+// The default rules (./rules if it exists and otherwise the embedded rules) are loaded first, then the rules
+// of each of the specified directories in order. A rule with the same id as an earlier rule replaces it,
+// so private rules can be layered on top of the bundled ones. The default rules are skipped with no_default_rules.
+// Rule files that cannot be read or parsed as YAML are skipped and added to errors.
+pub fn load_rules(
+    dirs: &[PathBuf],
+    no_default_rules: bool,
+    errors: &mut Vec<RuleError>,
+) -> Result<Vec<(String, Yaml)>, Box<dyn std::error::Error>> {
+    let mut rules: Vec<(String, Yaml)> = vec![];
+    let mut id_index: HashMap<String, usize> = HashMap::new();
+    let mut add_rules = |yaml_files: Vec<(String, Yaml)>| {
+        for (path, yaml) in yaml_files {
+            let id = yaml["id"].as_str().map(|id| id.to_string());
+            match id.as_ref().and_then(|id| id_index.get(id)) {
+                Some(&index) => rules[index] = (path, yaml),
+                None => {
+                    if let Some(id) = id {
+                        id_index.insert(id, rules.len());
+                    }
+                    rules.push((path, yaml));
+                }
+            }
+        }
+    };
+
+    if !no_default_rules {
+        let default_dir = Path::new("./rules");
+        if default_dir.is_dir() {
+            add_rules(read_yaml_files(default_dir, errors)?);
+        } else {
+            add_rules(read_embedded_rules(errors));
+        }
+    }
+    for dir in dirs {
+        if !dir.is_dir() {
+            return Err(format!("Rules directory not found: {}", dir.display()).into());
+        }
+        add_rules(read_yaml_files(dir, errors)?);
+    }
+    Ok(rules)
+}
+
+pub fn read_embedded_rules(errors: &mut Vec<RuleError>) -> Vec<(String, Yaml)> {
+    let mut yaml_files = vec![];
+    visit_embedded_dirs(&EMBEDDED_RULES, &mut yaml_files, errors);
+    yaml_files
+}
+
+pub fn read_yaml_files(
+    dir: &Path,
+    errors: &mut Vec<RuleError>,
+) -> Result<Vec<(String, Yaml)>, Box<dyn std::error::Error>> {
     let mut yaml_files = vec![];
-    visit_dirs(dir, &mut yaml_files)?;
+    visit_dirs(dir, &mut yaml_files, errors)?;
     Ok(yaml_files)
 }
 
+// Path of a file shipped next to the executable (ex: config/field_mapping.yml), if it exists.
+// Resolved against the directory of the executable, so the result does not depend on the working directory
+pub fn app_file_path(relative_path: &str) -> Option<PathBuf> {
+    let exe = std::env::current_exe().ok()?;
+    let path = exe.parent()?.join(relative_path);
+    if path.exists() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+// A value given either as a single string or as a list of strings
+pub fn get_string_list(yaml: &Yaml) -> Vec<String> {
+    match yaml {
+        Yaml::String(value) => vec![value.to_owned()],
+        Yaml::Array(values) => values
+            .iter()
+            .filter_map(|value| value.as_str())
+            .map(|value| value.to_string())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+pub fn read_field_mapping(path: &Path) -> Result<FieldMapping, Box<dyn std::error::Error>> {
+    let file_content = fs::read_to_string(path)?;
+    parse_field_mapping(&file_content)
+}
+
+pub fn parse_field_mapping(file_content: &str) -> Result<FieldMapping, Box<dyn std::error::Error>> {
+    let yaml_contents = YamlLoader::load_from_str(file_content)?;
+    let mapping = FieldMapping::from_yaml(yaml_contents.first().unwrap_or(&Yaml::Null))
+        .map_err(|err_msgs| err_msgs.join(" "))?;
+    Ok(mapping)
+}
+
+pub fn read_logsource_mapping(path: &Path) -> Result<LogsourceMapping, Box<dyn std::error::Error>> {
+    let file_content = fs::read_to_string(path)?;
+    parse_logsource_mapping(&file_content)
+}
+
+pub fn parse_logsource_mapping(
+    file_content: &str,
+) -> Result<LogsourceMapping, Box<dyn std::error::Error>> {
+    let yaml_contents = YamlLoader::load_from_str(file_content)?;
+    let mapping = LogsourceMapping::from_yaml(yaml_contents.first().unwrap_or(&Yaml::Null))
+        .map_err(|err_msgs| err_msgs.join(" "))?;
+    Ok(mapping)
+}
+
 fn visit_dirs(
     dir: &Path,
     yaml_files: &mut Vec<(String, Yaml)>,
+    errors: &mut Vec<RuleError>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-
+        let mut paths = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<PathBuf>, _>>()?;
+        // Load the rules in path order, so that which of the rules with the same id is kept does not depend
+        // on the directory order
+        paths.sort();
+        for path in paths {
             if path.is_dir() {
-                visit_dirs(&path, yaml_files)?;
-            } else if let Some(extension) = path.extension() {
-                if extension == "yaml" || extension == "yml" {
-                    let file_content = fs::read_to_string(&path)?;
-                    let yaml_contents = YamlLoader::load_from_str(&file_content);
-                    yaml_files.extend(yaml_contents.unwrap().into_iter().map(|yaml_content| {
-                        let filepath = format!("{}", path.display());
-                        (filepath, yaml_content)
-                    }));
+                visit_dirs(&path, yaml_files, errors)?;
+            } else if is_yaml_file(&path) {
+                let filepath = format!("{}", path.display());
+                match fs::read_to_string(&path) {
+                    Ok(file_content) => load_yaml(filepath, &file_content, yaml_files, errors),
+                    Err(err) => errors.push(RuleError {
+                        path: filepath,
+                        messages: vec![format!("Cannot read file. {}", err)],
+                    }),
                 }
             }
         }
@@ -34,14 +176,229 @@ fn visit_dirs(
     Ok(())
 }
 
+fn visit_embedded_dirs(
+    dir: &Dir,
+    yaml_files: &mut Vec<(String, Yaml)>,
+    errors: &mut Vec<RuleError>,
+) {
+    // Same order as the rules directory on disk (see visit_dirs)
+    let mut entries: Vec<&DirEntry> = dir.entries().iter().collect();
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        match entry {
+            DirEntry::Dir(sub_dir) => visit_embedded_dirs(sub_dir, yaml_files, errors),
+            DirEntry::File(file) if is_yaml_file(file.path()) => {
+                let filepath = format!("{}/{}", EMBEDDED_RULES_PREFIX, file.path().display());
+                match file.contents_utf8() {
+                    Some(file_content) => load_yaml(filepath, file_content, yaml_files, errors),
+                    None => {
+                        errors.push(RuleError {
+                            path: filepath,
+                            messages: vec![
+                                "Cannot read file. The file is not valid UTF-8.".to_string()
+                            ],
+                        })
+                    }
+                }
+            }
+            DirEntry::File(_) => {}
+        }
+    }
+}
+
+fn is_yaml_file(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some("yaml" | "yml")
+    )
+}
+
+// Parse the content of a rule file. A file may contain multiple YAML documents
+fn load_yaml(
+    filepath: String,
+    file_content: &str,
+    yaml_files: &mut Vec<(String, Yaml)>,
+    errors: &mut Vec<RuleError>,
+) {
+    match YamlLoader::load_from_str(file_content) {
+        Ok(yaml_contents) => yaml_files.extend(
+            yaml_contents
+                .into_iter()
+                .map(|yaml_content| (filepath.to_owned(), yaml_content)),
+        ),
+        Err(err) => errors.push(RuleError {
+            path: filepath,
+            messages: vec![format!("Cannot parse yml file. {}", err)],
+        }),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::yml::read_yaml_files;
-    use std::path::{Path, PathBuf};
+    use crate::yml::{
+        is_yaml_file, load_rules, read_embedded_rules, read_yaml_files, visit_embedded_dirs,
+        EMBEDDED_RULES_PREFIX,
+    };
+    use include_dir::{Dir, DirEntry, File};
+    use std::fs;
+    use std::path::Path;
+
+    // ディレクトリ以下のymlファイルの数
+    fn count_yaml_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .map(|path| match path.is_dir() {
+                true => count_yaml_files(&path),
+                false => is_yaml_file(&path) as usize,
+            })
+            .sum()
+    }
 
     #[test]
     fn test_read_yaml_files() {
-        let r = read_yaml_files(&Path::new("./rules")).unwrap();
-        assert_eq!(r.len(), 66);
+        let mut errors = vec![];
+        let r = read_yaml_files(Path::new("./rules"), &mut errors).unwrap();
+        // 同梱のルールは1ファイルに1つずつ
+        assert!(errors.is_empty());
+        assert_eq!(r.len(), count_yaml_files(Path::new("./rules")));
+    }
+
+    #[test]
+    fn test_read_yaml_files_sorted() {
+        let dir = std::env::temp_dir().join("macos-log-dfir-test-sorted-rules");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("b")).unwrap();
+        for (path, title) in [("c.yml", "c"), ("a.yml", "a"), ("b/a.yml", "b/a")] {
+            fs::write(
+                dir.join(path),
+                format!("title: {title}\nid: 00000000-0000-0000-0000-00000000000a\n"),
+            )
+            .unwrap();
+        }
+
+        // ディレクトリの順序に関係なくパス順に読み込むので、同じidのルールは常にパスが最後のものになる
+        let rules = read_yaml_files(&dir, &mut vec![]).unwrap();
+        let titles: Vec<&str> = rules
+            .iter()
+            .map(|(_, yaml)| yaml["title"].as_str().unwrap())
+            .collect();
+        assert_eq!(titles, vec!["a", "b/a", "c"]);
+        let rules = load_rules(std::slice::from_ref(&dir), true, &mut vec![]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].1["title"].as_str(), Some("c"));
+    }
+
+    #[test]
+    fn test_read_embedded_rules_invalid_utf8() {
+        let entries = [
+            DirEntry::File(File::new("b.yml", b"title: valid rule\n")),
+            DirEntry::File(File::new("a.yml", b"title: \xff\xfe\n")),
+        ];
+        let dir = Dir::new("", &entries);
+        let mut yaml_files = vec![];
+        let mut errors = vec![];
+        visit_embedded_dirs(&dir, &mut yaml_files, &mut errors);
+        // UTF-8でないファイルは空のルールとして読み込まずにエラーにする
+        assert_eq!(yaml_files.len(), 1);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, format!("{}/a.yml", EMBEDDED_RULES_PREFIX));
+    }
+
+    #[test]
+    fn test_read_embedded_rules() {
+        let mut errors = vec![];
+        let embedded = read_embedded_rules(&mut errors);
+        let on_disk = read_yaml_files(Path::new("./rules/sigma/macos"), &mut errors).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(embedded.len(), on_disk.len());
+        assert!(embedded
+            .iter()
+            .all(|(path, _)| path.starts_with(EMBEDDED_RULES_PREFIX)));
+    }
+
+    #[test]
+    fn test_load_rules_layered() {
+        let dir = std::env::temp_dir().join("macos-log-dfir-test-rules");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("base")).unwrap();
+        fs::create_dir_all(dir.join("private")).unwrap();
+        fs::write(
+            dir.join("base/a.yml"),
+            "title: base rule a\nid: 00000000-0000-0000-0000-00000000000a\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("base/b.yml"),
+            "title: base rule b\nid: 00000000-0000-0000-0000-00000000000b\n",
+        )
+        .unwrap();
+        // 同じidのルールは後から指定したディレクトリのもので置き換える
+        fs::write(
+            dir.join("private/a.yml"),
+            "title: private rule a\nid: 00000000-0000-0000-0000-00000000000a\n",
+        )
+        .unwrap();
+        fs::write(dir.join("private/c.yml"), "title: private rule c\n").unwrap();
+
+        let rules =
+            load_rules(&[dir.join("base"), dir.join("private")], true, &mut vec![]).unwrap();
+        let mut titles: Vec<&str> = rules
+            .iter()
+            .map(|(_, yaml)| yaml["title"].as_str().unwrap())
+            .collect();
+        titles.sort_unstable();
+        assert_eq!(
+            titles,
+            vec!["base rule b", "private rule a", "private rule c"]
+        );
+        assert!(load_rules(&[dir.join("no_such_dir")], true, &mut vec![]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_rules_over_default_rules() {
+        let dir = std::env::temp_dir().join("macos-log-dfir-test-default-rules");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("private")).unwrap();
+        // 同梱のルールと同じidのルールと、新しいルール
+        fs::write(
+            dir.join("private/emond.yml"),
+            "title: private emond rule\nid: 23c43900-e732-45a4-8354-63e4a6c187ce\n",
+        )
+        .unwrap();
+        fs::write(dir.join("private/new.yml"), "title: private new rule\n").unwrap();
+
+        // --rulesを指定しても同梱のルールは読み込み、その上に重ねる
+        let default_rules = load_rules(&[], false, &mut vec![]).unwrap();
+        let rules = load_rules(&[dir.join("private")], false, &mut vec![]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(rules.len(), default_rules.len() + 1);
+        let titles: Vec<&str> = rules
+            .iter()
+            .filter_map(|(_, yaml)| yaml["title"].as_str())
+            .collect();
+        assert!(titles.contains(&"private emond rule"));
+        assert!(titles.contains(&"private new rule"));
+        assert!(!titles.contains(&"MacOS Emond Launch Daemon"));
+    }
+
+    #[test]
+    fn test_read_yaml_files_invalid() {
+        let dir = std::env::temp_dir().join("macos-log-dfir-test-invalid-rules");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("valid.yml"), "title: valid rule\n").unwrap();
+        fs::write(dir.join("invalid.yml"), "title: [invalid rule\n").unwrap();
+
+        // 壊れたymlファイルがあっても他のルールは読み込む
+        let mut errors = vec![];
+        let rules = read_yaml_files(&dir, &mut errors).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].path.ends_with("invalid.yml"));
+        assert!(errors[0].messages[0].starts_with("Cannot parse yml file."));
     }
 }